@@ -3,11 +3,14 @@ use std::io::{stdin, stdout, BufRead, BufReader, Write};
 
 use anyhow::{anyhow, Result};
 use clap::{crate_version, App, Arg};
+use fuser::FileType;
 use tikv_client::TransactionClient;
 use tracing_subscriber::EnvFilter;
 
+use tifs::fs::compression::decode_block;
 use tifs::fs::inode::Inode;
 use tifs::fs::key::{ScopedKey, ROOT_INODE};
+use tifs::fs::tikv_fs::TiFs;
 use tifs::fs::transaction::Txn;
 
 #[async_std::main]
@@ -24,6 +27,13 @@ async fn main() -> Result<()> {
                 .help("set all pd endpoints of the tikv cluster")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .help("name of the filesystem")
+                .takes_value(true),
+        )
         .get_matches();
 
     tracing_subscriber::fmt()
@@ -37,7 +47,8 @@ async fn main() -> Result<()> {
         .to_owned()
         .collect();
 
-    let console = Console::construct(endpoints).await?;
+    let prefix = ScopedKey::namespace(matches.value_of("name").unwrap_or_default());
+    let console = Console::construct(endpoints, prefix).await?;
 
     loop {
         match console.interact().await {
@@ -50,11 +61,12 @@ async fn main() -> Result<()> {
 
 struct Console {
     pd_endpoints: Vec<String>,
+    prefix: Vec<u8>,
     client: TransactionClient,
 }
 
 impl Console {
-    async fn construct<S>(pd_endpoints: Vec<S>) -> Result<Self>
+    async fn construct<S>(pd_endpoints: Vec<S>, prefix: Vec<u8>) -> Result<Self>
     where
         S: Clone + Debug + Into<String>,
     {
@@ -63,12 +75,16 @@ impl Console {
             .map_err(|err| anyhow!("{}", err))?;
         Ok(Self {
             client,
+            prefix,
             pd_endpoints: pd_endpoints.into_iter().map(Into::into).collect(),
         })
     }
 
     async fn interact(&self) -> Result<bool> {
-        let mut txn = Txn::begin_optimistic(&self.client).await?;
+        let mut txn = Txn::begin_optimistic(&self.client, self.prefix.clone())
+            .await?
+            .with_stored_block_size()
+            .await?;
         match self.interact_with_txn(&mut txn).await {
             Ok(exit) => {
                 txn.commit().await?;
@@ -101,6 +117,8 @@ impl Console {
             "get_raw" => self.get_attr_raw(txn, &commands[1..]).await?,
             "get_inline" => self.get_inline(txn, &commands[1..]).await?,
             "rm" => self.delete_block(txn, &commands[1..]).await?,
+            "fsck_dir" => self.check_dir(txn, &commands[1..]).await?,
+            "find_type" => self.find_type(txn, &commands[1..]).await?,
             cmd => return Err(anyhow!("unknow command `{}`", cmd)),
         }
 
@@ -108,11 +126,7 @@ impl Console {
     }
 
     async fn reset(&self, txn: &mut Txn) -> Result<()> {
-        let next_inode = txn
-            .read_meta()
-            .await?
-            .map(|meta| meta.inode_next)
-            .unwrap_or(ROOT_INODE);
+        let next_inode = txn.meta().await?.inode_next;
         for inode in txn
             .scan(
                 ScopedKey::inode_range(ROOT_INODE..next_inode),
@@ -122,9 +136,28 @@ impl Console {
             .map(|pair| Inode::deserialize(pair.value()))
         {
             let inode = inode?;
+            let entries: Vec<_> = txn
+                .scan(ScopedKey::dir_entry_range(inode.ino, 0), u32::MAX)
+                .await?
+                .map(|pair| pair.into_key())
+                .collect();
+            for key in entries {
+                txn.delete(key).await?;
+            }
             txn.clear_data(inode.ino).await?;
             txn.remove_inode(inode.ino).await?;
         }
+        let free_inodes: Vec<_> = txn
+            .scan(
+                ScopedKey::free_inode_range(),
+                (next_inode - ROOT_INODE) as u32 + 1,
+            )
+            .await?
+            .map(|pair| pair.into_key())
+            .collect();
+        for key in free_inodes {
+            txn.delete(key).await?;
+        }
         txn.delete(ScopedKey::meta()).await?;
         Ok(())
     }
@@ -133,11 +166,12 @@ impl Console {
         if args.len() < 2 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
         }
-        match txn
-            .get(ScopedKey::block(args[0].parse()?, args[1].parse()?))
-            .await?
-        {
-            Some(value) => println!("{:?}", &value[args.get(2).unwrap_or(&"0").parse()?..]),
+        let (ino, block) = (args[0].parse()?, args[1].parse()?);
+        match txn.get(ScopedKey::block(ino, block)).await? {
+            Some(value) => {
+                let value = decode_block(value, txn.block_size(), ino, block, None)?;
+                println!("{:?}", &value[args.get(2).unwrap_or(&"0").parse()?..])
+            }
             None => println!("Not Found"),
         }
         Ok(())
@@ -147,11 +181,14 @@ impl Console {
         if args.len() < 2 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
         }
-        match txn
-            .get(ScopedKey::block(args[0].parse()?, args[1].parse()?))
-            .await?
-        {
-            Some(value) => println!("{:?}", String::from_utf8_lossy(&value)),
+        let (ino, block) = (args[0].parse()?, args[1].parse()?);
+        match txn.get(ScopedKey::block(ino, block)).await? {
+            Some(value) => {
+                // directories in the legacy layout keep their entries in block 0 as is
+                let value = decode_block(value.clone(), txn.block_size(), ino, block, None)
+                    .unwrap_or(value);
+                println!("{:?}", String::from_utf8_lossy(&value))
+            }
             None => println!("Not Found"),
         }
         Ok(())
@@ -195,6 +232,54 @@ impl Console {
         Ok(())
     }
 
+    async fn check_dir(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 1 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let repair = args.get(1) == Some(&"repair");
+        for (item, kind) in txn.check_entry_types(args[0].parse()?, repair).await? {
+            println!(
+                "entry({}) of inode({}) is {:?}, but the inode is {:?}{}",
+                item.name,
+                item.ino,
+                item.typ,
+                kind,
+                if repair { ", repaired" } else { "" }
+            );
+        }
+        Ok(())
+    }
+
+    async fn find_type(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.len() < 1 {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let kind = match args[0] {
+            "d" | "dir" => FileType::Directory,
+            "f" | "file" => FileType::RegularFile,
+            "l" | "symlink" => FileType::Symlink,
+            typ => return Err(anyhow!("unknown file type `{}`", typ)),
+        };
+        let end = txn.meta().await?.inode_next;
+        let mut next = ROOT_INODE;
+        loop {
+            let pairs: Vec<_> = txn
+                .scan(ScopedKey::inode_range(next..end), TiFs::SCAN_LIMIT)
+                .await?
+                .collect();
+            for pair in pairs.iter() {
+                let inode = Inode::deserialize(pair.value())?;
+                next = inode.ino + 1;
+                if inode.kind == kind {
+                    println!("{}", inode.ino);
+                }
+            }
+            if pairs.len() < TiFs::SCAN_LIMIT as usize {
+                return Ok(());
+            }
+        }
+    }
+
     async fn delete_block(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
         if args.len() < 2 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
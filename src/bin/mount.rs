@@ -1,8 +1,7 @@
 use clap::{crate_version, App, Arg};
-use tracing_subscriber::EnvFilter;
 
-use tifs::mount_tifs_daemonize;
 use tifs::MountOption;
+use tifs::{init_tracing, mount_tifs_daemonize};
 use tracing::{debug, info, trace};
 
 #[async_std::main]
@@ -52,10 +51,8 @@ async fn main() {
         )
         .get_matches();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init()
-        .unwrap();
+    let options = MountOption::to_vec(matches.values_of("options").unwrap_or_default());
+    let _guard = init_tracing(&options).unwrap();
 
     let serve = matches.is_present("serve");
     let foreground = serve || matches.is_present("foreground");
@@ -84,8 +81,6 @@ async fn main() {
             .unwrap()
             .to_owned();
 
-    let options = MountOption::to_vec(matches.values_of("options").unwrap_or_default());
-
     let runtime_config_string = format!(
         "mountpoint={:?} endpoints={:?} opt={:?}",
         mountpoint, endpoints, options
@@ -0,0 +1,151 @@
+use clap::{crate_version, App, Arg};
+use tracing_subscriber::EnvFilter;
+
+use tifs::fs::check::CheckReport;
+use tifs::{check_tifs, MountOption};
+
+#[async_std::main]
+async fn main() {
+    let matches = App::new("tifs-fsck")
+        .version(crate_version!())
+        .author("Hexi Lee")
+        .about("check consistency of a filesystem that is not mounted")
+        .arg(
+            Arg::with_name("pd")
+                .long("pd-endpoints")
+                .multiple(true)
+                .value_name("ENDPOINTS")
+                .default_value("127.0.0.1:2379")
+                .help("set all pd endpoints of the tikv cluster")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .help("name of the filesystem")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("options")
+                .value_name("OPTION")
+                .long("option")
+                .short("o")
+                .multiple(true)
+                .help("mount options of the filesystem, e.g. its encryption key"),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .help("repair the inconsistencies found"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("print the findings as JSON"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .requires("repair")
+                .help("repair even if mounts are counted as alive, which must have crashed"),
+        )
+        .get_matches();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init()
+        .unwrap();
+
+    let endpoints: Vec<&str> = matches.values_of("pd").unwrap_or_default().collect();
+    let mut options = MountOption::to_vec(matches.values_of("options").unwrap_or_default());
+    if let Some(name) = matches.value_of("name") {
+        options.push(MountOption::Name(name.to_owned()));
+    }
+    let repair = matches.is_present("repair");
+    let force = matches.is_present("force");
+
+    let report = match check_tifs(endpoints, options, repair, force).await {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("fail to check the filesystem: {}", err);
+            std::process::exit(2);
+        }
+    };
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print_report(&report, repair);
+    }
+    if !report.is_clean() && !repair {
+        std::process::exit(1);
+    }
+}
+
+fn print_report(report: &CheckReport, repair: bool) {
+    println!(
+        "checked {} inodes, {} entries and {} blocks",
+        report.inodes, report.entries, report.blocks
+    );
+    if let Some(mismatch) = &report.inode_next {
+        println!(
+            "meta: inode_next is {}, but inode {} is allocated",
+            mismatch.stored,
+            mismatch.actual - 1
+        );
+    }
+    if let Some(mismatch) = &report.usage {
+        println!(
+            "meta: usage counters are {:?}, but {:?} is counted",
+            mismatch.stored, mismatch.actual
+        );
+    }
+    if let Some(live_mounts) = report.live_mounts {
+        println!(
+            "meta: {} mounts are counted as alive, they crashed if none is mounted",
+            live_mounts
+        );
+    }
+    for orphaned in &report.orphaned_blocks {
+        println!(
+            "inode {}: {} blocks are stored, but the inode doesn't exist",
+            orphaned.ino, orphaned.blocks
+        );
+    }
+    for entry in &report.dangling_entries {
+        println!(
+            "inode {}: entry {:?} refers to inode {}, which doesn't exist",
+            entry.parent, entry.name, entry.ino
+        );
+    }
+    for ino in &report.unreferenced_inodes {
+        println!("inode {}: in no directory and not opened", ino);
+    }
+    for mismatch in &report.nlink_mismatches {
+        println!(
+            "inode {}: nlink is {}, but {} links are found",
+            mismatch.ino, mismatch.nlink, mismatch.links
+        );
+    }
+    for mismatch in &report.size_mismatches {
+        println!(
+            "inode {}: size is {} with {} blocks counted, {} expected, and {} blocks stored beyond the size",
+            mismatch.ino,
+            mismatch.size,
+            mismatch.blocks,
+            mismatch.expected_blocks,
+            mismatch.blocks_beyond_size
+        );
+    }
+    for mismatch in &report.dir_count_mismatches {
+        println!(
+            "inode {}: entry counter is {}, but {} entries are found",
+            mismatch.ino, mismatch.count, mismatch.entries
+        );
+    }
+    if report.is_clean() {
+        println!("no inconsistency found");
+    } else if repair {
+        println!("the inconsistencies are repaired");
+    }
+}
@@ -1,14 +1,31 @@
 pub mod async_fs;
 pub mod block;
+pub mod block_map;
+pub mod check;
+pub mod compression;
+pub mod copy;
 pub mod dir;
+pub mod dir_hub;
+pub mod encryption;
 pub mod error;
 pub mod file_handler;
+pub mod file_hub;
+pub mod id_map;
 pub mod index;
 pub mod inode;
+pub mod inode_lease;
 pub mod key;
+pub mod lock_wait;
 pub mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod mode;
+pub mod pd;
+pub mod rate_limit;
 pub mod reply;
+pub mod retry;
+pub mod runtime;
 pub mod serialize;
 pub mod tikv_fs;
 pub mod transaction;
+pub mod write_back;
@@ -1,7 +1,7 @@
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use std::{future::Future, path::Path};
 
 use async_std::task::{block_on, spawn};
@@ -12,26 +12,14 @@ use fuser::{
     ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs,
     ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use tracing::trace;
+use tracing::{info_span, trace, Span};
+use tracing_futures::Instrument;
 
 use super::error::{FsError, Result};
 use super::reply::{
     Attr, Bmap, Create, Data, Dir, DirPlus, Entry, FsReply, Lock, Lseek, Open, StatFs, Write, Xattr,
 };
 
-pub fn spawn_reply<F, R, V>(id: u64, reply: R, f: F)
-where
-    F: Future<Output = Result<V>> + Send + 'static,
-    R: FsReply<V> + Send + 'static,
-    V: Debug,
-{
-    spawn(async move {
-        trace!("reply to request({})", id);
-        let result = f.await;
-        reply.reply(id, result);
-    });
-}
-
 #[async_trait]
 pub trait AsyncFileSystem: Send + Sync {
     /// Initialize filesystem.
@@ -45,6 +33,9 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Called on filesystem exit.
     async fn destroy(&self) {}
 
+    /// Observe the latency of a request, from receiving it to replying, and the errno replied.
+    fn observe(&self, _op: &'static str, _elapsed: Duration, _errno: Option<libc::c_int>) {}
+
     /// Look up a directory entry by name and get its attributes.
     async fn lookup(&self, _parent: u64, _name: ByteString) -> Result<Entry> {
         Err(FsError::unimplemented())
@@ -67,6 +58,8 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Set file attributes.
     async fn setattr(
         &self,
+        _caller_uid: u32,
+        _caller_gid: u32,
         _ino: u64,
         _mode: Option<u32>,
         _uid: Option<u32>,
@@ -204,6 +197,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// lock_owner: only supported with ABI >= 7.9
     async fn write(
         &self,
+        _pid: u32,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -271,8 +265,8 @@ pub trait AsyncFileSystem: Send + Sync {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    async fn readdir(&self, _ino: u64, _fh: u64, offset: i64) -> Result<Dir> {
-        Ok(Dir::offset(offset as usize))
+    async fn readdir(&self, _ino: u64, _fh: u64, _offset: i64) -> Result<Dir> {
+        Err(FsError::unimplemented())
     }
 
     /// Read directory.
@@ -280,8 +274,8 @@ pub trait AsyncFileSystem: Send + Sync {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    async fn readdirplus(&self, _ino: u64, _fh: u64, offset: i64) -> Result<DirPlus> {
-        Ok(DirPlus::offset(offset as usize))
+    async fn readdirplus(&self, _ino: u64, _fh: u64, _offset: i64) -> Result<DirPlus> {
+        Err(FsError::unimplemented())
     }
 
     /// Release an open directory.
@@ -302,7 +296,7 @@ pub trait AsyncFileSystem: Send + Sync {
 
     /// Get file system statistics.
     async fn statfs(&self, _ino: u64) -> Result<StatFs> {
-        Ok(StatFs::new(0, 0, 0, 0, 0, 512, 255, 0))
+        Ok(StatFs::new(0, 0, 0, 0, 0, 512))
     }
 
     /// Set an extended attribute.
@@ -444,6 +438,15 @@ pub trait AsyncFileSystem: Send + Sync {
     }
 }
 
+/// Span of the FUSE request `id`, the spans of its transactions and key-value calls are created
+/// beneath it.
+///
+/// The id is not propagated to TiKV: the tikv client sends no request context that could carry
+/// it, so the spans are client-side only and TiKV slow logs are matched by time.
+pub fn request_span(id: u64, op: &'static str) -> Span {
+    info_span!("request", id, op)
+}
+
 pub struct AsyncFs<T>(Arc<T>);
 
 impl<T: AsyncFileSystem> From<T> for AsyncFs<T> {
@@ -452,6 +455,36 @@ impl<T: AsyncFileSystem> From<T> for AsyncFs<T> {
     }
 }
 
+impl<T> AsyncFs<T> {
+    pub fn inner(&self) -> Arc<T> {
+        self.0.clone()
+    }
+}
+
+impl<T: AsyncFileSystem + 'static> AsyncFs<T> {
+    // Run a request in a new task, reply its result and report its latency to the filesystem.
+    fn spawn_reply<F, R, V>(&self, id: u64, op: &'static str, reply: R, f: F)
+    where
+        F: Future<Output = Result<V>> + Send + 'static,
+        R: FsReply<V> + Send + 'static,
+        V: Debug,
+    {
+        let fs = self.0.clone();
+        let span = request_span(id, op);
+        spawn(
+            async move {
+                trace!("reply to request({})", id);
+                let start = Instant::now();
+                let result = f.await;
+                let errno = result.as_ref().err().map(FsError::errno);
+                fs.observe(op, start.elapsed(), errno);
+                reply.reply(id, result);
+            }
+            .instrument(span),
+        );
+    }
+}
+
 impl<T: Debug> Debug for AsyncFs<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -477,7 +510,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "lookup", reply, async move {
             async_impl.lookup(parent, name).await
         });
     }
@@ -493,11 +526,9 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.getattr(ino).await },
-        );
+        self.spawn_reply(req.unique(), "getattr", reply, async move {
+            async_impl.getattr(ino).await
+        });
     }
 
     fn setattr(
@@ -519,11 +550,13 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        let caller_uid = req.uid();
+        let caller_gid = req.gid();
+        self.spawn_reply(req.unique(), "setattr", reply, async move {
             async_impl
                 .setattr(
-                    ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
-                    flags,
+                    caller_uid, caller_gid, ino, mode, uid, gid, size, atime, mtime, ctime, fh,
+                    crtime, chgtime, bkuptime, flags,
                 )
                 .await
         });
@@ -531,7 +564,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "readlink", reply, async move {
             async_impl.readlink(ino).await
         });
     }
@@ -551,7 +584,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "mknod", reply, async move {
             async_impl
                 .mknod(parent, name, mode, gid, uid, umask, rdev)
                 .await
@@ -572,7 +605,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "mkdir", reply, async move {
             async_impl.mkdir(parent, name, mode, gid, uid, umask).await
         });
     }
@@ -580,7 +613,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "unlink", reply, async move {
             async_impl.unlink(parent, name).await
         });
     }
@@ -588,7 +621,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "rmdir", reply, async move {
             async_impl.rmdir(parent, name).await
         });
     }
@@ -607,7 +640,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "symlink", reply, async move {
             async_impl.symlink(gid, uid, parent, name, link).await
         });
     }
@@ -625,7 +658,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
         let newname = newname.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "rename", reply, async move {
             async_impl
                 .rename(parent, name, newparent, newname, flags)
                 .await
@@ -642,14 +675,14 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     ) {
         let async_impl = self.0.clone();
         let newname = newname.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "link", reply, async move {
             async_impl.link(ino, newparent, newname).await
         });
     }
 
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "open", reply, async move {
             async_impl.open(ino, flags).await
         });
     }
@@ -666,7 +699,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "read", reply, async move {
             async_impl
                 .read(ino, fh, offset, size, flags, lock_owner)
                 .await
@@ -687,16 +720,17 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     ) {
         let async_impl = self.0.clone();
         let data = data.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        let pid = req.pid();
+        self.spawn_reply(req.unique(), "write", reply, async move {
             async_impl
-                .write(ino, fh, offset, data, write_flags, flags, lock_owner)
+                .write(pid, ino, fh, offset, data, write_flags, flags, lock_owner)
                 .await
         });
     }
 
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "flush", reply, async move {
             async_impl.flush(ino, fh, lock_owner).await
         });
     }
@@ -712,28 +746,28 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "release", reply, async move {
             async_impl.release(ino, fh, flags, lock_owner, flush).await
         });
     }
 
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "fsync", reply, async move {
             async_impl.fsync(ino, fh, datasync).await
         });
     }
 
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "opendir", reply, async move {
             async_impl.opendir(ino, flags).await
         });
     }
 
     fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "readdir", reply, async move {
             async_impl.readdir(ino, fh, offset).await
         });
     }
@@ -747,25 +781,23 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyDirectoryPlus,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "readdirplus", reply, async move {
             async_impl.readdirplus(ino, fh, offset).await
         });
     }
 
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "fsyncdir", reply, async move {
             async_impl.fsyncdir(ino, fh, datasync).await
         });
     }
 
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.statfs(ino).await },
-        );
+        self.spawn_reply(req.unique(), "statfs", reply, async move {
+            async_impl.statfs(ino).await
+        });
     }
 
     fn setxattr(
@@ -781,7 +813,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
         let value = value.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "setxattr", reply, async move {
             async_impl.setxattr(ino, name, value, flags, position).await
         });
     }
@@ -789,14 +821,14 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "getxattr", reply, async move {
             async_impl.getxattr(ino, name, size).await
         });
     }
 
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "listxattr", reply, async move {
             async_impl.listxattr(ino, size).await
         });
     }
@@ -804,13 +836,13 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "removexattr", reply, async move {
             async_impl.removexattr(ino, name).await
         });
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "access", reply, async move {
             async_impl.access(ino, mask).await
         });
     }
@@ -830,7 +862,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "create", reply, async move {
             async_impl
                 .create(uid, gid, parent, name, mode, umask, flags)
                 .await
@@ -850,7 +882,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "getlk", reply, async move {
             async_impl
                 .getlk(ino, fh, lock_owner, start, end, typ, pid)
                 .await
@@ -871,7 +903,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "setlk", reply, async move {
             async_impl
                 .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
                 .await
@@ -880,7 +912,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
     fn bmap(&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "bmap", reply, async move {
             async_impl.bmap(ino, blocksize, idx).await
         });
     }
@@ -896,7 +928,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "fallocate", reply, async move {
             async_impl.fallocate(ino, fh, offset, length, mode).await
         });
     }
@@ -911,7 +943,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLseek,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "lseek", reply, async move {
             async_impl.lseek(ino, fh, offset, whence).await
         });
     }
@@ -930,7 +962,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        self.spawn_reply(req.unique(), "copy_file_range", reply, async move {
             async_impl
                 .copy_file_range(
                     ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
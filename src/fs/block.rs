@@ -1,7 +1,134 @@
-use super::tikv_fs::TiFs;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use tracing::trace;
+
+#[cfg(feature = "metrics")]
+use super::metrics::Metrics;
 
 type Block = Vec<u8>;
 
-pub fn empty_block() -> Block {
-    vec![0; TiFs::BLOCK_SIZE as usize]
+pub fn empty_block(block_size: u64) -> Block {
+    vec![0; block_size as usize]
+}
+
+struct CachedBlock {
+    // generation and data version of the inode when the block was read,
+    // the block is stale once either changes
+    generation: u64,
+    data_version: u64,
+    data: Block,
+}
+
+/// In-process LRU cache of data blocks, keyed by (ino, block index).
+///
+/// Blocks written by another mount are detected by the data version of the inode, and blocks
+/// of a removed inode by the generation of the one reusing its number. Blocks written by this
+/// mount are invalidated explicitly.
+pub struct BlockCache {
+    block_size: u64,
+    blocks: Mutex<LruCache<(u64, u64), CachedBlock>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
+}
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` bytes of blocks in `block_size`.
+    pub fn new(capacity: usize, block_size: u64) -> Self {
+        let blocks = (capacity / block_size as usize).max(1);
+        Self {
+            block_size,
+            blocks: Mutex::new(LruCache::new(blocks)),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Count hits and misses of lookups in `metrics`.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn count(&self, _hit: bool) {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.count_block_cache(_hit);
+        }
+    }
+
+    /// Change the capacity to `capacity` bytes, the least recently used blocks are evicted if needed.
+    pub fn resize(&self, capacity: usize) {
+        let blocks = (capacity / self.block_size as usize).max(1);
+        self.blocks.lock().unwrap().resize(blocks);
+    }
+
+    /// Get all blocks in `range` if they are cached at the current `generation` and `data_version`.
+    pub fn get_range(
+        &self,
+        ino: u64,
+        generation: u64,
+        data_version: u64,
+        range: Range<u64>,
+    ) -> Option<Vec<Block>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        for block in range {
+            match blocks.get(&(ino, block)) {
+                Some(cached)
+                    if cached.generation == generation && cached.data_version == data_version =>
+                {
+                    data.push(cached.data.clone())
+                }
+                Some(_) => {
+                    trace!("drop stale block({}, {})", ino, block);
+                    blocks.pop(&(ino, block));
+                    self.count(false);
+                    return None;
+                }
+                None => {
+                    self.count(false);
+                    return None;
+                }
+            }
+        }
+        trace!("block cache hit: ino({}), {} blocks", ino, data.len());
+        self.count(true);
+        Some(data)
+    }
+
+    pub fn put(&self, ino: u64, block: u64, generation: u64, data_version: u64, data: Block) {
+        self.blocks.lock().unwrap().put(
+            (ino, block),
+            CachedBlock {
+                generation,
+                data_version,
+                data,
+            },
+        );
+    }
+
+    pub fn clear(&self) {
+        self.blocks.lock().unwrap().clear();
+    }
+
+    pub fn invalidate(&self, ino: u64, range: Range<u64>) {
+        let mut blocks = self.blocks.lock().unwrap();
+        if range.end - range.start > blocks.len() as u64 {
+            let keys: Vec<_> = blocks
+                .iter()
+                .map(|(key, _)| *key)
+                .filter(|(i, block)| *i == ino && range.contains(block))
+                .collect();
+            for key in keys {
+                blocks.pop(&key);
+            }
+        } else {
+            for block in range {
+                blocks.pop(&(ino, block));
+            }
+        }
+    }
 }
@@ -0,0 +1,92 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Blocks of a file stored in TiKV, as sorted and disjoint runs of block indices,
+/// so that a missing block can be told apart from a hole.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BlockMap {
+    runs: Vec<(u64, u64)>,
+}
+
+impl BlockMap {
+    /// Files fragmented into more runs are no longer tracked, to keep inodes small.
+    pub const MAX_RUNS: usize = 1 << 8;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn contains(&self, block: u64) -> bool {
+        self.runs
+            .iter()
+            .any(|(start, end)| *start <= block && block < *end)
+    }
+
+    /// The first stored block in `blocks`.
+    pub fn next_stored(&self, blocks: Range<u64>) -> Option<u64> {
+        self.runs
+            .iter()
+            .find(|(_, end)| *end > blocks.start)
+            .map(|(start, _)| (*start).max(blocks.start))
+            .filter(|block| *block < blocks.end)
+    }
+
+    /// The first block not stored in `blocks`, or `blocks.end` if all of them are stored.
+    pub fn next_missing(&self, blocks: Range<u64>) -> u64 {
+        let mut block = blocks.start;
+        for (start, end) in self.runs.iter() {
+            if *start > block {
+                break;
+            }
+            block = block.max(*end);
+        }
+        block.min(blocks.end)
+    }
+
+    pub fn insert(&mut self, blocks: Range<u64>) {
+        if blocks.start >= blocks.end {
+            return;
+        }
+        let (mut start, mut end) = (blocks.start, blocks.end);
+        // merge all runs overlapping or adjacent to the new one
+        self.runs.retain(|(run_start, run_end)| {
+            if *run_end < start || *run_start > end {
+                return true;
+            }
+            start = start.min(*run_start);
+            end = end.max(*run_end);
+            false
+        });
+        let index = self
+            .runs
+            .iter()
+            .position(|(run_start, _)| *run_start > start)
+            .unwrap_or_else(|| self.runs.len());
+        self.runs.insert(index, (start, end));
+    }
+
+    pub fn remove(&mut self, blocks: Range<u64>) {
+        if blocks.start >= blocks.end {
+            return;
+        }
+        let mut runs = Vec::with_capacity(self.runs.len() + 1);
+        for (start, end) in self.runs.drain(..) {
+            if end <= blocks.start || start >= blocks.end {
+                runs.push((start, end));
+                continue;
+            }
+            if start < blocks.start {
+                runs.push((start, blocks.start));
+            }
+            if end > blocks.end {
+                runs.push((blocks.end, end));
+            }
+        }
+        self.runs = runs;
+    }
+}
@@ -0,0 +1,509 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use fuser::FileType;
+use serde::{Deserialize, Serialize};
+use tikv_client::{Key, KvPair};
+use tracing::{info, warn};
+
+use super::dir::decode_item;
+use super::error::{FsError, Result};
+use super::inode::Inode;
+use super::key::{ScopedKey, ROOT_INODE};
+use super::meta::Usage;
+use super::reply::DirItem;
+use super::tikv_fs::TiFs;
+use super::transaction::decode_dir_count;
+
+/// Inconsistencies found by `TiFs::check`, each kind sorted by inode numbers.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CheckReport {
+    pub inodes: u64,
+    pub entries: u64,
+    pub blocks: u64,
+    /// `inode_next` in the meta is not greater than all inode numbers.
+    pub inode_next: Option<Mismatch<u64>>,
+    /// Usage counters in the meta disagree with the inodes.
+    pub usage: Option<Mismatch<Usage>>,
+    /// Mounts counted as alive in the meta, which crashed if the filesystem is not mounted.
+    /// It's no inconsistency, but a repair refuses to run with it unless forced, and resets it.
+    pub live_mounts: Option<u64>,
+    /// Blocks of inodes that don't exist.
+    pub orphaned_blocks: Vec<OrphanedBlocks>,
+    /// Entries in directories that don't exist, or of inodes that don't exist.
+    pub dangling_entries: Vec<DanglingEntry>,
+    /// Inodes in no directory and not opened.
+    pub unreferenced_inodes: Vec<u64>,
+    pub nlink_mismatches: Vec<NlinkMismatch>,
+    pub size_mismatches: Vec<SizeMismatch>,
+    /// Entry counters of directories disagree with their entries, absent counters are counted
+    /// on the next change.
+    pub dir_count_mismatches: Vec<DirCountMismatch>,
+}
+
+/// A value stored in the filesystem and the one it should be.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Mismatch<T> {
+    pub stored: T,
+    pub actual: T,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OrphanedBlocks {
+    pub ino: u64,
+    pub blocks: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DanglingEntry {
+    pub parent: u64,
+    pub name: String,
+    pub ino: u64,
+}
+
+/// Link count of an inode and the links found, which is 2 plus subdirectories for a directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NlinkMismatch {
+    pub ino: u64,
+    pub nlink: u32,
+    pub links: u32,
+}
+
+/// Blocks counted by an inode disagree with its size, or blocks are stored beyond its size.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SizeMismatch {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub expected_blocks: u64,
+    pub blocks_beyond_size: u64,
+}
+
+/// Entry counter of a directory and the entries found in it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DirCountMismatch {
+    pub ino: u64,
+    pub count: u64,
+    pub entries: u64,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.inode_next.is_none()
+            && self.usage.is_none()
+            && self.orphaned_blocks.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.unreferenced_inodes.is_empty()
+            && self.nlink_mismatches.is_empty()
+            && self.size_mismatches.is_empty()
+            && self.dir_count_mismatches.is_empty()
+    }
+}
+
+// Attributes of an inode needed by the check, with what's found referring to it.
+struct InodeSummary {
+    kind: FileType,
+    nlink: u32,
+    size: u64,
+    blocks: u64,
+    blksize: u32,
+    opened_fh: u64,
+    keyed_entries: bool,
+    entries: u64,
+    links: u32,
+    subdirs: u32,
+    blocks_beyond_size: u64,
+}
+
+impl From<&Inode> for InodeSummary {
+    fn from(inode: &Inode) -> Self {
+        Self {
+            kind: inode.kind,
+            nlink: inode.nlink,
+            size: inode.size,
+            blocks: inode.blocks,
+            blksize: inode.blksize,
+            opened_fh: inode.opened_fh,
+            keyed_entries: inode.keyed_entries,
+            entries: 0,
+            links: 0,
+            subdirs: 0,
+            blocks_beyond_size: 0,
+        }
+    }
+}
+
+impl TiFs {
+    /// Check the meta, inodes, directory entries and blocks of the filesystem against each other,
+    /// and repair the inconsistencies found if `repair` is set.
+    ///
+    /// Keys are scanned in batches by transactions of their own, so the filesystem should not be
+    /// mounted meanwhile, otherwise operations racing with the scans are reported as well.
+    /// Removing an unreferenced directory leaves its entries dangling, they are found by the next check.
+    ///
+    /// A repair under a live mount would undo its changes, so it fails with `FsError::Mounted` while
+    /// mounts are counted in the meta, unless `force` tells they crashed.
+    pub async fn check(&self, repair: bool, force: bool) -> Result<CheckReport> {
+        let mut report = CheckReport::default();
+        let meta = match self.spin_with_policy(|_, txn| Box::pin(txn.meta())).await {
+            Ok(meta) => meta,
+            Err(FsError::Uninitialized) => {
+                warn!("filesystem({}) is not initialized", self.name);
+                return Ok(report);
+            }
+            Err(err) => return Err(err),
+        };
+        if repair && !force && meta.live_mounts > 0 {
+            return Err(FsError::Mounted {
+                mounts: meta.live_mounts,
+            });
+        }
+        let block_size = meta.block_size();
+        let stored_usage = self
+            .spin_with_policy(|_, txn| Box::pin(txn.stored_usage()))
+            .await?;
+
+        let mut inodes = BTreeMap::new();
+        let mut usage = Usage::new();
+        let mut range = Some(ScopedKey::inode_range(0..u64::MAX));
+        while let Some(scan) = range.take() {
+            let (pairs, rest) = self.scan_batch(scan).await?;
+            for pair in pairs {
+                let inode = Inode::deserialize(pair.value())?;
+                usage.apply(inode.blocks as i64, 1);
+                inodes.insert(inode.ino, InodeSummary::from(&inode));
+            }
+            range = rest;
+        }
+        report.inodes = inodes.len() as u64;
+
+        let mut range = Some(ScopedKey::all_dir_entry_range());
+        while let Some(scan) = range.take() {
+            let (pairs, rest) = self.scan_batch(scan).await?;
+            for pair in pairs {
+                let parent = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::DirEntry { parent, name: _ } => parent,
+                    _ => unreachable!("the keys from scanning should be always valid entry keys"),
+                };
+                report.entries += 1;
+                if let Some(dir) = inodes.get_mut(&parent) {
+                    dir.entries += 1;
+                }
+                report.dangling_entries.extend(link_entry(
+                    &mut inodes,
+                    parent,
+                    decode_item(pair.value())?,
+                ));
+            }
+            range = rest;
+        }
+        // directories in the layout before version 2 keep their entries in the first block
+        let legacy_dirs: Vec<u64> = inodes
+            .iter()
+            .filter(|(_, inode)| inode.kind == FileType::Directory && !inode.keyed_entries)
+            .map(|(ino, _)| *ino)
+            .collect();
+        for parent in legacy_dirs {
+            let dir = self
+                .spin_with_policy(move |_, txn| Box::pin(txn.read_dir(parent)))
+                .await?;
+            for item in dir {
+                report.entries += 1;
+                report
+                    .dangling_entries
+                    .extend(link_entry(&mut inodes, parent, item));
+            }
+        }
+
+        // counters of directories in the legacy layout are stored once they're migrated
+        let mut range = Some(ScopedKey::all_dir_count_range());
+        while let Some(scan) = range.take() {
+            let (pairs, rest) = self.scan_batch(scan).await?;
+            for pair in pairs {
+                let ino = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::DirCount(ino) => ino,
+                    _ => unreachable!("the keys from scanning should be always valid count keys"),
+                };
+                let count = decode_dir_count(pair.value())?;
+                match inodes.get(&ino) {
+                    Some(dir) if dir.kind == FileType::Directory && dir.entries != count => {
+                        report.dir_count_mismatches.push(DirCountMismatch {
+                            ino,
+                            count,
+                            entries: dir.entries,
+                        })
+                    }
+                    _ => (),
+                }
+            }
+            range = rest;
+        }
+
+        let mut orphaned_blocks = BTreeMap::new();
+        let mut range = Some(ScopedKey::all_block_range());
+        while let Some(scan) = range.take() {
+            let (pairs, rest) = self.scan_batch(scan).await?;
+            for pair in pairs {
+                let (ino, block) = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::Block { ino, block } => (ino, block),
+                    _ => unreachable!("the keys from scanning should be always valid block keys"),
+                };
+                report.blocks += 1;
+                match inodes.get_mut(&ino) {
+                    None => *orphaned_blocks.entry(ino).or_insert(0) += 1,
+                    Some(inode)
+                        if inode.kind == FileType::RegularFile
+                            && block >= (inode.size + block_size - 1) / block_size =>
+                    {
+                        inode.blocks_beyond_size += 1
+                    }
+                    Some(_) => (),
+                }
+            }
+            range = rest;
+        }
+        report.orphaned_blocks = orphaned_blocks
+            .into_iter()
+            .map(|(ino, blocks)| OrphanedBlocks { ino, blocks })
+            .collect();
+
+        for (ino, inode) in inodes.iter() {
+            if *ino != ROOT_INODE && inode.links == 0 && inode.opened_fh == 0 {
+                report.unreferenced_inodes.push(*ino);
+                continue;
+            }
+            let links = match inode.kind {
+                FileType::Directory => 2 + inode.subdirs,
+                _ => inode.links,
+            };
+            if inode.nlink != links {
+                report.nlink_mismatches.push(NlinkMismatch {
+                    ino: *ino,
+                    nlink: inode.nlink,
+                    links,
+                });
+            }
+            let blksize = inode.blksize.max(1) as u64;
+            let expected_blocks = (inode.size + blksize - 1) / blksize;
+            if inode.blocks != expected_blocks || inode.blocks_beyond_size > 0 {
+                report.size_mismatches.push(SizeMismatch {
+                    ino: *ino,
+                    size: inode.size,
+                    blocks: inode.blocks,
+                    expected_blocks,
+                    blocks_beyond_size: inode.blocks_beyond_size,
+                });
+            }
+        }
+
+        let inode_next = inodes.keys().last().map_or(ROOT_INODE, |ino| ino + 1);
+        if meta.inode_next < inode_next {
+            report.inode_next = Some(Mismatch {
+                stored: meta.inode_next,
+                actual: inode_next,
+            });
+        }
+        if let Some(stored) = stored_usage {
+            if stored != usage {
+                report.usage = Some(Mismatch {
+                    stored,
+                    actual: usage,
+                });
+            }
+        }
+        if meta.live_mounts > 0 {
+            report.live_mounts = Some(meta.live_mounts);
+        }
+
+        info!(
+            "check filesystem({}): {} inodes, {} entries and {} blocks, clean: {}",
+            self.name,
+            report.inodes,
+            report.entries,
+            report.blocks,
+            report.is_clean()
+        );
+        if repair && (!report.is_clean() || report.live_mounts.is_some()) {
+            self.repair(&report, block_size).await?;
+            info!("repair filesystem({})", self.name);
+        }
+        Ok(report)
+    }
+
+    // Scan a batch of keys in `range` by a transaction of its own, return the range left.
+    async fn scan_batch(&self, range: Range<Key>) -> Result<(Vec<KvPair>, Option<Range<Key>>)> {
+        let end = range.end.clone();
+        let pairs: Vec<KvPair> = self
+            .spin_with_policy(move |_, txn| {
+                let range = range.clone();
+                Box::pin(async move { Ok(txn.scan(range, TiFs::SCAN_LIMIT).await?.collect()) })
+            })
+            .await?;
+        let rest = match pairs.last() {
+            Some(last) if pairs.len() == TiFs::SCAN_LIMIT as usize => {
+                // the smallest key after the last one
+                let mut next: Vec<u8> = last.key().clone().into();
+                next.push(0);
+                Some(next.into()..end)
+            }
+            _ => None,
+        };
+        Ok((pairs, rest))
+    }
+
+    // Fix the findings in `report` one by one, so that fixing one doesn't make another stale:
+    // link counts are counted without dangling entries, and usage counters are counted at last.
+    async fn repair(&self, report: &CheckReport, block_size: u64) -> Result<()> {
+        for entry in report.dangling_entries.iter() {
+            let (parent, name) = (entry.parent, entry.name.clone());
+            self.spin_with_policy(move |_, txn| {
+                let name = name.clone();
+                Box::pin(async move {
+                    let parent_is_dir = txn
+                        .read_inodes(&[parent])
+                        .await?
+                        .get(&parent)
+                        .map_or(false, |inode| inode.kind == FileType::Directory);
+                    if parent_is_dir {
+                        txn.remove_entry(parent, name.into()).await
+                    } else {
+                        txn.delete(ScopedKey::dir_entry(parent, &name)).await
+                    }
+                })
+            })
+            .await?;
+            warn!("remove dangling entry({}) of dir({})", entry.name, parent);
+        }
+
+        // counted after dangling entries are removed
+        for mismatch in report.dir_count_mismatches.iter().copied() {
+            self.spin_with_policy(move |_, txn| Box::pin(txn.repair_dir_entry_count(mismatch.ino)))
+                .await?;
+            warn!("recount entries of dir({})", mismatch.ino);
+        }
+
+        for ino in report.unreferenced_inodes.iter().copied() {
+            self.spin_with_policy(move |_, txn| Box::pin(txn.remove_inode(ino)))
+                .await?;
+            warn!("remove unreferenced inode({})", ino);
+        }
+
+        for mismatch in report.nlink_mismatches.iter().copied() {
+            self.spin_with_policy(move |_, txn| {
+                Box::pin(async move {
+                    let mut inode = txn.lock_inode(mismatch.ino).await?;
+                    inode.nlink = mismatch.links;
+                    txn.save_inode(&inode).await
+                })
+            })
+            .await?;
+            warn!(
+                "fix link count of inode({}) from {} to {}",
+                mismatch.ino, mismatch.nlink, mismatch.links
+            );
+        }
+
+        for mismatch in report.size_mismatches.iter().copied() {
+            self.spin_with_policy(move |_, txn| {
+                Box::pin(async move {
+                    let mut inode = txn.lock_inode(mismatch.ino).await?;
+                    let size = inode.size;
+                    inode.set_size(size);
+                    if inode.kind == FileType::RegularFile {
+                        let first_beyond = (size + block_size - 1) / block_size;
+                        txn.delete_blocks(inode.ino, first_beyond..u64::MAX).await?;
+                        inode.unmark_blocks(first_beyond..u64::MAX);
+                    }
+                    txn.save_inode(&inode).await
+                })
+            })
+            .await?;
+            warn!("fix blocks of inode({})", mismatch.ino);
+        }
+
+        for orphaned in report.orphaned_blocks.iter() {
+            let ino = orphaned.ino;
+            // blocks of large files are deleted in batches to keep transactions small
+            loop {
+                let deleted = self
+                    .spin_with_policy(move |_, txn| {
+                        Box::pin(async move {
+                            let keys: Vec<Key> = txn
+                                .scan(ScopedKey::block_range(ino, 0..u64::MAX), TiFs::SCAN_LIMIT)
+                                .await?
+                                .map(KvPair::into_key)
+                                .collect();
+                            for key in keys.iter() {
+                                txn.delete(key.clone()).await?;
+                            }
+                            Ok(keys.len())
+                        })
+                    })
+                    .await?;
+                if deleted < TiFs::SCAN_LIMIT as usize {
+                    break;
+                }
+            }
+            warn!("remove orphaned blocks of inode({})", ino);
+        }
+
+        let inode_next = report.inode_next.map(|mismatch| mismatch.actual);
+        let recount_usage = report.usage.is_some();
+        let reset_mounts = report.live_mounts.is_some();
+        if inode_next.is_some() || recount_usage || reset_mounts {
+            self.spin_with_policy(move |_, txn| {
+                Box::pin(async move {
+                    txn.update_meta(|meta| {
+                        if let Some(inode_next) = inode_next {
+                            meta.inode_next = meta.inode_next.max(inode_next);
+                        }
+                        if recount_usage {
+                            // counters absent are counted by scanning all inodes
+                            meta.usage = None;
+                        }
+                        if reset_mounts {
+                            meta.live_mounts = 0;
+                        }
+                    })
+                    .await?;
+                    txn.read_usage().await?;
+                    Ok(())
+                })
+            })
+            .await?;
+            warn!("fix counters in the meta");
+        }
+        Ok(())
+    }
+}
+
+// Count an entry of directory `parent` as a link of its inode,
+// return it if the directory or the inode doesn't exist.
+fn link_entry(
+    inodes: &mut BTreeMap<u64, InodeSummary>,
+    parent: u64,
+    item: DirItem,
+) -> Option<DanglingEntry> {
+    let parent_is_dir = inodes
+        .get(&parent)
+        .map_or(false, |inode| inode.kind == FileType::Directory);
+    let is_dir = match inodes.get_mut(&item.ino) {
+        Some(inode) if parent_is_dir => {
+            inode.links += 1;
+            inode.kind == FileType::Directory
+        }
+        _ => {
+            return Some(DanglingEntry {
+                parent,
+                name: item.name,
+                ino: item.ino,
+            })
+        }
+    };
+    if is_dir {
+        if let Some(parent) = inodes.get_mut(&parent) {
+            parent.subdirs += 1;
+        }
+    }
+    None
+}
@@ -0,0 +1,263 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use super::encryption::{FileKey, NONCE_LEN, TAG_LEN};
+use super::error::{FsError, Result};
+use crate::OptionValue;
+
+/// Codec to compress data blocks, chosen for a filesystem and stored in its meta.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Compression {
+    None,
+    Lz4,
+    /// Zstd with a compression level, 0 for the default level of zstd.
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// Written as `none`, `lz4`, `zstd` or `zstd:<level>`.
+impl OptionValue for Compression {
+    fn parse_value(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Self::None),
+            "lz4" => Some(Self::Lz4),
+            "zstd" => Some(Self::Zstd(0)),
+            _ => {
+                let level = value.strip_prefix("zstd:")?.parse().ok()?;
+                Some(Self::Zstd(level))
+            }
+        }
+    }
+
+    fn format_value(&self) -> String {
+        match self {
+            Self::None => "none".to_owned(),
+            Self::Lz4 => "lz4".to_owned(),
+            Self::Zstd(0) => "zstd".to_owned(),
+            Self::Zstd(level) => format!("zstd:{}", level),
+        }
+    }
+}
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+// set in the header byte if the value ends with a CRC32C of the header and the payload
+const CHECKSUM: u8 = 0x80;
+const CHECKSUM_LEN: usize = 4;
+// set in the header byte if the payload is encrypted, with the nonce prefixed to it
+const ENCRYPTED: u8 = 0x40;
+
+/// Encode a full block into a value, the payload is compressed and then encrypted by `key`.
+///
+/// The value starts with a byte of the codec and ends with a checksum, a block that
+/// doesn't shrink is stored raw between them. Blocks written by older versions are either
+/// unencoded, which are exactly a block in size, or encoded without the checksum.
+pub fn encode_block(
+    data: Vec<u8>,
+    compression: Compression,
+    block: u64,
+    key: Option<&FileKey>,
+) -> Vec<u8> {
+    let block_size = data.len();
+    let (codec, compressed) = match compression {
+        Compression::None => (RAW, Vec::new()),
+        Compression::Lz4 => (LZ4, lz4_flex::compress_prepend_size(&data)),
+        Compression::Zstd(level) => match zstd::encode_all(data.as_slice(), level) {
+            Ok(compressed) => (ZSTD, compressed),
+            Err(_) => (RAW, Vec::new()),
+        },
+    };
+
+    let overhead = match key {
+        Some(_) => 1 + NONCE_LEN + TAG_LEN + CHECKSUM_LEN,
+        None => 1 + CHECKSUM_LEN,
+    };
+    // an encoded value of exactly the block size would be taken as an unencoded block
+    let (codec, payload) = if codec != RAW && compressed.len() + overhead < block_size {
+        (codec, compressed)
+    } else {
+        (RAW, data)
+    };
+    let (header, payload) = match key {
+        Some(key) => (codec | CHECKSUM | ENCRYPTED, key.seal(block, &payload)),
+        None => (codec | CHECKSUM, payload),
+    };
+    let mut value = Vec::with_capacity(1 + payload.len() + CHECKSUM_LEN);
+    value.push(header);
+    value.extend(payload);
+    let checksum = crc32c::crc32c(&value);
+    value.extend(checksum.to_be_bytes().iter());
+    value
+}
+
+/// Whether a value of block is encrypted.
+pub fn is_encrypted(value: &[u8], block_size: u64) -> bool {
+    value.len() as u64 != block_size
+        && value
+            .first()
+            .map_or(false, |header| header & ENCRYPTED != 0)
+}
+
+/// Decode a value into a block of `block_size` bytes, encrypted values are decrypted by `key`.
+/// `ino` and `block` locate the value in a checksum mismatch.
+pub fn decode_block(
+    value: Vec<u8>,
+    block_size: u64,
+    ino: u64,
+    block: u64,
+    key: Option<&FileKey>,
+) -> Result<Vec<u8>> {
+    if value.len() as u64 == block_size {
+        return Ok(value);
+    }
+    let header = *value
+        .first()
+        .ok_or_else(|| FsError::Corruption("empty block".to_string()))?;
+    let payload = if header & CHECKSUM == 0 {
+        &value[1..]
+    } else {
+        if value.len() < 1 + CHECKSUM_LEN {
+            return Err(FsError::ChecksumMismatch { ino, block });
+        }
+        let (content, checksum) = value.split_at(value.len() - CHECKSUM_LEN);
+        let mut expected = [0; CHECKSUM_LEN];
+        expected.copy_from_slice(checksum);
+        if crc32c::crc32c(content) != u32::from_be_bytes(expected) {
+            return Err(FsError::ChecksumMismatch { ino, block });
+        }
+        &content[1..]
+    };
+    let payload = if header & ENCRYPTED == 0 {
+        Cow::Borrowed(payload)
+    } else {
+        let data = key
+            .and_then(|key| key.open(block, payload))
+            .ok_or(FsError::Undecryptable { ino, block })?;
+        Cow::Owned(data)
+    };
+    let data = match header & !(CHECKSUM | ENCRYPTED) {
+        RAW => payload.into_owned(),
+        LZ4 => lz4_flex::decompress_size_prepended(&payload)
+            .map_err(|err| FsError::Corruption(format!("invalid lz4 block: {}", err)))?,
+        ZSTD => zstd::decode_all(&payload[..])
+            .map_err(|err| FsError::Corruption(format!("invalid zstd block: {}", err)))?,
+        codec => {
+            return Err(FsError::Corruption(format!(
+                "unknown codec({}) of block",
+                codec
+            )))
+        }
+    };
+    if data.len() as u64 != block_size {
+        return Err(FsError::Corruption(format!(
+            "block of {} bytes, expect {}",
+            data.len(),
+            block_size
+        )));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_block, encode_block, Compression};
+    use crate::fs::error::FsError;
+    use crate::OptionValue;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn text_block() -> Vec<u8> {
+        b"2021-01-01 INFO request served\n"
+            .iter()
+            .cycle()
+            .take(BLOCK_SIZE)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_each_compression() {
+        for compression in &[Compression::None, Compression::Lz4, Compression::Zstd(0)] {
+            let value = encode_block(text_block(), *compression, 0, None);
+            if *compression != Compression::None {
+                assert!(value.len() < BLOCK_SIZE / 2, "{:?}", compression);
+            }
+            let data = decode_block(value, BLOCK_SIZE as u64, 1, 0, None).unwrap();
+            assert_eq!(data, text_block());
+        }
+    }
+
+    #[test]
+    fn stores_incompressible_blocks_raw() {
+        let data: Vec<u8> = (0..BLOCK_SIZE).map(|_| rand::random()).collect();
+        let value = encode_block(data.clone(), Compression::Zstd(0), 0, None);
+        assert_ne!(value.len(), BLOCK_SIZE);
+        assert_eq!(
+            decode_block(value, BLOCK_SIZE as u64, 1, 0, None).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn reads_unencoded_blocks() {
+        let value = text_block();
+        assert_eq!(
+            decode_block(value, BLOCK_SIZE as u64, 1, 0, None).unwrap(),
+            text_block()
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_values() {
+        for compression in &[Compression::None, Compression::Lz4] {
+            let mut value = encode_block(text_block(), *compression, 3, None);
+            let middle = value.len() / 2;
+            value[middle] ^= 1;
+            assert!(matches!(
+                decode_block(value, BLOCK_SIZE as u64, 1, 3, None),
+                Err(FsError::ChecksumMismatch { ino: 1, block: 3 })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        let mut value = encode_block(text_block(), Compression::Lz4, 0, None);
+        value.truncate(value.len() - 1);
+        assert!(matches!(
+            decode_block(value, BLOCK_SIZE as u64, 1, 0, None),
+            Err(FsError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            decode_block(vec![0x80, 0], BLOCK_SIZE as u64, 1, 0, None),
+            Err(FsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_values_without_checksum() {
+        let mut value = vec![2];
+        value.extend(zstd::encode_all(text_block().as_slice(), 0).unwrap());
+        assert_eq!(
+            decode_block(value, BLOCK_SIZE as u64, 1, 0, None).unwrap(),
+            text_block()
+        );
+    }
+
+    #[test]
+    fn parses_compression_options() {
+        for value in &["none", "lz4", "zstd", "zstd:19"] {
+            let compression = Compression::parse_value(value).unwrap();
+            assert_eq!(compression.format_value(), *value);
+        }
+        assert_eq!(Compression::parse_value("zstd:high"), None);
+        assert_eq!(Compression::parse_value("gzip"), None);
+    }
+}
@@ -0,0 +1,399 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use async_std::task::sleep;
+use bytestring::ByteString;
+use fuser::FileType;
+use tikv_client::{KvPair, TransactionClient};
+use tracing::{debug, info, warn};
+
+use super::compression::Compression;
+use super::encryption::EncryptionKey;
+use super::error::{FsError, Result};
+use super::inode::Inode;
+use super::key::ScopedKey;
+use super::mode::make_mode;
+use super::tikv_fs::TiFs;
+use super::transaction::Txn;
+
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// Key prefix of the filesystem.
+    pub prefix: Vec<u8>,
+    /// Bandwidth cap in bytes per second, a cap of 0 is ignored.
+    pub bwlimit: Option<u64>,
+    /// Replace files that already exist at the destination.
+    pub force: bool,
+    /// Master key of an encrypted filesystem.
+    pub encryption: Option<EncryptionKey>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyProgress {
+    pub dirs: u64,
+    pub files: u64,
+    pub symlinks: u64,
+    pub bytes: u64,
+}
+
+struct Copier<'a, F> {
+    client: &'a TransactionClient,
+    snapshot: Txn,
+    options: CopyOptions,
+    // names created must be portable
+    portable_names: bool,
+    compression: Compression,
+    progress: CopyProgress,
+    started: Instant,
+    on_progress: F,
+}
+
+impl<'a, F> Copier<'a, F>
+where
+    F: FnMut(&CopyProgress),
+{
+    const BATCH_BLOCKS: u32 = 1 << 6;
+
+    async fn run(&mut self, from: &str, to_parent: &str, to_name: &str) -> Result<()> {
+        let src = self.snapshot.resolve(from).await?;
+        let mut txn = self.begin().await?;
+        let parent = txn.resolve(to_parent).await;
+        txn.rollback().await?;
+
+        let mut pending = vec![(src, parent?, ByteString::from(to_name.to_string()))];
+        let mut dirs = Vec::new();
+        while let Some((src, parent, name)) = pending.pop() {
+            let inode = self.snapshot.read_inode(src).await?;
+            let link = match inode.kind {
+                FileType::Symlink => Some(self.snapshot.read_link(src).await?),
+                _ => None,
+            };
+
+            if self.portable_names {
+                TiFs::check_portable_name(&name)?;
+            }
+            let mut txn = self.begin().await?;
+            let result = make_entry(&mut txn, self.options.force, &inode, parent, name, link).await;
+            let dst = commit(txn, result).await?;
+            let ino = dst.ino;
+            debug!("copy inode({}) to inode({})", src, ino);
+
+            match inode.kind {
+                FileType::Directory => {
+                    for item in self.snapshot.read_dir(src).await? {
+                        pending.push((item.ino, ino, item.name.into()));
+                    }
+                    // directory attributes are restored after all children are created
+                    dirs.push((ino, inode));
+                    self.progress.dirs += 1;
+                }
+                FileType::RegularFile => {
+                    // the destination may be truncated by others meanwhile, then the copy fails
+                    // rather than restoring the size over the blocks deleted by the truncation
+                    let epoch = Some(dst.truncate_epoch);
+                    let result = match self.copy_blocks(&inode, ino, epoch).await {
+                        Ok(()) => self.restore_attr(ino, &inode, epoch).await,
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        if let Err(discard_err) = self.discard_blocks(&inode, ino).await {
+                            warn!("fail to discard blocks of inode({}): {}", ino, discard_err);
+                        }
+                        return Err(err);
+                    }
+                    self.progress.files += 1;
+                }
+                FileType::Symlink => {
+                    self.restore_attr(ino, &inode, None).await?;
+                    self.progress.symlinks += 1;
+                }
+                _ => {
+                    self.restore_attr(ino, &inode, None).await?;
+                    self.progress.files += 1;
+                }
+            }
+            (self.on_progress)(&self.progress);
+        }
+
+        for (ino, inode) in dirs.into_iter().rev() {
+            self.restore_attr(ino, &inode, None).await?;
+        }
+        Ok(())
+    }
+
+    // Copy the block keys that exist in the source, so holes of sparse files stay holes.
+    async fn copy_blocks(&mut self, src: &Inode, dst: u64, epoch: Option<u64>) -> Result<()> {
+        // inline data is decrypted when the inode is read, unless the key is missing
+        if src.inline_encrypted {
+            return Err(FsError::Undecryptable {
+                ino: src.ino,
+                block: 0,
+            });
+        }
+        if let Some(data) = &src.inline_data {
+            self.progress.bytes += data.len() as u64;
+            return Ok(());
+        }
+
+        let block_size = self.snapshot.block_size();
+        let end_block = (src.size + block_size - 1) / block_size;
+        let mut next_block = 0;
+        while next_block < end_block {
+            let pairs: Vec<KvPair> = self
+                .snapshot
+                .scan(
+                    ScopedKey::block_range(src.ino, next_block..end_block),
+                    Self::BATCH_BLOCKS,
+                )
+                .await?
+                .collect();
+            if pairs.is_empty() {
+                break;
+            }
+
+            let mut txn = self.begin().await?;
+            let result = put_blocks(&mut txn, dst, epoch, pairs).await;
+            let (last_block, bytes) = commit(txn, result).await?;
+
+            next_block = last_block + 1;
+            self.progress.bytes += bytes;
+            (self.on_progress)(&self.progress);
+            self.throttle().await;
+        }
+        Ok(())
+    }
+
+    // The size of `dst` is restored only after all blocks are copied, so the blocks copied
+    // before a failure are not visible, delete them before the file is extended over them.
+    // Blocks within the current size are kept, they're written by others after a truncation.
+    async fn discard_blocks(&self, src: &Inode, dst: u64) -> Result<()> {
+        let block_size = self.snapshot.block_size();
+        let end_block = (src.size + block_size - 1) / block_size;
+        let mut txn = self.begin().await?;
+        let inode = txn.read_inode(dst).await;
+        txn.rollback().await?;
+        let mut next_block = (inode?.size + block_size - 1) / block_size;
+        while next_block < end_block {
+            let batch_end = (next_block + Self::BATCH_BLOCKS as u64).min(end_block);
+            let mut txn = self.begin().await?;
+            let result = delete_blocks(&mut txn, dst, next_block..batch_end).await;
+            commit(txn, result).await?;
+            next_block = batch_end;
+        }
+        Ok(())
+    }
+
+    async fn begin(&self) -> Result<Txn> {
+        let txn = Txn::begin_optimistic(self.client, self.options.prefix.clone())
+            .await?
+            .with_stored_block_size()
+            .await?
+            .with_compression(self.compression);
+        Ok(match &self.options.encryption {
+            Some(key) => txn.with_encryption(key.clone()),
+            None => txn,
+        })
+    }
+
+    async fn restore_attr(&self, ino: u64, src: &Inode, epoch: Option<u64>) -> Result<()> {
+        let mut txn = self.begin().await?;
+        let result = restore_attr(&mut txn, ino, src, epoch).await;
+        commit(txn, result).await
+    }
+
+    async fn throttle(&self) {
+        if let Some(limit) = self.options.bwlimit.filter(|limit| *limit > 0) {
+            let expected = Duration::from_secs_f64(self.progress.bytes as f64 / limit as f64);
+            let elapsed = self.started.elapsed();
+            if expected > elapsed {
+                sleep(expected - elapsed).await;
+            }
+        }
+    }
+}
+
+async fn commit<T>(mut txn: Txn, result: Result<T>) -> Result<T> {
+    match result {
+        Ok(v) => {
+            txn.commit().await?;
+            Ok(v)
+        }
+        Err(err) => {
+            txn.rollback().await?;
+            Err(err)
+        }
+    }
+}
+
+async fn make_entry(
+    txn: &mut Txn,
+    force: bool,
+    src: &Inode,
+    parent: u64,
+    name: ByteString,
+    link: Option<Vec<u8>>,
+) -> Result<Inode> {
+    if let Some(existing) = txn.get_index(parent, name.clone()).await? {
+        let current = txn.read_inode(existing).await?;
+        if !force {
+            return Err(FsError::FileExist {
+                file: name.to_string(),
+            });
+        }
+        if current.kind == FileType::Directory {
+            if src.kind == FileType::Directory {
+                return Ok(current);
+            }
+            return Err(FsError::FileExist {
+                file: name.to_string(),
+            });
+        }
+        txn.unlink(parent, name.clone()).await?;
+    }
+
+    let mut inode = match src.kind {
+        FileType::Directory => {
+            txn.mkdir(parent, name, src.perm as _, src.gid, src.uid)
+                .await?
+        }
+        kind => {
+            let mode = make_mode(kind, src.perm);
+            txn.make_inode(parent, name, mode, src.gid, src.uid, src.rdev)
+                .await?
+        }
+    };
+
+    if let Some(link) = link {
+        txn.write_link(&mut inode, link.into()).await?;
+    }
+    Ok(inode)
+}
+
+async fn put_blocks(
+    txn: &mut Txn,
+    dst: u64,
+    epoch: Option<u64>,
+    pairs: Vec<KvPair>,
+) -> Result<(u64, u64)> {
+    txn.check_truncate_epoch(dst, epoch).await?;
+    let mut last_block = 0;
+    let mut bytes = 0;
+    for pair in pairs {
+        let src = match ScopedKey::parse(pair.key().into())? {
+            ScopedKey::Block { ino, block } => {
+                last_block = block;
+                ino
+            }
+            _ => unreachable!("the keys from scanning should be always valid block keys"),
+        };
+        let value = pair.into_value();
+        bytes += value.len() as u64;
+        txn.copy_block(src, dst, last_block, value).await?;
+    }
+    Ok((last_block, bytes))
+}
+
+async fn delete_blocks(txn: &mut Txn, dst: u64, blocks: Range<u64>) -> Result<()> {
+    for block in blocks {
+        txn.delete(ScopedKey::block(dst, block)).await?;
+    }
+    Ok(())
+}
+
+async fn restore_attr(txn: &mut Txn, ino: u64, src: &Inode, epoch: Option<u64>) -> Result<()> {
+    txn.check_truncate_epoch(ino, epoch).await?;
+    let mut inode = txn.read_inode(ino).await?;
+    inode.perm = src.perm;
+    inode.uid = src.uid;
+    inode.gid = src.gid;
+    inode.flags = src.flags;
+    inode.atime = src.atime;
+    inode.mtime = src.mtime;
+    inode.ctime = src.ctime;
+    inode.crtime = src.crtime;
+    if src.kind == FileType::RegularFile {
+        inode.inline_data = src.inline_data.clone();
+        // the stored blocks are copied as they are
+        inode.block_map = src.block_map.clone();
+        inode.set_size(src.size);
+        inode.data_version += 1;
+    }
+    txn.save_inode(&inode).await
+}
+
+/// Copy the tree at path `from` to path `to` within the same filesystem.
+///
+/// The source is read under the start timestamp of a single transaction, so the copy is
+/// consistent even if the source is modified concurrently; the destination is written in
+/// batched transactions. The timestamp is not refreshed, so the copy fails once the GC safe
+/// point of the cluster passes it, e.g. after `tikv_gc_life_time` of a TiDB sharing the cluster.
+///
+/// Files of an encrypted filesystem are copied with `options.encryption`, which must match
+/// the key the filesystem is encrypted with.
+pub async fn copy_tree<F>(
+    client: &TransactionClient,
+    from: &str,
+    to: &str,
+    options: CopyOptions,
+    on_progress: F,
+) -> Result<CopyProgress>
+where
+    F: FnMut(&CopyProgress),
+{
+    let to = to.trim_end_matches('/');
+    let mut segments = to.rsplitn(2, '/');
+    let to_name = segments.next().unwrap_or_default();
+    let to_parent = segments.next().unwrap_or_default();
+    if to_name.is_empty() {
+        return Err(FsError::FileExist {
+            file: "/".to_string(),
+        });
+    }
+
+    let mut snapshot = Txn::begin_optimistic(client, options.prefix.clone())
+        .await?
+        .with_stored_block_size()
+        .await?;
+    let meta = match snapshot.meta().await {
+        Ok(meta) => meta,
+        Err(err) => {
+            snapshot.rollback().await?;
+            return Err(err);
+        }
+    };
+    // data written with another key would be unreadable
+    match (&options.encryption, &meta.key_check) {
+        (Some(key), Some(check)) if key.verify(check) => (),
+        (None, None) => (),
+        _ => {
+            snapshot.rollback().await?;
+            return Err(FsError::EncryptionKeyMismatch);
+        }
+    }
+    if let Some(key) = &options.encryption {
+        snapshot = snapshot.with_encryption(key.clone());
+    }
+    let mut copier = Copier {
+        client,
+        portable_names: meta.portable_names,
+        compression: meta.compression,
+        snapshot,
+        options,
+        progress: CopyProgress::default(),
+        started: Instant::now(),
+        on_progress,
+    };
+
+    let result = copier.run(from, to_parent, to_name).await;
+    copier.snapshot.rollback().await?;
+    result?;
+
+    info!(
+        "copied {} to {}: {:?} in {:?}",
+        from,
+        to,
+        copier.progress,
+        copier.started.elapsed()
+    );
+    Ok(copier.progress)
+}
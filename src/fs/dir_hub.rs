@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::reply::DirItem;
+
+/// Entries of a directory with their cookies, sorted by the cookies.
+pub type DirSnapshot = Arc<Vec<(u64, DirItem)>>;
+
+#[derive(Debug, Default)]
+struct DirHandle {
+    ino: u64,
+    snapshot: Option<DirSnapshot>,
+}
+
+/// Registry of directory handlers opened by this mount.
+///
+/// Unlike file handlers, directory handlers are only kept in memory. Each of them holds
+/// a snapshot of the entries taken by the first `readdir` of a listing, so the following
+/// pages of the listing are read from it instead of scanning TiKV again, and keep working
+/// until `releasedir` even if the directory is removed meanwhile.
+#[derive(Debug)]
+pub struct DirHub {
+    next_fh: AtomicU64,
+    handles: Mutex<HashMap<u64, DirHandle>>,
+}
+
+impl Default for DirHub {
+    fn default() -> Self {
+        Self {
+            // 0 is left for directories read without opendir
+            next_fh: AtomicU64::new(1),
+            handles: Default::default(),
+        }
+    }
+}
+
+impl DirHub {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn make(&self, ino: u64) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(
+            fh,
+            DirHandle {
+                ino,
+                snapshot: None,
+            },
+        );
+        fh
+    }
+
+    pub fn snapshot(&self, ino: u64, fh: u64) -> Option<DirSnapshot> {
+        self.handles
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .filter(|handle| handle.ino == ino)
+            .and_then(|handle| handle.snapshot.clone())
+    }
+
+    /// Replace the snapshot of a handler, return false if it's not tracked.
+    pub fn save_snapshot(&self, ino: u64, fh: u64, snapshot: Option<DirSnapshot>) -> bool {
+        match self.handles.lock().unwrap().get_mut(&fh) {
+            Some(handle) if handle.ino == ino => {
+                handle.snapshot = snapshot;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove a handler from the hub, return false if it's not tracked.
+    pub fn close(&self, ino: u64, fh: u64) -> bool {
+        let mut handles = self.handles.lock().unwrap();
+        match handles.get(&fh) {
+            Some(handle) if handle.ino == ino => handles.remove(&fh).is_some(),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::{self, Debug};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::OptionValue;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+/// Index authenticated with data inlined in an inode, beyond the index of any block.
+pub const INLINE_INDEX: u64 = u64::MAX;
+
+// there is no inode 0, its key only seals the value checking the master key
+const CHECK_INODE: u64 = 0;
+const CHECK_PLAINTEXT: &[u8] = b"tifs";
+
+/// Master key to encrypt file data, each file is encrypted by a key derived from it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    /// Derive the key of the file `ino` by HKDF-SHA256, with the inode number as the info.
+    pub fn file_key(&self, ino: u64) -> FileKey {
+        let mut key = [0; KEY_LEN];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(&ino.to_be_bytes(), &mut key)
+            .expect("32 bytes is a valid length of HKDF-SHA256 output");
+        FileKey(Aes256Gcm::new(GenericArray::from_slice(&key)))
+    }
+
+    /// A value stored in the meta to tell whether a filesystem is mounted with the same key.
+    pub fn check_value(&self) -> Vec<u8> {
+        self.file_key(CHECK_INODE).seal(0, CHECK_PLAINTEXT)
+    }
+
+    pub fn verify(&self, check_value: &[u8]) -> bool {
+        self.file_key(CHECK_INODE).open(0, check_value).as_deref() == Some(CHECK_PLAINTEXT)
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+/// Written as 64 hex digits, the key is never formatted so that it doesn't end up in logs.
+impl OptionValue for EncryptionKey {
+    fn parse_value(value: &str) -> Option<Self> {
+        if value.len() != KEY_LEN * 2 || !value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut key = [0; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(Self(key))
+    }
+
+    fn format_value(&self) -> String {
+        "<redacted>".to_owned()
+    }
+}
+
+/// AES-256-GCM key of a single file.
+pub struct FileKey(Aes256Gcm);
+
+impl FileKey {
+    /// Encrypt `data` with a random nonce and authenticate `index` as the additional data,
+    /// so a value cannot be moved to another index. The nonce is prefixed to the ciphertext.
+    pub fn seal(&self, index: u64, data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = self
+            .0
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: &index.to_be_bytes(),
+                },
+            )
+            .expect("data of a block is never too long for AES-GCM");
+        let mut value = Vec::with_capacity(NONCE_LEN + sealed.len());
+        value.extend_from_slice(&nonce);
+        value.extend(sealed);
+        value
+    }
+
+    /// Decrypt a value sealed with the same `index`, None if it isn't authentic.
+    pub fn open(&self, index: u64, value: &[u8]) -> Option<Vec<u8>> {
+        if value.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, sealed) = value.split_at(NONCE_LEN);
+        self.0
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &index.to_be_bytes(),
+                },
+            )
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EncryptionKey, KEY_LEN};
+    use crate::OptionValue;
+
+    fn master_key(byte: u8) -> EncryptionKey {
+        EncryptionKey::new([byte; KEY_LEN])
+    }
+
+    #[test]
+    fn opens_sealed_data() {
+        let key = master_key(1).file_key(2);
+        let value = key.seal(3, b"secret");
+        assert_eq!(key.open(3, &value).as_deref(), Some(&b"secret"[..]));
+    }
+
+    #[test]
+    fn rejects_another_block_index() {
+        let key = master_key(1).file_key(2);
+        let value = key.seal(3, b"secret");
+        assert_eq!(key.open(4, &value), None);
+    }
+
+    #[test]
+    fn rejects_another_file_or_tampered_data() {
+        let value = master_key(1).file_key(2).seal(3, b"secret");
+        assert_eq!(master_key(1).file_key(5).open(3, &value), None);
+        let mut tampered = value;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(master_key(1).file_key(2).open(3, &tampered), None);
+        assert_eq!(master_key(1).file_key(2).open(3, &[0; 8]), None);
+    }
+
+    #[test]
+    fn verifies_the_master_key() {
+        let check_value = master_key(1).check_value();
+        assert!(master_key(1).verify(&check_value));
+        assert!(!master_key(2).verify(&check_value));
+    }
+
+    #[test]
+    fn parses_hex_keys() {
+        let hex = "01".repeat(KEY_LEN);
+        assert!(EncryptionKey::parse_value(&hex) == Some(master_key(1)));
+        assert!(EncryptionKey::parse_value(&hex[2..]).is_none());
+        assert!(EncryptionKey::parse_value(&"0g".repeat(KEY_LEN)).is_none());
+        assert_eq!(master_key(1).format_value(), "<redacted>");
+    }
+}
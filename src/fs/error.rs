@@ -19,6 +19,12 @@ pub enum FsError {
     #[error("name of file({file}) is too long")]
     NameTooLong { file: String },
 
+    #[error("invalid file name({name:?})")]
+    InvalidName { name: String },
+
+    #[error("name({name:?}) is not portable: {reason}")]
+    NonPortableName { name: String, reason: String },
+
     #[error("cannot find path({file})")]
     FileNotFound { file: String },
 
@@ -37,12 +43,24 @@ pub enum FsError {
     #[error("unknown whence({whence})")]
     UnknownWhence { whence: i32 },
 
+    #[error("no data or hole after offset({offset}) of ino({ino})")]
+    NoSeekTarget { ino: u64, offset: i64 },
+
     #[error("cannot find block(<{inode}>[{block}])")]
     BlockNotFound { inode: u64, block: u64 },
 
     #[error("dir({dir}) not empty")]
     DirNotEmpty { dir: String },
 
+    #[error("inode({ino}) is a directory")]
+    IsADirectory { ino: u64 },
+
+    #[error("inode({ino}) is not a symlink")]
+    NotSymlink { ino: u64 },
+
+    #[error("no extended attribute({name}) of inode({ino})")]
+    XattrNotFound { ino: u64, name: String },
+
     #[error("invalid string")]
     InvalidStr,
 
@@ -52,6 +70,9 @@ pub enum FsError {
     #[error("key error: {0}")]
     KeyError(String),
 
+    #[error("region of keys is moved or changes leader: {0}")]
+    RegionError(String),
+
     #[error("excess max retry times: {0}")]
     RetryTimesExcess(u64),
 
@@ -61,8 +82,68 @@ pub enum FsError {
     #[error("unknown error({0})")]
     UnknownError(String),
 
+    #[error("connection to the cluster fails: {0}")]
+    Disconnected(String),
+
+    #[error("entry({file}) is changed after the kernel looked it up")]
+    StaleEntry { file: String },
+
     #[error("invalid lock")]
     InvalidLock,
+
+    #[error("timeout waiting for lock")]
+    LockTimeout,
+
+    #[error("mount filesystem({expected}), but found filesystem({found})")]
+    NameMismatch { expected: String, found: String },
+
+    #[error("unsupported layout version({version})")]
+    UnsupportedLayout { version: u32 },
+
+    #[error("mount with block size({expected}), but the filesystem uses block size({found})")]
+    BlockSizeMismatch { expected: u64, found: u64 },
+
+    #[error("the filesystem is encrypted by another key, or mounted without one")]
+    EncryptionKeyMismatch,
+
+    #[error("operation not supported: {0}")]
+    NotSupported(String),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("data corruption: {0}")]
+    Corruption(String),
+
+    #[error("checksum mismatch of block(<{ino}>[{block}])")]
+    ChecksumMismatch { ino: u64, block: u64 },
+
+    #[error("cannot decrypt block(<{ino}>[{block}]), the encryption key is missing or mismatched")]
+    Undecryptable { ino: u64, block: u64 },
+
+    #[error("transaction conflicts after {attempts} attempts")]
+    TooManyRetries { attempts: u32 },
+
+    #[error("file of ino({ino}) is truncated during the write")]
+    Truncated { ino: u64 },
+
+    #[error("the filesystem is mounted read-only")]
+    ReadOnly,
+
+    #[error("only root can create device({file})")]
+    DeviceNotPermitted { file: String },
+
+    #[error("the filesystem is not initialized")]
+    Uninitialized,
+
+    #[error("{operation} of inode({ino}) is not permitted")]
+    NotPermitted { ino: u64, operation: &'static str },
+
+    #[error("{mounts} mounts of the filesystem are alive or crashed")]
+    Mounted { mounts: u64 },
+
+    #[error("access to inode({ino}) is denied")]
+    AccessDenied { ino: u64 },
 }
 
 pub type Result<T> = std::result::Result<T, FsError>;
@@ -91,6 +172,13 @@ impl From<tikv_client::Error> for FsError {
 
         match err {
             KeyError(err) => Self::KeyError(format!("{:?}", err)),
+            // stale region cache, split or merged regions and leader changes pass on retry
+            RegionError(err) => Self::RegionError(format!("{:?}", err)),
+            err @ RegionForKeyNotFound { .. } | err @ LeaderNotFound { .. } => {
+                Self::RegionError(err.to_string())
+            }
+            Grpc(err) => Self::Disconnected(err.to_string()),
+            Io(err) => Self::Disconnected(err.to_string()),
             _ => Self::UnknownError(err.to_string()),
         }
     }
@@ -98,23 +186,66 @@ impl From<tikv_client::Error> for FsError {
 
 impl Into<libc::c_int> for FsError {
     fn into(self) -> libc::c_int {
+        self.errno()
+    }
+}
+
+impl FsError {
+    /// The errno replied to the kernel for this error.
+    pub fn errno(&self) -> libc::c_int {
         use FsError::*;
 
         match self {
             Unimplemented => libc::ENOSYS,
             NameTooLong { file: _ } => libc::ENAMETOOLONG,
+            InvalidName { name: _ } => libc::EINVAL,
+            NonPortableName { name: _, reason: _ } => libc::EINVAL,
             FileNotFound { file: _ } => libc::ENOENT,
             FileExist { file: _ } => libc::EEXIST,
-            InodeNotFound { inode: _ } => libc::EFAULT,
+            InodeNotFound { inode: _ } => libc::ENOENT,
             FhNotFound { ino: _, fh: _ } => libc::EBADF,
             InvalidOffset { ino: _, offset: _ } => libc::EINVAL,
             UnknownWhence { whence: _ } => libc::EINVAL,
+            NoSeekTarget { ino: _, offset: _ } => libc::ENXIO,
             BlockNotFound { inode: _, block: _ } => libc::EINVAL,
             DirNotEmpty { dir: _ } => libc::ENOTEMPTY,
+            IsADirectory { ino: _ } => libc::EISDIR,
+            NotSymlink { ino: _ } => libc::EINVAL,
+            XattrNotFound { ino: _, name: _ } => libc::ENODATA,
             UnknownFileType => libc::EINVAL,
             KeyError(_) => libc::EAGAIN,
+            RegionError(_) => libc::EAGAIN,
             RetryTimesExcess(_) => libc::EAGAIN,
             InvalidStr => libc::EINVAL,
+            LockTimeout => libc::ETIMEDOUT,
+            NameMismatch {
+                expected: _,
+                found: _,
+            } => libc::EINVAL,
+            UnsupportedLayout { version: _ } => libc::EINVAL,
+            BlockSizeMismatch {
+                expected: _,
+                found: _,
+            } => libc::EINVAL,
+            EncryptionKeyMismatch => libc::EACCES,
+            NotSupported(_) => libc::EOPNOTSUPP,
+            InvalidConfig(_) => libc::EINVAL,
+            Corruption(_) => libc::EIO,
+            ChecksumMismatch { ino: _, block: _ } => libc::EIO,
+            Undecryptable { ino: _, block: _ } => libc::EIO,
+            TooManyRetries { attempts: _ } => libc::EBUSY,
+            Truncated { ino: _ } => libc::EIO,
+            Disconnected(_) => libc::EIO,
+            StaleEntry { file: _ } => libc::ESTALE,
+            ReadOnly => libc::EROFS,
+            DeviceNotPermitted { file: _ } => libc::EPERM,
+            Uninitialized => libc::ENODEV,
+            NotPermitted {
+                ino: _,
+                operation: _,
+            } => libc::EPERM,
+            Mounted { mounts: _ } => libc::EBUSY,
+            AccessDenied { ino: _ } => libc::EACCES,
             _ => libc::EFAULT,
         }
     }
@@ -3,15 +3,22 @@ use serde::{Deserialize, Serialize};
 use super::error::{FsError, Result};
 use super::serialize::{deserialize, serialize, ENCODING};
 
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
+/// A file handler stored in TiKV, with the open flags that writes depend on.
+///
+/// File positions are tracked by the kernel, handlers stored by older versions
+/// carry a cursor, which is ignored.
+#[derive(
+    Debug, Default, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize,
+)]
 pub struct FileHandler {
-    // TODO: add open flags
-    pub cursor: u64,
+    /// Opened with `O_APPEND`, each write goes to the end of the file.
+    #[serde(default)]
+    pub append_mode: bool,
 }
 
 impl FileHandler {
-    pub const fn new(cursor: u64) -> Self {
-        Self { cursor }
+    pub const fn new(append_mode: bool) -> Self {
+        Self { append_mode }
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>> {
@@ -30,9 +37,3 @@ impl FileHandler {
         })
     }
 }
-
-impl Default for FileHandler {
-    fn default() -> Self {
-        Self::new(0)
-    }
-}
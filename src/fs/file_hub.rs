@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::lock_wait::{Clock, SystemClock};
+
+/// State of a file handler opened by this mount.
+#[derive(Debug, Clone, Copy)]
+pub struct HandleState {
+    pub last_access: Instant,
+}
+
+/// Registry of file handlers opened by this mount, lookup counts of inodes and owners of locks
+/// taken through this mount.
+///
+/// The file handlers and locks themselves are stored in TiKV, the hub only tracks which of them
+/// belong to this mount, so that handlers leaked by a broken kernel connection can be reaped,
+/// and both are released on unmount.
+#[derive(Debug, Default)]
+pub struct FileHub<C = SystemClock> {
+    clock: C,
+    handles: Mutex<HashMap<(u64, u64), HandleState>>,
+    lookups: Mutex<HashMap<u64, u64>>,
+    lock_owners: Mutex<HashMap<u64, HashSet<u64>>>,
+    reaped: AtomicU64,
+    next_local_fh: AtomicU64,
+}
+
+impl FileHub {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl<C: Clock> FileHub<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            handles: Default::default(),
+            lookups: Default::default(),
+            lock_owners: Default::default(),
+            reaped: Default::default(),
+            next_local_fh: Default::default(),
+        }
+    }
+
+    pub fn make(&self, ino: u64, fh: u64) {
+        self.handles.lock().unwrap().insert(
+            (ino, fh),
+            HandleState {
+                last_access: self.clock.now(),
+            },
+        );
+    }
+
+    /// Make a handler only kept in memory, for read-only mounts which store no handlers in TiKV.
+    pub fn make_local(&self, ino: u64) -> u64 {
+        let fh = self.next_local_fh.fetch_add(1, Ordering::Relaxed);
+        self.make(ino, fh);
+        fh
+    }
+
+    pub fn get(&self, ino: u64, fh: u64) -> Option<HandleState> {
+        self.handles.lock().unwrap().get(&(ino, fh)).copied()
+    }
+
+    pub fn touch(&self, ino: u64, fh: u64) {
+        if let Some(state) = self.handles.lock().unwrap().get_mut(&(ino, fh)) {
+            state.last_access = self.clock.now();
+        }
+    }
+
+    /// Remove a handler from the hub, return false if it's not tracked.
+    /// Only the caller getting true may release the handler, so racing releases close it exactly once.
+    pub fn close(&self, ino: u64, fh: u64) -> bool {
+        self.handles.lock().unwrap().remove(&(ino, fh)).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.lock().unwrap().len()
+    }
+
+    /// Handlers without any operation for longer than `timeout`.
+    pub fn idle(&self, timeout: Duration) -> Vec<(u64, u64)> {
+        let now = self.clock.now();
+        self.handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| now.saturating_duration_since(state.last_access) >= timeout)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    pub fn handles(&self) -> Vec<(u64, u64)> {
+        self.handles.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn handles_of(&self, ino: u64) -> Vec<(u64, u64)> {
+        self.handles
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(handle_ino, _)| *handle_ino == ino)
+            .copied()
+            .collect()
+    }
+
+    pub fn lookup(&self, ino: u64) {
+        *self.lookups.lock().unwrap().entry(ino).or_insert(0) += 1;
+    }
+
+    /// Decrease the lookup count, return true if it reaches zero.
+    pub fn forget(&self, ino: u64, nlookup: u64) -> bool {
+        let mut lookups = self.lookups.lock().unwrap();
+        let count = lookups.entry(ino).or_insert(0);
+        *count = count.saturating_sub(nlookup);
+        if *count == 0 {
+            lookups.remove(&ino);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn add_lock_owner(&self, ino: u64, owner: u64) {
+        self.lock_owners
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_default()
+            .insert(owner);
+    }
+
+    pub fn remove_lock_owner(&self, ino: u64, owner: u64) {
+        let mut lock_owners = self.lock_owners.lock().unwrap();
+        if let Some(owners) = lock_owners.get_mut(&ino) {
+            owners.remove(&owner);
+            if owners.is_empty() {
+                lock_owners.remove(&ino);
+            }
+        }
+    }
+
+    /// Whether `owner` may hold a lock on `ino`, taken through this mount.
+    pub fn holds_lock(&self, ino: u64, owner: u64) -> bool {
+        self.lock_owners
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .map_or(false, |owners| owners.contains(&owner))
+    }
+
+    /// Take the owners of locks that may still be held, by inode.
+    pub fn take_lock_owners(&self) -> Vec<(u64, Vec<u64>)> {
+        self.lock_owners
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(ino, owners)| (ino, owners.into_iter().collect()))
+            .collect()
+    }
+
+    pub fn count_reaped(&self) {
+        self.reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of handlers reaped since mount.
+    pub fn reaped(&self) -> u64 {
+        self.reaped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::lock_wait::ManualClock;
+    use super::FileHub;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn handles_idle_after_timeout() {
+        let clock = ManualClock::new();
+        let hub = FileHub::with_clock(clock.clone());
+        hub.make(1, 10);
+        clock.advance(TIMEOUT / 2);
+        hub.make(2, 20);
+        assert!(hub.idle(TIMEOUT).is_empty());
+        clock.advance(TIMEOUT / 2);
+        assert_eq!(hub.idle(TIMEOUT), vec![(1, 10)]);
+        clock.advance(TIMEOUT / 2);
+        let mut idle = hub.idle(TIMEOUT);
+        idle.sort();
+        assert_eq!(idle, vec![(1, 10), (2, 20)]);
+    }
+
+    #[test]
+    fn access_resets_idle_time() {
+        let clock = ManualClock::new();
+        let hub = FileHub::with_clock(clock.clone());
+        hub.make(1, 10);
+        clock.advance(TIMEOUT - Duration::from_secs(1));
+        hub.touch(1, 10);
+        clock.advance(TIMEOUT - Duration::from_secs(1));
+        assert!(hub.idle(TIMEOUT).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(hub.idle(TIMEOUT), vec![(1, 10)]);
+        // a closed handler is not reaped again
+        assert!(hub.close(1, 10));
+        assert!(hub.idle(TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn tracks_lock_owners_by_inode() {
+        let hub = FileHub::new();
+        hub.add_lock_owner(1, 10);
+        assert!(hub.holds_lock(1, 10));
+        assert!(!hub.holds_lock(1, 11));
+        assert!(!hub.holds_lock(2, 10));
+        hub.remove_lock_owner(1, 10);
+        assert!(!hub.holds_lock(1, 10));
+        assert!(hub.take_lock_owners().is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use fuser::FileAttr;
+
+use crate::{MountOption, OptionValue};
+
+/// Ids of this host mapped to ids stored in the filesystem, `count` ids from each start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub local: u32,
+    pub stored: u32,
+    pub count: u32,
+}
+
+/// Ranges of ids mapped between this host and the filesystem, ids out of every range
+/// are mapped to `NOBODY` both ways.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap(pub Vec<IdRange>);
+
+impl IdMap {
+    pub const NOBODY: u32 = 65534;
+
+    pub fn to_stored(&self, id: u32) -> u32 {
+        self.0
+            .iter()
+            .find(|range| id >= range.local && id - range.local < range.count)
+            .map_or(Self::NOBODY, |range| range.stored + (id - range.local))
+    }
+
+    pub fn to_local(&self, id: u32) -> u32 {
+        self.0
+            .iter()
+            .find(|range| id >= range.stored && id - range.stored < range.count)
+            .map_or(Self::NOBODY, |range| range.local + (id - range.stored))
+    }
+}
+
+/// Written as ranges of `<local>:<stored>:<count>` separated by `/`,
+/// e.g. `uid_map=0:0:1/1000:2000:100`.
+impl OptionValue for IdMap {
+    fn parse_value(value: &str) -> Option<Self> {
+        let mut ranges = Vec::new();
+        for range in value.split('/') {
+            let mut fields = range.split(':').map(|field| field.parse::<u32>().ok());
+            let local = fields.next()??;
+            let stored = fields.next()??;
+            let count = fields.next()??;
+            if fields.next().is_some()
+                || count == 0
+                || local.checked_add(count - 1).is_none()
+                || stored.checked_add(count - 1).is_none()
+            {
+                return None;
+            }
+            ranges.push(IdRange {
+                local,
+                stored,
+                count,
+            });
+        }
+        Some(Self(ranges))
+    }
+
+    fn format_value(&self) -> String {
+        self.0
+            .iter()
+            .map(|range| format!("{}:{}:{}", range.local, range.stored, range.count))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// How owners of files are translated between this host and the filesystem,
+/// by `squash_uid` and `squash_gid` or else by `uid_map` and `gid_map`.
+///
+/// Ids are mapped to the stored ones as they are written and as the caller of an operation
+/// is checked against them, and mapped back to local ones in attributes replied to the kernel.
+#[derive(Debug, Clone, Default)]
+pub struct IdMapping {
+    squash_uid: Option<u32>,
+    squash_gid: Option<u32>,
+    uid_map: Option<IdMap>,
+    gid_map: Option<IdMap>,
+}
+
+impl IdMapping {
+    pub fn from_mount_options(options: &[MountOption]) -> Self {
+        let mut mapping = Self::default();
+        for option in options {
+            match option {
+                MountOption::SquashUid(uid) => mapping.squash_uid = Some(*uid),
+                MountOption::SquashGid(gid) => mapping.squash_gid = Some(*gid),
+                MountOption::UidMap(map) => mapping.uid_map = Some(map.clone()),
+                MountOption::GidMap(map) => mapping.gid_map = Some(map.clone()),
+                _ => (),
+            }
+        }
+        mapping
+    }
+
+    pub fn stored_uid(&self, uid: u32) -> u32 {
+        Self::map(self.squash_uid, &self.uid_map, uid, IdMap::to_stored)
+    }
+
+    pub fn stored_gid(&self, gid: u32) -> u32 {
+        Self::map(self.squash_gid, &self.gid_map, gid, IdMap::to_stored)
+    }
+
+    /// Attributes with owners of this host, to be replied to the kernel.
+    pub fn local_attr(&self, mut attr: FileAttr) -> FileAttr {
+        attr.uid = Self::map(self.squash_uid, &self.uid_map, attr.uid, IdMap::to_local);
+        attr.gid = Self::map(self.squash_gid, &self.gid_map, attr.gid, IdMap::to_local);
+        attr
+    }
+
+    fn map(squash: Option<u32>, map: &Option<IdMap>, id: u32, f: fn(&IdMap, u32) -> u32) -> u32 {
+        match (squash, map) {
+            (Some(squash), _) => squash,
+            (None, Some(map)) => f(map, id),
+            (None, None) => id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdMap, IdMapping};
+    use crate::{MountOption, OptionValue};
+
+    fn map(value: &str) -> IdMap {
+        IdMap::parse_value(value).unwrap()
+    }
+
+    #[test]
+    fn maps_ids_in_ranges_both_ways() {
+        let map = map("0:0:1/1000:2000:100");
+        assert_eq!(map.to_stored(0), 0);
+        assert_eq!(map.to_stored(1000), 2000);
+        assert_eq!(map.to_stored(1099), 2099);
+        assert_eq!(map.to_local(2000), 1000);
+    }
+
+    #[test]
+    fn maps_ids_out_of_ranges_to_nobody() {
+        let map = map("1000:2000:100");
+        assert_eq!(map.to_stored(999), IdMap::NOBODY);
+        assert_eq!(map.to_stored(1100), IdMap::NOBODY);
+        assert_eq!(map.to_stored(0), IdMap::NOBODY);
+        assert_eq!(map.to_local(1000), IdMap::NOBODY);
+    }
+
+    #[test]
+    fn owners_round_trip_between_hosts() {
+        let host_a = map("1000:2000:1");
+        let host_b = map("1000:2000:1");
+        assert_eq!(host_b.to_local(host_a.to_stored(1000)), 1000);
+    }
+
+    #[test]
+    fn squash_overrides_maps() {
+        let mapping = IdMapping::from_mount_options(&[
+            MountOption::UidMap(map("1000:2000:100")),
+            MountOption::SquashUid(500),
+        ]);
+        assert_eq!(mapping.stored_uid(1000), 500);
+        assert_eq!(mapping.stored_gid(1000), 1000);
+    }
+
+    #[test]
+    fn parses_id_maps() {
+        assert_eq!(
+            map("0:0:1/1000:2000:100").format_value(),
+            "0:0:1/1000:2000:100"
+        );
+        for invalid in &[
+            "1000:2000",
+            "1000:2000:0",
+            "1:2:3:4",
+            "a:2:3",
+            "4294967295:0:2",
+        ] {
+            assert_eq!(IdMap::parse_value(invalid), None, "{}", invalid);
+        }
+    }
+}
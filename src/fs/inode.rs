@@ -1,16 +1,24 @@
+use super::block_map::BlockMap;
 use super::error::{FsError, Result};
 use super::serialize::{deserialize, serialize, ENCODING};
-use super::tikv_fs::TiFs;
-use fuser::FileAttr;
+use fuser::{FileAttr, FileType};
 use libc::F_UNLCK;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::ops::{Deref, DerefMut};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut, Range};
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct LockState {
     pub owner_set: HashSet<u64>,
     pub lk_type: i32,
+    /// Pid of the process holding the lock for each owner, only meaningful on the host
+    /// of the mount that took the lock.
+    #[serde(default)]
+    pub owner_pids: HashMap<u64, u32>,
+    /// Id of the mount that took the lock for each owner, only that mount may tell
+    /// whether the process of an owner is dead.
+    #[serde(default)]
+    pub owner_mounts: HashMap<u64, u64>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -20,11 +28,36 @@ pub struct Inode {
     pub inline_data: Option<Vec<u8>>,
     pub next_fh: u64,
     pub opened_fh: u64,
+    /// Entries of a directory are stored as separate keys,
+    /// otherwise as a single value in the first block, the layout before version 2.
+    #[serde(default)]
+    pub keyed_entries: bool,
+    /// Blocks stored of a regular file, None if they are not tracked,
+    /// e.g. files created by older versions or too fragmented.
+    #[serde(default)]
+    pub block_map: Option<BlockMap>,
+    /// Bumped by each explicit change of the size, so a write spanning several transactions
+    /// can tell whether the file is truncated under it.
+    #[serde(default)]
+    pub truncate_epoch: u64,
+    /// The inline data is sealed by the key of the file, it's decrypted once the inode is read
+    /// by a transaction holding the encryption key.
+    #[serde(default)]
+    pub inline_encrypted: bool,
+    /// Tells the inode from earlier ones of the same number, which the kernel may still cache.
+    /// Zero for inodes created by older versions.
+    #[serde(default)]
+    pub generation: u64,
+    /// Bumped by each change of the data, blocks cached at another version are stale.
+    /// Unlike the mtime, it cannot be set by users.
+    #[serde(default)]
+    pub data_version: u64,
 }
 
 impl Inode {
     fn update_blocks(&mut self) {
-        self.blocks = (self.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        let blksize = self.blksize as u64;
+        self.blocks = (self.size + blksize - 1) / blksize;
     }
 
     pub fn set_size(&mut self, size: u64) {
@@ -32,6 +65,32 @@ impl Inode {
         self.update_blocks();
     }
 
+    /// Record `blocks` as stored.
+    pub fn mark_blocks(&mut self, blocks: Range<u64>) {
+        if let Some(map) = self.block_map.as_mut() {
+            map.insert(blocks);
+        }
+        self.limit_block_map();
+    }
+
+    /// Record `blocks` as holes.
+    pub fn unmark_blocks(&mut self, blocks: Range<u64>) {
+        if let Some(map) = self.block_map.as_mut() {
+            map.remove(blocks);
+        }
+        self.limit_block_map();
+    }
+
+    fn limit_block_map(&mut self) {
+        if self
+            .block_map
+            .as_ref()
+            .map_or(false, |map| map.runs() > BlockMap::MAX_RUNS)
+        {
+            self.block_map = None;
+        }
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>> {
         serialize(self).map_err(|err| FsError::Serialize {
             target: "inode",
@@ -57,6 +116,15 @@ impl From<FileAttr> for Inode {
             inline_data: None,
             next_fh: 0,
             opened_fh: 0,
+            keyed_entries: attr.kind == FileType::Directory,
+            block_map: match attr.kind {
+                FileType::RegularFile => Some(BlockMap::new()),
+                _ => None,
+            },
+            truncate_epoch: 0,
+            inline_encrypted: false,
+            generation: 0,
+            data_version: 0,
         }
     }
 }
@@ -89,6 +157,99 @@ impl DerefMut for Inode {
 
 impl LockState {
     pub fn new(owner_set: HashSet<u64>, lk_type: i32) -> LockState {
-        LockState { owner_set, lk_type }
+        LockState {
+            owner_set,
+            lk_type,
+            owner_pids: HashMap::new(),
+            owner_mounts: HashMap::new(),
+        }
+    }
+
+    /// Record `owner` as holding the lock, taken by process `pid` through mount `mount`.
+    pub fn add_owner(&mut self, owner: u64, pid: u32, mount: u64) {
+        self.owner_set.insert(owner);
+        self.owner_pids.insert(owner, pid);
+        self.owner_mounts.insert(owner, mount);
+    }
+
+    /// Release the lock held by `owner`, return false if it holds none.
+    pub fn release(&mut self, owner: u64) -> bool {
+        let held = self.owner_set.remove(&owner);
+        self.owner_pids.remove(&owner);
+        self.owner_mounts.remove(&owner);
+        if self.owner_set.is_empty() {
+            self.lk_type = F_UNLCK;
+        }
+        held
+    }
+
+    /// Release locks taken through mount `mount` by owners whose process no longer exists,
+    /// pids of owners of other mounts may belong to other hosts.
+    /// Return true if any owner is removed.
+    pub fn remove_dead_owners(&mut self, mount: u64) -> bool {
+        let owner_mounts = &self.owner_mounts;
+        let dead_owners: Vec<u64> = self
+            .owner_pids
+            .iter()
+            .filter(|(owner, pid)| owner_mounts.get(owner) == Some(&mount) && !process_alive(**pid))
+            .map(|(owner, _)| *owner)
+            .collect();
+
+        for owner in dead_owners.iter() {
+            self.owner_set.remove(owner);
+            self.owner_pids.remove(owner);
+            self.owner_mounts.remove(owner);
+        }
+        if self.owner_set.is_empty() {
+            self.lk_type = F_UNLCK;
+        }
+        !dead_owners.is_empty()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    std::path::Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+    || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use libc::{F_RDLCK, F_UNLCK};
+
+    use super::LockState;
+
+    // beyond the pid limit of linux and FreeBSD
+    const DEAD_PID: u32 = i32::MAX as u32;
+
+    #[test]
+    fn removes_dead_owners_of_this_mount_only() {
+        let mut state = LockState::new(HashSet::new(), F_RDLCK);
+        state.add_owner(1, DEAD_PID, 10);
+        state.add_owner(2, DEAD_PID, 20);
+        state.add_owner(3, std::process::id(), 10);
+
+        assert!(state.remove_dead_owners(10));
+        assert_eq!(state.owner_set, vec![2, 3].into_iter().collect());
+        assert!(!state.owner_mounts.contains_key(&1));
+        assert!(!state.remove_dead_owners(10));
+        assert_eq!(state.lk_type, F_RDLCK);
+    }
+
+    #[test]
+    fn unlocks_once_the_last_owner_is_released() {
+        let mut state = LockState::new(HashSet::new(), F_RDLCK);
+        state.add_owner(1, DEAD_PID, 10);
+        assert!(!state.release(2));
+        assert!(state.release(1));
+        assert_eq!(state.lk_type, F_UNLCK);
+        assert!(state.owner_pids.is_empty() && state.owner_mounts.is_empty());
     }
 }
@@ -0,0 +1,31 @@
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Inode numbers leased by this mount from the meta.
+///
+/// Numbers are handed out from the lease without touching `inode_next` in the meta,
+/// so that mounts creating files at the same time don't conflict on it. Numbers left
+/// in the lease on unmount are never used, which leaves gaps in the inode numbers.
+#[derive(Debug, Default)]
+pub struct InodeLease {
+    range: Mutex<Range<u64>>,
+}
+
+impl InodeLease {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn take(&self) -> Option<u64> {
+        self.range.lock().unwrap().next()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.lock().unwrap().is_empty()
+    }
+
+    /// Replace the lease by a range newly leased, what's left of the old one is skipped.
+    pub fn renew(&self, range: Range<u64>) {
+        *self.range.lock().unwrap() = range;
+    }
+}
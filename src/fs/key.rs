@@ -14,6 +14,10 @@ pub enum ScopedKey<'a> {
     Block { ino: u64, block: u64 },
     FileHandler { ino: u64, handler: u64 },
     FileIndex { parent: u64, name: &'a str },
+    FreeInode(u64),
+    DirEntry { parent: u64, name: &'a str },
+    DirCount(u64),
+    UsageShard(u64),
 }
 
 impl<'a> ScopedKey<'a> {
@@ -22,6 +26,40 @@ impl<'a> ScopedKey<'a> {
     const BLOCK: u8 = 2;
     const HANDLER: u8 = 3;
     const INDEX: u8 = 4;
+    const FREE_INODE: u8 = 5;
+    const DIR_ENTRY: u8 = 6;
+    const DIR_COUNT: u8 = 7;
+    const USAGE_SHARD: u8 = 8;
+    const NAMESPACE: u8 = u8::MAX;
+
+    /// Offsets up to 2 are taken by `..` and `.`.
+    pub const FIRST_DIR_COOKIE: u64 = 3;
+
+    /// Prefix of all keys of the filesystem named `name`.
+    /// The unnamed filesystem has an empty prefix, keeping the original layout.
+    pub fn namespace(name: &str) -> Vec<u8> {
+        if name.is_empty() {
+            return Vec::new();
+        }
+        // the length makes sure no namespace is a prefix of another one
+        let mut data = Vec::with_capacity(1 + size_of::<u16>() + name.len());
+        data.push(Self::NAMESPACE);
+        data.extend((name.len() as u16).to_be_bytes().iter());
+        data.extend(name.as_bytes().iter());
+        data
+    }
+
+    /// The smallest key greater than all keys with the `prefix`.
+    pub fn namespace_end(prefix: &[u8]) -> Vec<u8> {
+        let mut end = prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                break;
+            }
+        }
+        end
+    }
 
     pub const fn meta() -> Self {
         Self::Meta
@@ -47,11 +85,81 @@ impl<'a> ScopedKey<'a> {
         Self::FileIndex { parent, name }
     }
 
+    /// Key of a run of free inode numbers, ending before `end`.
+    pub const fn free_inode(end: u64) -> Self {
+        Self::FreeInode(end)
+    }
+
+    /// Keys of runs of free inode numbers ending not before `end`.
+    pub fn free_inode_range(end: u64) -> Range<Key> {
+        Self::free_inode(end).into()..Self::free_inode(u64::MAX).into()
+    }
+
+    /// Key of an entry in a directory, entries are ordered by the cookies of their names.
+    pub fn dir_entry(parent: u64, name: &'a str) -> Self {
+        Self::DirEntry { parent, name }
+    }
+
+    /// Keys of entries in directory `parent` whose cookies are not less than `cookie`.
+    pub fn dir_entry_range(parent: u64, cookie: u64) -> Range<Key> {
+        let bound = |parent: u64, cookie: u64| {
+            let mut data = Vec::with_capacity(1 + size_of::<u64>() * 2);
+            data.push(Self::DIR_ENTRY);
+            data.extend(parent.to_be_bytes().iter());
+            data.extend(cookie.to_be_bytes().iter());
+            Key::from(data)
+        };
+        bound(parent, cookie)..bound(parent + 1, 0)
+    }
+
+    /// Keys of entries in all directories.
+    pub fn all_dir_entry_range() -> Range<Key> {
+        Key::from(vec![Self::DIR_ENTRY])..Key::from(vec![Self::DIR_ENTRY + 1])
+    }
+
+    /// Key of the number of entries in directory `parent`.
+    pub const fn dir_count(parent: u64) -> Self {
+        Self::DirCount(parent)
+    }
+
+    pub fn all_dir_count_range() -> Range<Key> {
+        Key::from(vec![Self::DIR_COUNT])..Key::from(vec![Self::DIR_COUNT + 1])
+    }
+
+    /// Key of the usage changes of inodes in `shard`.
+    pub const fn usage_shard(shard: u64) -> Self {
+        Self::UsageShard(shard)
+    }
+
+    pub fn usage_shard_range() -> Range<Key> {
+        Key::from(vec![Self::USAGE_SHARD])..Key::from(vec![Self::USAGE_SHARD + 1])
+    }
+
+    /// Position of an entry in a directory stream, a hash of the `name`
+    /// greater than the offsets of `.` and `..` that fits in the offset of readdir.
+    ///
+    /// Entries whose names collide may be skipped if the kernel resumes a listing between them,
+    /// which is as unlikely as a collision of 62-bit hashes in a directory.
+    pub fn dir_cookie(name: &str) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+        let hash = name.bytes().fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        });
+        (hash >> 2) + Self::FIRST_DIR_COOKIE
+    }
+
     pub fn block_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
         debug_assert_ne!(0, ino);
         Self::block(ino, block_range.start).into()..Self::block(ino, block_range.end).into()
     }
 
+    /// Keys of blocks of all files.
+    pub fn all_block_range() -> Range<Key> {
+        Key::from(vec![Self::BLOCK])..Key::from(vec![Self::BLOCK + 1])
+    }
+
     pub fn inode_range(ino_range: Range<u64>) -> Range<Key> {
         Self::inode(ino_range.start).into()..Self::inode(ino_range.end).into()
     }
@@ -65,6 +173,10 @@ impl<'a> ScopedKey<'a> {
             Block { ino: _, block: _ } => Self::BLOCK,
             FileHandler { ino: _, handler: _ } => Self::HANDLER,
             FileIndex { parent: _, name: _ } => Self::INDEX,
+            FreeInode(_) => Self::FREE_INODE,
+            DirEntry { parent: _, name: _ } => Self::DIR_ENTRY,
+            DirCount(_) => Self::DIR_COUNT,
+            UsageShard(_) => Self::USAGE_SHARD,
         }
     }
 
@@ -77,6 +189,10 @@ impl<'a> ScopedKey<'a> {
             Block { ino: _, block: _ } => size_of::<u64>() * 2,
             FileHandler { ino: _, handler: _ } => size_of::<u64>() * 2,
             FileIndex { parent: _, name } => size_of::<u64>() + name.len(),
+            FreeInode(_) => size_of::<u64>(),
+            DirEntry { parent: _, name } => size_of::<u64>() * 2 + name.len(),
+            DirCount(_) => size_of::<u64>(),
+            UsageShard(_) => size_of::<u64>(),
         }
     }
 
@@ -109,6 +225,29 @@ impl<'a> ScopedKey<'a> {
                     std::str::from_utf8(&data[size_of::<u64>()..]).map_err(|_| invalid_key())?,
                 ))
             }
+            Self::FREE_INODE => {
+                let end = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::free_inode(end))
+            }
+            Self::DIR_ENTRY => {
+                let parent =
+                    u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                let name = data.get(size_of::<u64>() * 2..).ok_or_else(invalid_key)?;
+                Ok(Self::dir_entry(
+                    parent,
+                    std::str::from_utf8(name).map_err(|_| invalid_key())?,
+                ))
+            }
+            Self::DIR_COUNT => {
+                let parent =
+                    u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::dir_count(parent))
+            }
+            Self::USAGE_SHARD => {
+                let shard =
+                    u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::usage_shard(shard))
+            }
             _ => Err(invalid_key()),
         }
     }
@@ -135,6 +274,14 @@ impl<'a> From<ScopedKey<'a>> for Key {
                 data.extend(parent.to_be_bytes().iter());
                 data.extend(name.as_bytes().iter());
             }
+            FreeInode(end) => data.extend(end.to_be_bytes().iter()),
+            DirEntry { parent, name } => {
+                data.extend(parent.to_be_bytes().iter());
+                data.extend(ScopedKey::dir_cookie(name).to_be_bytes().iter());
+                data.extend(name.as_bytes().iter());
+            }
+            DirCount(parent) => data.extend(parent.to_be_bytes().iter()),
+            UsageShard(shard) => data.extend(shard.to_be_bytes().iter()),
         }
         data.into()
     }
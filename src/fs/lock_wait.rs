@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use super::retry::RetryPolicy;
+
+/// Source of the current time, replaced by tests to control time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock only advanced by tests.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock(std::rc::Rc<std::cell::Cell<Instant>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(std::rc::Rc::new(std::cell::Cell::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.0.set(self.0.get() + duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.get()
+    }
+}
+
+/// Attempts of `setlkw` to take a lock held by others, backing off between them
+/// until the lock timeout is passed.
+#[derive(Debug)]
+pub struct LockWait<C = SystemClock> {
+    clock: C,
+    start: Instant,
+    attempts: u32,
+    policy: RetryPolicy,
+}
+
+impl LockWait {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self::with_clock(SystemClock, timeout)
+    }
+}
+
+impl<C: Clock> LockWait<C> {
+    pub const INITIAL_DELAY: Duration = Duration::from_millis(1);
+    pub const MAX_DELAY: Duration = Duration::from_millis(100);
+
+    pub fn with_clock(clock: C, timeout: Option<Duration>) -> Self {
+        let start = clock.now();
+        Self {
+            clock,
+            start,
+            attempts: 0,
+            policy: RetryPolicy {
+                max_attempts: None,
+                initial_delay: Self::INITIAL_DELAY,
+                max_delay: Self::MAX_DELAY,
+                jitter: false,
+                deadline: timeout,
+            },
+        }
+    }
+
+    /// Delay before the next attempt after a failed one, None once the timeout is passed.
+    /// The delay never ends beyond the timeout.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        let elapsed = self.clock.now().saturating_duration_since(self.start);
+        if !self.policy.allows(self.attempts, elapsed) {
+            return None;
+        }
+        let delay = self.policy.delay(self.attempts);
+        Some(
+            self.policy
+                .deadline
+                .map_or(delay, |deadline| delay.min(deadline - elapsed)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LockWait, ManualClock};
+
+    #[test]
+    fn times_out_after_timeout() {
+        let clock = ManualClock::new();
+        let mut wait = LockWait::with_clock(clock.clone(), Some(Duration::from_millis(10)));
+        let mut waited = Duration::from_millis(0);
+        while let Some(delay) = wait.next_delay() {
+            assert!(delay > Duration::from_millis(0));
+            clock.advance(delay);
+            waited += delay;
+        }
+        assert_eq!(waited, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn backs_off_up_to_max_delay() {
+        let clock = ManualClock::new();
+        let mut wait = LockWait::with_clock(clock.clone(), None);
+        let delays: Vec<_> = (0..10).map(|_| wait.next_delay().unwrap()).collect();
+        assert_eq!(delays[0], LockWait::<ManualClock>::INITIAL_DELAY);
+        assert_eq!(delays[1], LockWait::<ManualClock>::INITIAL_DELAY * 2);
+        assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(delays[9], LockWait::<ManualClock>::MAX_DELAY);
+    }
+
+    #[test]
+    fn waits_forever_without_timeout() {
+        let clock = ManualClock::new();
+        let mut wait = LockWait::with_clock(clock.clone(), None);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(
+            wait.next_delay(),
+            Some(LockWait::<ManualClock>::INITIAL_DELAY)
+        );
+    }
+}
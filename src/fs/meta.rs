@@ -1,18 +1,118 @@
 use serde::{Deserialize, Serialize};
 
+use super::compression::Compression;
 use super::error::{FsError, Result};
 use super::key::ROOT_INODE;
 use super::serialize::{deserialize, serialize, ENCODING};
+use super::tikv_fs::TiFs;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Meta {
     pub inode_next: u64,
+    #[serde(default)]
+    pub name: String,
+    /// Version of the on-disk layout, zero for filesystems created before versioning.
+    #[serde(default)]
+    pub layout_version: u32,
+    /// Counters as they were set, the usage adds the deltas of all shards to them.
+    /// None for filesystems created before the counters, they are initialized by the first `statfs`.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Zero for filesystems created before the block size is configurable.
+    #[serde(default)]
+    pub block_size: u64,
+    /// New names must be valid on Windows, names created before it's enabled are kept.
+    #[serde(default)]
+    pub portable_names: bool,
+    /// Compression of blocks written, blocks are readable whatever it's changed to.
+    #[serde(default)]
+    pub compression: Compression,
+    /// A value sealed by the encryption key if file data is encrypted,
+    /// to reject mounts without the key or with another one.
+    #[serde(default)]
+    pub key_check: Option<Vec<u8>>,
+    /// Writable mounts initialized and not destroyed since, a crashed mount is counted forever,
+    /// until `TiFs::check` repairs it.
+    #[serde(default)]
+    pub live_mounts: u64,
+}
+
+/// Blocks and inodes in use, updated by every transaction changing them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Usage {
+    pub blocks: u64,
+    pub files: u64,
+}
+
+/// Changes of the usage counted by a shard of inodes since the counters in the meta are set,
+/// so that transactions changing inodes of different shards don't conflict.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct UsageDelta {
+    pub blocks: i64,
+    pub files: i64,
+}
+
+impl UsageDelta {
+    pub fn add(&mut self, other: UsageDelta) {
+        self.blocks += other.blocks;
+        self.files += other.files;
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self).map_err(|err| FsError::Serialize {
+            target: "usage",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        deserialize(bytes).map_err(|err| FsError::Serialize {
+            target: "usage",
+            typ: ENCODING,
+            msg: err.to_string(),
+        })
+    }
+}
+
+impl Usage {
+    pub const fn new() -> Self {
+        Self {
+            blocks: 0,
+            files: 0,
+        }
+    }
+
+    pub fn apply(&mut self, blocks: i64, files: i64) {
+        self.blocks = (self.blocks as i64 + blocks).max(0) as u64;
+        self.files = (self.files as i64 + files).max(0) as u64;
+    }
 }
 
 impl Meta {
+    /// Directories are migrated to version 2 once they are modified.
+    pub const LAYOUT_VERSION: u32 = 2;
+
     pub const fn new() -> Self {
         Self {
             inode_next: ROOT_INODE,
+            name: String::new(),
+            layout_version: Self::LAYOUT_VERSION,
+            usage: Some(Usage::new()),
+            block_size: TiFs::DEFAULT_BLOCK_SIZE,
+            portable_names: false,
+            compression: Compression::None,
+            key_check: None,
+            live_mounts: 0,
+        }
+    }
+
+    /// Size of data blocks, which cannot be changed once the filesystem is created.
+    pub fn block_size(&self) -> u64 {
+        if self.block_size == 0 {
+            TiFs::DEFAULT_BLOCK_SIZE
+        } else {
+            self.block_size
         }
     }
 
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tracing::{debug, info, warn};
+
+/// Prometheus metrics of a mount, registered in a registry of its own
+/// labeled by the name of the filesystem.
+#[derive(Clone)]
+pub struct Metrics {
+    pub registry: Registry,
+    op_duration: HistogramVec,
+    requests: IntCounterVec,
+    request_errors: IntCounterVec,
+    transactions: IntCounterVec,
+    txn_retries: IntCounter,
+    read_bytes: IntCounter,
+    written_bytes: IntCounter,
+    block_cache: IntCounterVec,
+    open_handles: IntGauge,
+    reaped_handles: IntCounter,
+    legacy_dir_bytes: Histogram,
+    warm_up_seconds: Gauge,
+}
+
+impl Metrics {
+    pub fn new(name: &str) -> prometheus::Result<Self> {
+        let mut labels = HashMap::new();
+        labels.insert("name".to_string(), name.to_string());
+        let registry = Registry::new_custom(Some("tifs".to_string()), Some(labels))?;
+
+        let op_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "operation_duration_seconds",
+                "Latency of FUSE operations from request to reply",
+            ),
+            &["op"],
+        )?;
+        let requests = IntCounterVec::new(
+            Opts::new("fuse_requests_total", "FUSE requests replied"),
+            &["op"],
+        )?;
+        let request_errors = IntCounterVec::new(
+            Opts::new(
+                "fuse_request_errors_total",
+                "FUSE requests replied with an error, by its errno",
+            ),
+            &["op", "errno"],
+        )?;
+        let transactions = IntCounterVec::new(
+            Opts::new(
+                "transactions_total",
+                "Transactions begun, committed and rolled back, each attempt counted",
+            ),
+            &["state"],
+        )?;
+        let txn_retries = IntCounter::with_opts(Opts::new(
+            "transaction_retries_total",
+            "Transactions retried on key errors",
+        ))?;
+        let read_bytes =
+            IntCounter::with_opts(Opts::new("read_bytes_total", "Bytes of file data read"))?;
+        let written_bytes = IntCounter::with_opts(Opts::new(
+            "written_bytes_total",
+            "Bytes of file data written",
+        ))?;
+        let block_cache = IntCounterVec::new(
+            Opts::new(
+                "block_cache_requests_total",
+                "Reads of blocks looked up in the block cache, by hit or miss",
+            ),
+            &["result"],
+        )?;
+        let open_handles = IntGauge::with_opts(Opts::new(
+            "open_file_handles",
+            "File handlers opened by this mount",
+        ))?;
+        let reaped_handles = IntCounter::with_opts(Opts::new(
+            "reaped_file_handles_total",
+            "File handlers reaped as idle or forgotten by the kernel",
+        ))?;
+        let legacy_dir_bytes = Histogram::with_opts(
+            HistogramOpts::new(
+                "legacy_directory_bytes",
+                "Size of directories read in the layout before version 2",
+            )
+            .buckets(exponential_buckets(4096.0, 4.0, 8)?),
+        )?;
+        let warm_up_seconds = Gauge::with_opts(Opts::new(
+            "warm_up_seconds",
+            "Time spent warming up regions at mount",
+        ))?;
+        registry.register(Box::new(op_duration.clone()))?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_errors.clone()))?;
+        registry.register(Box::new(transactions.clone()))?;
+        registry.register(Box::new(txn_retries.clone()))?;
+        registry.register(Box::new(read_bytes.clone()))?;
+        registry.register(Box::new(written_bytes.clone()))?;
+        registry.register(Box::new(block_cache.clone()))?;
+        registry.register(Box::new(open_handles.clone()))?;
+        registry.register(Box::new(reaped_handles.clone()))?;
+        registry.register(Box::new(legacy_dir_bytes.clone()))?;
+        registry.register(Box::new(warm_up_seconds.clone()))?;
+
+        Ok(Self {
+            registry,
+            op_duration,
+            requests,
+            request_errors,
+            transactions,
+            txn_retries,
+            read_bytes,
+            written_bytes,
+            block_cache,
+            open_handles,
+            reaped_handles,
+            legacy_dir_bytes,
+            warm_up_seconds,
+        })
+    }
+
+    pub fn observe_op(&self, op: &str, elapsed: Duration, errno: Option<i32>) {
+        self.op_duration
+            .with_label_values(&[op])
+            .observe(elapsed.as_secs_f64());
+        self.requests.with_label_values(&[op]).inc();
+        if let Some(errno) = errno {
+            self.request_errors
+                .with_label_values(&[op, &errno.to_string()])
+                .inc();
+        }
+    }
+
+    /// Count a transaction entering `state`, one of `begun`, `committed` and `rolled_back`.
+    pub fn count_txn(&self, state: &str) {
+        self.transactions.with_label_values(&[state]).inc();
+    }
+
+    pub fn count_retry(&self) {
+        self.txn_retries.inc();
+    }
+
+    pub fn count_read(&self, bytes: usize) {
+        self.read_bytes.inc_by(bytes as u64);
+    }
+
+    pub fn count_written(&self, bytes: usize) {
+        self.written_bytes.inc_by(bytes as u64);
+    }
+
+    pub fn count_block_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.block_cache.with_label_values(&[result]).inc();
+    }
+
+    pub fn observe_legacy_dir(&self, bytes: usize) {
+        self.legacy_dir_bytes.observe(bytes as f64);
+    }
+
+    pub fn set_warm_up(&self, elapsed: Duration) {
+        self.warm_up_seconds.set(elapsed.as_secs_f64());
+    }
+
+    pub fn set_open_handles(&self, handles: usize) {
+        self.open_handles.set(handles as i64);
+    }
+
+    pub fn count_reaped(&self) {
+        self.reaped_handles.inc();
+    }
+}
+
+/// Serve the metrics in `registry` at `http://<addr>/metrics` in the Prometheus text format.
+/// Each connection gets a single response, which is enough for scrapers and curl.
+pub async fn serve(registry: Registry, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("serve metrics at http://{}/metrics", addr);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let registry = registry.clone();
+                task::spawn(async move {
+                    if let Err(err) = respond(stream, &registry).await {
+                        debug!("fail to respond to a metrics request: {}", err);
+                    }
+                });
+            }
+            Err(err) => warn!("fail to accept a metrics connection: {}", err),
+        }
+    }
+    Ok(())
+}
+
+// Only the request line is read, the headers and body of the request don't matter.
+async fn respond(mut stream: TcpStream, registry: &Registry) -> io::Result<()> {
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let encoder = TextEncoder::new();
+    let (status, content_type, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let mut body = Vec::new();
+            encoder
+                .encode(&registry.gather(), &mut body)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            ("200 OK", encoder.format_type(), body)
+        }
+        _ => ("404 Not Found", "text/plain", b"not found\n".to_vec()),
+    };
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await
+}
@@ -1,9 +1,13 @@
 use fuser::FileType;
 
+/// Permission bits of `mode`, without the file type and the set-id bits.
 pub const fn as_file_perm(mode: u32) -> u16 {
-    (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as _
+    (mode & PERM_MASK & !(libc::S_ISUID | libc::S_ISGID) as u32) as _
 }
 
+/// Permission bits including the set-id and sticky bits.
+pub const PERM_MASK: u32 = 0o7777;
+
 #[cfg(target_os = "freebsd")]
 pub fn as_file_kind(mode: u32) -> FileType {
     use FileType::*;
@@ -16,6 +20,8 @@ pub fn as_file_kind(mode: u32) -> FileType {
         libc::S_IFBLK => BlockDevice,
         libc::S_IFCHR => CharDevice,
         libc::S_IFSOCK => Socket,
+        // mknod(2) creates a regular file if no type is given
+        0 => RegularFile,
         _ => unimplemented!("{}", mode),
     }
 }
@@ -32,6 +38,8 @@ pub fn as_file_kind(mode: u32) -> FileType {
         libc::S_IFBLK => BlockDevice,
         libc::S_IFCHR => CharDevice,
         libc::S_IFSOCK => Socket,
+        // mknod(2) creates a regular file if no type is given
+        0 => RegularFile,
         _ => unimplemented!("{}", mode),
     }
 }
@@ -0,0 +1,159 @@
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use super::error::{FsError, Result};
+
+#[derive(Debug, Deserialize)]
+struct Stores {
+    #[serde(default)]
+    stores: Vec<StoreInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoreInfo {
+    status: StoreStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoreStatus {
+    #[serde(default)]
+    capacity: String,
+    #[serde(default)]
+    available: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Replicate {
+    #[serde(rename = "max-replicas")]
+    max_replicas: u64,
+}
+
+// Get `path` from the HTTP API of PD and parse the JSON body.
+async fn get_json<T: DeserializeOwned>(endpoint: &str, path: &str) -> Result<T> {
+    let host = endpoint.trim_start_matches("http://");
+    let mut stream = TcpStream::connect(host).await?;
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+                path, host
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let invalid_response = || FsError::UnknownError(format!("invalid response of pd({})", host));
+    let split = response.find("\r\n\r\n").ok_or_else(invalid_response)?;
+    let status = response[..split].lines().next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(FsError::UnknownError(format!(
+            "fail to get {} from pd({}): {}",
+            path, host, status
+        )));
+    }
+    serde_json::from_str(&response[split + 4..]).map_err(|_| invalid_response())
+}
+
+/// Space of the tikv cluster in bytes as `(capacity, available)`, summed over all stores.
+///
+/// The tikv client doesn't expose stats of stores, so they are queried from the HTTP API of PD.
+pub async fn cluster_space(endpoint: &str) -> Result<(u64, u64)> {
+    let stores: Stores = get_json(endpoint, "/pd/api/v1/stores").await?;
+    stores
+        .stores
+        .iter()
+        .try_fold((0, 0), |(capacity, available), store| {
+            Ok((
+                capacity + parse_size(&store.status.capacity)?,
+                available + parse_size(&store.status.available)?,
+            ))
+        })
+}
+
+/// Replicas of each region, the raw space of stores is taken this many times by each byte.
+pub async fn max_replicas(endpoint: &str) -> Result<u64> {
+    let replicate: Replicate = get_json(endpoint, "/pd/api/v1/config/replicate").await?;
+    Ok(replicate.max_replicas.max(1))
+}
+
+// Parse sizes formatted by PD, like `1.819TiB`.
+fn parse_size(size: &str) -> Result<u64> {
+    let invalid_size = || FsError::UnknownError(format!("invalid size({}) from pd", size));
+    let number_len = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or_else(|| size.len());
+    let number: f64 = size[..number_len].parse().map_err(|_| invalid_size())?;
+    let shift = match size[number_len..].trim() {
+        "" | "B" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        "TiB" => 40,
+        "PiB" => 50,
+        "EiB" => 60,
+        _ => return Err(invalid_size()),
+    };
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, Replicate, Stores};
+
+    #[test]
+    fn parses_replicate_config() {
+        let body = r#"{"max-replicas": 3, "location-labels": "", "strictly-match-label": "false"}"#;
+        let replicate: Replicate = serde_json::from_str(body).unwrap();
+        assert_eq!(replicate.max_replicas, 3);
+    }
+
+    #[test]
+    fn parses_sizes_of_pd() {
+        let cases = [
+            ("0B", 0),
+            ("512", 512),
+            ("1KiB", 1 << 10),
+            ("1.5MiB", 3 << 19),
+            ("100GiB", 100 << 30),
+            ("1.819TiB", 2_000_011_650_924),
+            ("2 PiB", 2 << 50),
+            ("1EiB", 1 << 60),
+        ];
+        for (size, bytes) in cases.iter() {
+            assert_eq!(parse_size(size).unwrap(), *bytes, "{}", size);
+        }
+        for size in &["", "GiB", "1GB", "1.2.3KiB", "-1KiB"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn parses_stores() {
+        let body = r#"{
+            "count": 2,
+            "stores": [
+                {"store": {"id": 1}, "status": {"capacity": "100GiB", "available": "40GiB"}},
+                {"store": {"id": 4}, "status": {"capacity": "100GiB", "available": "60.5GiB"}}
+            ]
+        }"#;
+        let stores: Stores = serde_json::from_str(body).unwrap();
+        let space: Vec<(u64, u64)> = stores
+            .stores
+            .iter()
+            .map(|store| {
+                (
+                    parse_size(&store.status.capacity).unwrap(),
+                    parse_size(&store.status.available).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(space, vec![(100 << 30, 40 << 30), (100 << 30, 121 << 29)]);
+        // a cluster without stores has no `stores`
+        let stores: Stores = serde_json::from_str(r#"{"count": 0}"#).unwrap();
+        assert!(stores.stores.is_empty());
+    }
+}
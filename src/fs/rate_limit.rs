@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+/// Token bucket refilled by `rate` bytes per second, holding at most a second of them.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` tokens, return how long the caller should wait for the tokens it owes.
+    /// The bucket goes into debt instead of rejecting, so large requests are not starved.
+    pub fn acquire(&mut self, rate: u64, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + refilled).min(rate as f64) - bytes as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 || rate == 0 {
+            Duration::default()
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+
+    // A bucket idle for a second is full, the same as a new one.
+    fn is_idle(&self) -> bool {
+        self.updated.elapsed() >= Duration::from_secs(1)
+    }
+}
+
+/// Write rate limits of processes, keyed by pid.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: DashMap<u32, TokenBucket>,
+}
+
+impl RateLimiter {
+    // buckets of exited processes are dropped once there are more than this
+    const MAX_IDLE_BUCKETS: usize = 1 << 10;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Account `bytes` written by `pid` limited to `rate` bytes per second,
+    /// return how long the write should be delayed.
+    pub fn acquire(&self, pid: u32, rate: u64, bytes: u64) -> Duration {
+        if self.buckets.len() > Self::MAX_IDLE_BUCKETS {
+            self.buckets.retain(|_, bucket| !bucket.is_idle());
+        }
+        self.buckets
+            .entry(pid)
+            .or_insert_with(|| TokenBucket::new(rate))
+            .acquire(rate, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RateLimiter, TokenBucket};
+
+    const RATE: u64 = 1000;
+
+    // tokens refilled while a test runs shorten the delays a little
+    fn assert_about(delay: Duration, expected: Duration) {
+        assert!(
+            delay <= expected && delay + Duration::from_millis(50) > expected,
+            "{:?} is not about {:?}",
+            delay,
+            expected
+        );
+    }
+
+    #[test]
+    fn delays_writes_beyond_the_burst() {
+        let mut bucket = TokenBucket::new(RATE);
+        assert_eq!(bucket.acquire(RATE, RATE), Duration::default());
+        assert_about(bucket.acquire(RATE, RATE / 2), Duration::from_millis(500));
+        assert_about(bucket.acquire(RATE, RATE), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = TokenBucket::new(0);
+        assert_eq!(bucket.acquire(0, 1 << 20), Duration::default());
+    }
+
+    #[test]
+    fn limits_each_pid_apart() {
+        let limiter = RateLimiter::new();
+        assert_eq!(limiter.acquire(1, RATE, RATE), Duration::default());
+        assert_about(limiter.acquire(1, RATE, RATE), Duration::from_secs(1));
+        assert_eq!(limiter.acquire(2, RATE, RATE), Duration::default());
+    }
+}
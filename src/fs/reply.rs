@@ -19,9 +19,10 @@ pub struct Entry {
 }
 
 impl Entry {
-    pub fn new(stat: FileAttr, generation: u64) -> Self {
+    /// The kernel caches the entry and its attributes for `ttl`.
+    pub fn new(stat: FileAttr, generation: u64, ttl: Duration) -> Self {
         Self {
-            time: get_time(),
+            time: ttl,
             stat,
             generation,
         }
@@ -45,11 +46,8 @@ pub struct Attr {
     pub attr: FileAttr,
 }
 impl Attr {
-    pub fn new(attr: FileAttr) -> Self {
-        Self {
-            time: get_time(),
-            attr,
-        }
+    pub fn new(attr: FileAttr, ttl: Duration) -> Self {
+        Self { time: ttl, attr }
     }
 }
 
@@ -70,47 +68,33 @@ pub struct DirItem {
 }
 #[derive(Debug)]
 pub struct Dir {
-    offset: usize,
-    items: Vec<DirItem>,
+    pub items: Vec<(i64, DirItem)>,
 }
 
 impl Dir {
-    pub fn offset(offset: usize) -> Self {
-        Self {
-            offset,
-            items: Vec::new(),
-        }
-    }
-
     pub fn new() -> Self {
-        Self::offset(0)
+        Self { items: Vec::new() }
     }
 
-    pub fn push(&mut self, item: DirItem) {
-        self.items.push(item)
+    /// Push an entry, `offset` is where the kernel resumes reading after this entry.
+    pub fn push(&mut self, offset: i64, item: DirItem) {
+        self.items.push((offset, item))
     }
 }
 
 #[derive(Debug)]
 pub struct DirPlus {
-    offset: usize,
-    items: Vec<(DirItem, Entry)>,
+    items: Vec<(i64, DirItem, Entry)>,
 }
 
 impl DirPlus {
-    pub fn offset(offset: usize) -> Self {
-        Self {
-            offset,
-            items: Vec::new(),
-        }
-    }
-
     pub fn new() -> Self {
-        Self::offset(0)
+        Self { items: Vec::new() }
     }
 
-    pub fn push(&mut self, item: DirItem, entry: Entry) {
-        self.items.push((item, entry))
+    /// Push an entry, `offset` is where the kernel resumes reading after this entry.
+    pub fn push(&mut self, offset: i64, item: DirItem, entry: Entry) {
+        self.items.push((offset, item, entry))
     }
 }
 
@@ -126,27 +110,34 @@ pub struct StatFs {
     pub frsize: u32,
 }
 impl StatFs {
+    pub const DEFAULT_NAMELEN: u32 = 255;
+
+    /// Build the reply from usage, `reserved_bytes` are free but not available to users.
     pub fn new(
-        blocks: u64,
-        bfree: u64,
-        bavail: u64,
-        files: u64,
-        ffree: u64,
-        bsize: u32,
-        namelen: u32,
-        frsize: u32,
+        used_blocks: u64,
+        total_blocks: u64,
+        used_inodes: u64,
+        total_inodes: u64,
+        reserved_bytes: u64,
+        block_size: u32,
     ) -> Self {
+        let bfree = total_blocks.saturating_sub(used_blocks);
         Self {
-            blocks,
+            blocks: total_blocks,
             bfree,
-            bavail,
-            files,
-            ffree,
-            bsize,
-            namelen,
-            frsize,
+            bavail: bfree.saturating_sub(reserved_bytes / block_size as u64),
+            files: total_inodes,
+            ffree: total_inodes.saturating_sub(used_inodes),
+            bsize: block_size,
+            namelen: Self::DEFAULT_NAMELEN,
+            frsize: 0,
         }
     }
+
+    pub fn with_namelen(mut self, namelen: u32) -> Self {
+        self.namelen = namelen;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -168,9 +159,9 @@ pub struct Create {
     pub flags: u32,
 }
 impl Create {
-    pub fn new(attr: FileAttr, generation: u64, fh: u64, flags: u32) -> Self {
+    pub fn new(attr: FileAttr, generation: u64, fh: u64, flags: u32, ttl: Duration) -> Self {
         Self {
-            ttl: get_time(),
+            ttl,
             attr,
             generation,
             fh,
@@ -225,7 +216,7 @@ impl Bmap {
 
 #[derive(Debug)]
 pub struct Lseek {
-    offset: i64,
+    pub offset: i64,
 }
 
 impl Lseek {
@@ -295,13 +286,8 @@ impl FsReply<Data> for ReplyData {
 
 impl FsReply<Dir> for ReplyDirectory {
     fn reply_ok(mut self, dir: Dir) {
-        for (index, item) in dir.items.into_iter().enumerate() {
-            if self.add(
-                item.ino,
-                (index + 1 + dir.offset) as i64,
-                item.typ,
-                item.name,
-            ) {
+        for (offset, item) in dir.items {
+            if self.add(item.ino, offset, item.typ, item.name) {
                 break;
             }
         }
@@ -314,10 +300,10 @@ impl FsReply<Dir> for ReplyDirectory {
 
 impl FsReply<DirPlus> for ReplyDirectoryPlus {
     fn reply_ok(mut self, dir: DirPlus) {
-        for (index, (item, entry)) in dir.items.into_iter().enumerate() {
+        for (offset, item, entry) in dir.items {
             if self.add(
                 item.ino,
-                (dir.offset + index) as i64,
+                offset,
                 item.name,
                 &entry.time,
                 &entry.stat,
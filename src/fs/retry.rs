@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::OptionValue;
+
+/// How a transaction is retried on key errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total before giving up, `None` for unlimited.
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Pick a random delay up to the backoff, so that conflicting clients don't retry at the same time.
+    pub jitter: bool,
+    /// Give up once retrying for this long, set by `retry_deadline` apart from the rest.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(500),
+            jitter: true,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retry at once and forever.
+    pub const fn no_delay() -> Self {
+        Self {
+            max_attempts: None,
+            initial_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter: false,
+            deadline: None,
+        }
+    }
+
+    /// Whether another attempt is allowed after `attempts` failed ones taking `elapsed`.
+    pub fn allows(&self, attempts: u32, elapsed: Duration) -> bool {
+        self.max_attempts.map_or(true, |max| attempts < max)
+            && self.deadline.map_or(true, |deadline| elapsed < deadline)
+    }
+
+    /// Delay before the next attempt after `attempts` failed ones,
+    /// doubled on each attempt from `initial_delay` and never longer than `max_delay`.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .checked_mul(1 << attempts.saturating_sub(1).min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        if !self.jitter {
+            return backoff;
+        }
+        backoff.mul_f64(rand::thread_rng().gen_range(0.0..1.0))
+    }
+}
+
+/// Written as `<max_attempts>/<initial_delay>/<max_delay>[/jitter]`,
+/// `max_attempts` is `inf` for unlimited attempts, e.g. `retry_policy=20/1ms/500ms/jitter`.
+impl OptionValue for RetryPolicy {
+    fn parse_value(value: &str) -> Option<Self> {
+        let mut fields = value.split('/');
+        let max_attempts = match fields.next()? {
+            "inf" => None,
+            attempts => Some(attempts.parse().ok().filter(|attempts| *attempts > 0)?),
+        };
+        let initial_delay = Duration::parse_value(fields.next()?)?;
+        let max_delay = Duration::parse_value(fields.next()?)?;
+        let jitter = match fields.next() {
+            None => false,
+            Some("jitter") => true,
+            Some(_) => return None,
+        };
+        if fields.next().is_some() || initial_delay > max_delay {
+            return None;
+        }
+        Some(Self {
+            max_attempts,
+            initial_delay,
+            max_delay,
+            jitter,
+            deadline: None,
+        })
+    }
+
+    fn format_value(&self) -> String {
+        let mut value = format!(
+            "{}/{}/{}",
+            self.max_attempts
+                .map_or_else(|| "inf".to_owned(), |attempts| attempts.to_string()),
+            self.initial_delay.format_value(),
+            self.max_delay.format_value()
+        );
+        if self.jitter {
+            value.push_str("/jitter");
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+    use crate::OptionValue;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(100),
+            jitter: false,
+            deadline: Some(Duration::from_secs(1)),
+        }
+    }
+
+    #[test]
+    fn doubles_delays_up_to_the_cap() {
+        let policy = policy();
+        assert_eq!(policy.delay(1), Duration::from_millis(1));
+        assert_eq!(policy.delay(2), Duration::from_millis(2));
+        assert_eq!(policy.delay(7), Duration::from_millis(64));
+        assert_eq!(policy.delay(8), Duration::from_millis(100));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_stays_below_the_backoff() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy()
+        };
+        for attempts in 1..20 {
+            assert!(policy.delay(attempts) <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn stops_at_max_attempts_or_deadline() {
+        let policy = policy();
+        assert!(policy.allows(4, Duration::from_millis(0)));
+        assert!(!policy.allows(5, Duration::from_millis(0)));
+        assert!(!policy.allows(1, Duration::from_secs(1)));
+        assert!(RetryPolicy::no_delay().allows(u32::MAX, Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn parses_retry_policies() {
+        let policy = RetryPolicy::parse_value("20/1ms/500ms/jitter").unwrap();
+        assert_eq!(policy.max_attempts, Some(20));
+        assert_eq!(policy.format_value(), "20/1ms/500ms/jitter");
+        assert_eq!(
+            RetryPolicy::parse_value("inf/0/0").unwrap().format_value(),
+            "inf/0ms/0ms"
+        );
+        for invalid in &[
+            "0/1ms/500ms",
+            "20/1s/500ms",
+            "20/1ms",
+            "20/1ms/500ms/random",
+        ] {
+            assert_eq!(RetryPolicy::parse_value(invalid), None, "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use super::compression::Compression;
+use super::error::{FsError, Result};
+use super::reply::get_time;
+use super::retry::RetryPolicy;
+use super::tikv_fs::TiFs;
+use crate::{MountOption, OptionValue};
+
+/// Presets of the caching settings, selected by `consistency=<profile>`.
+/// Options given explicitly override them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consistency {
+    /// Nothing is cached by the kernel, each operation sees changes of other mounts.
+    Strong,
+    /// Cached data is dropped on each open and attributes expire in a second, so a file
+    /// opened after another mount closes it sees the changes.
+    CloseToOpen,
+    /// The kernel caches data and attributes as long as it likes, the default.
+    Relaxed,
+}
+
+impl Consistency {
+    pub const CLOSE_TO_OPEN_TTL: Duration = Duration::from_secs(1);
+
+    fn apply(self, config: &mut RuntimeConfig) {
+        let (direct_io, keep_cache, attr_ttl) = match self {
+            Self::Strong => (true, false, Some(Duration::default())),
+            Self::CloseToOpen => (false, false, Some(Self::CLOSE_TO_OPEN_TTL)),
+            Self::Relaxed => (false, true, None),
+        };
+        config.direct_io = direct_io;
+        config.keep_cache = keep_cache;
+        config.attr_ttl = attr_ttl;
+    }
+}
+
+/// Written as `strong`, `close-to-open` or `relaxed`.
+impl OptionValue for Consistency {
+    fn parse_value(value: &str) -> Option<Self> {
+        match value {
+            "strong" => Some(Self::Strong),
+            "close-to-open" => Some(Self::CloseToOpen),
+            "relaxed" => Some(Self::Relaxed),
+            _ => None,
+        }
+    }
+
+    fn format_value(&self) -> String {
+        match self {
+            Self::Strong => "strong",
+            Self::CloseToOpen => "close-to-open",
+            Self::Relaxed => "relaxed",
+        }
+        .to_owned()
+    }
+}
+
+/// Settings that can be changed without remounting.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    pub direct_io: bool,
+    /// Keep the page cache of a file across opens, otherwise the kernel drops it on each open.
+    pub keep_cache: bool,
+    /// How long the kernel caches attributes and entries, as long as it likes if None.
+    pub attr_ttl: Option<Duration>,
+    pub pessimistic: bool,
+    /// Ignore unsupported operations and requests instead of failing them.
+    pub pretend_legacy: bool,
+    /// Enable portable names of the filesystem at mount, it's never disabled once stored.
+    pub portable_names: bool,
+    pub retry_policy: RetryPolicy,
+    pub lock_timeout: Option<Duration>,
+    pub handle_idle_timeout: Option<Duration>,
+    pub block_cache_size: usize,
+    pub dir_cache_size: usize,
+    pub inode_cache_size: usize,
+    pub inline_data_threshold: u64,
+    pub min_free_bytes: u64,
+    /// Compression requested at mount, the one stored in the meta is used if it's absent.
+    pub compression: Option<Compression>,
+    /// Bytes per second each process is allowed to write.
+    pub max_write_bytes_per_pid: Option<u64>,
+    /// Fixed at mount, kept here to validate other settings against it.
+    pub block_size: u64,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            direct_io: false,
+            keep_cache: true,
+            attr_ttl: None,
+            pessimistic: false,
+            pretend_legacy: false,
+            portable_names: false,
+            retry_policy: RetryPolicy::default(),
+            lock_timeout: None,
+            handle_idle_timeout: None,
+            block_cache_size: TiFs::DEFAULT_BLOCK_CACHE,
+            dir_cache_size: TiFs::DEFAULT_DIR_CACHE,
+            inode_cache_size: TiFs::DEFAULT_INODE_CACHE,
+            inline_data_threshold: TiFs::DEFAULT_INLINE_DATA_THRESHOLD,
+            min_free_bytes: 0,
+            compression: None,
+            max_write_bytes_per_pid: None,
+            block_size: TiFs::DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Build the config from mount options, options that cannot be changed at runtime are ignored.
+    pub fn from_mount_options(options: &[MountOption]) -> Result<Self> {
+        let mut config = Self::default();
+        config.apply_profiles(options);
+        for option in options {
+            match option {
+                MountOption::BlkSize(size) => config.block_size = *size,
+                MountOption::PortableNames => config.portable_names = true,
+                MountOption::Compression(compression) => config.compression = Some(*compression),
+                _ => {
+                    config.set(option);
+                }
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings of this config by `options`,
+    /// options that cannot be changed at runtime are rejected.
+    pub fn apply(&self, options: &[MountOption]) -> Result<Self> {
+        let mut config = self.clone();
+        config.apply_profiles(options);
+        for option in options {
+            if !config.set(option) {
+                return Err(FsError::InvalidConfig(format!(
+                    "option `{}` cannot be changed without remount",
+                    String::from(option)
+                )));
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// TTL of attributes and entries replied to the kernel.
+    pub fn entry_ttl(&self) -> Duration {
+        // a TTL of the current time since epoch lasts for decades
+        self.attr_ttl.unwrap_or_else(get_time)
+    }
+
+    // Profiles are applied before other options, so that explicit options override them.
+    fn apply_profiles(&mut self, options: &[MountOption]) {
+        for option in options {
+            if let MountOption::Consistency(profile) = option {
+                profile.apply(self);
+            }
+        }
+    }
+
+    // Return false if the option cannot be changed at runtime.
+    fn set(&mut self, option: &MountOption) -> bool {
+        match option {
+            MountOption::DirectIO => self.direct_io = true,
+            MountOption::PageCache => self.direct_io = false,
+            MountOption::AttrTtl(ttl) => self.attr_ttl = Some(*ttl),
+            // applied before other options
+            MountOption::Consistency(_) => (),
+            MountOption::Pessimistic => self.pessimistic = true,
+            MountOption::PretendLegacy => self.pretend_legacy = true,
+            MountOption::RetryPolicy(policy) => {
+                self.retry_policy = RetryPolicy {
+                    deadline: self.retry_policy.deadline,
+                    ..*policy
+                }
+            }
+            MountOption::RetryDeadline(deadline) => self.retry_policy.deadline = Some(*deadline),
+            MountOption::LockTimeout(timeout) => self.lock_timeout = Some(*timeout),
+            MountOption::HandleIdleTimeout(timeout) => self.handle_idle_timeout = Some(*timeout),
+            MountOption::BlockCache(size) => self.block_cache_size = *size,
+            MountOption::DirCache(size) => self.dir_cache_size = *size,
+            MountOption::InodeCache(size) => self.inode_cache_size = *size,
+            MountOption::InlineThreshold(threshold) => self.inline_data_threshold = *threshold,
+            MountOption::MinFreeBytes(bytes) => self.min_free_bytes = *bytes,
+            MountOption::MaxWriteBytesPerSecondPerPid(rate) => {
+                self.max_write_bytes_per_pid = Some(*rate)
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.block_size.is_power_of_two()
+            || self.block_size < TiFs::MIN_BLOCK_SIZE
+            || self.block_size > TiFs::MAX_BLOCK_SIZE
+        {
+            return Err(FsError::InvalidConfig(format!(
+                "block size({}) should be a power of two between {} and {}",
+                self.block_size,
+                TiFs::MIN_BLOCK_SIZE,
+                TiFs::MAX_BLOCK_SIZE
+            )));
+        }
+        // inline data is moved into the first block once it grows beyond the threshold
+        if self.inline_data_threshold > self.block_size {
+            return Err(FsError::InvalidConfig(format!(
+                "inline threshold({}) cannot be larger than block size({})",
+                self.inline_data_threshold, self.block_size
+            )));
+        }
+        Ok(())
+    }
+}
@@ -1,48 +1,132 @@
+use std::collections::HashSet;
 use std::fmt::{self, Debug};
 use std::future::Future;
-use std::matches;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::anyhow;
+use async_std::sync::RwLock as AsyncRwLock;
 use async_std::task::sleep;
 use async_trait::async_trait;
 use bytes::Bytes;
 use bytestring::ByteString;
-use fuser::consts::FOPEN_DIRECT_IO;
+use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
 use fuser::*;
-use libc::{F_RDLCK, F_UNLCK, F_WRLCK, O_DIRECT, SEEK_CUR, SEEK_END, SEEK_SET};
+use libc::{
+    F_RDLCK, F_UNLCK, F_WRLCK, O_ACCMODE, O_APPEND, O_DIRECT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC,
+    O_WRONLY, R_OK, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET, W_OK, X_OK,
+};
 use tikv_client::{Config, TransactionClient};
 use tracing::{debug, info, instrument, trace, warn};
 
-use super::dir::Directory;
+use super::block::BlockCache;
+use super::compression::Compression;
+use super::dir_hub::DirHub;
+use super::encryption::EncryptionKey;
 use super::error::{FsError, Result};
-use super::inode::Inode;
+use super::file_hub::FileHub;
+use super::id_map::IdMapping;
+use super::inode::{Inode, LockState};
+use super::inode_lease::InodeLease;
 use super::key::{ScopedKey, ROOT_INODE};
-use super::mode::make_mode;
-use super::reply::get_time;
-use super::reply::{Attr, Create, Data, Dir, DirItem, Entry, Lseek, Open, StatFs, Write};
+use super::lock_wait::LockWait;
+use super::meta::Meta;
+#[cfg(feature = "metrics")]
+use super::metrics::Metrics;
+use super::mode::{as_file_kind, make_mode, PERM_MASK};
+use super::pd;
+use super::rate_limit::RateLimiter;
+use super::reply::{
+    Attr, Create, Data, Dir, DirItem, DirPlus, Entry, Lseek, Open, StatFs, Write, Xattr,
+};
+use super::retry::RetryPolicy;
+use super::runtime::RuntimeConfig;
 use super::transaction::Txn;
+use super::write_back::{WriteBack, WriteBuffer};
 use super::{async_fs::AsyncFileSystem, reply::Lock};
 use crate::MountOption;
 
 pub struct TiFs {
     pub pd_endpoints: Vec<String>,
     pub config: Config,
-    pub client: TransactionClient,
-    pub direct_io: bool,
+    // replaced by `reconnect`
+    client: AsyncRwLock<Arc<TransactionClient>>,
+    reconnected: Mutex<Option<Instant>>,
+    pub name: String,
+    pub prefix: Vec<u8>,
+    /// Random id of this mount, recorded with the locks it takes.
+    pub mount_id: u64,
+    pub warm_cache: Option<PathBuf>,
+    pub skip_warm_up: bool,
+    /// Mutating operations fail with `EROFS`, and nothing is written to TiKV.
+    pub read_only: bool,
+    /// Read the filesystem as it was at this timestamp, which implies `read_only`.
+    pub snapshot_ts: Option<u64>,
+    pub config_file: Option<PathBuf>,
+    /// Runtime config from mount options, the base of reloading.
+    pub mount_config: RuntimeConfig,
+    runtime: RwLock<Arc<RuntimeConfig>>,
+    pub hub: FileHub,
+    pub dir_hub: DirHub,
+    // writes buffered by handlers of a mount with `write_back`
+    write_back: Option<WriteBack>,
+    /// Owners of files mapped between this host and the filesystem.
+    pub id_mapping: IdMapping,
+    pub block_cache: Arc<BlockCache>,
+    /// Block reads sent to TiKV by this mount.
+    pub block_reads: Arc<AtomicU64>,
+    inode_lease: Arc<InodeLease>,
+    pub block_size: u64,
+    pub write_limiter: RateLimiter,
+    // loaded from the meta by `init`
+    portable_names: AtomicBool,
+    // loaded from the meta by `init`
+    compression: RwLock<Compression>,
+    encryption: Option<EncryptionKey>,
+    // available bytes of the cluster and when they were queried
+    cluster_available: Mutex<Option<(Instant, u64)>>,
+    // directories in the layout before version 2 too large to be left, migrated in the background
+    large_legacy_dirs: Mutex<HashSet<u64>>,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
 }
 
-type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
+pub type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
 
 impl TiFs {
     pub const SCAN_LIMIT: u32 = 1 << 10;
-    pub const BLOCK_SIZE: u64 = 1 << 16;
-    pub const BLOCK_CACHE: usize = 1 << 25;
-    pub const DIR_CACHE: usize = 1 << 24;
-    pub const INODE_CACHE: usize = 1 << 24;
+    pub const DEFAULT_BLOCK_SIZE: u64 = 1 << 16;
+    pub const MIN_BLOCK_SIZE: u64 = 1 << 12;
+    pub const MAX_BLOCK_SIZE: u64 = 1 << 22;
+    pub const DEFAULT_BLOCK_CACHE: usize = 1 << 25;
+    pub const DEFAULT_DIR_CACHE: usize = 1 << 24;
+    pub const DEFAULT_INODE_CACHE: usize = 1 << 24;
     pub const MAX_NAME_LEN: u32 = 1 << 8;
-    pub const INLINE_DATA_THRESHOLD: u64 = 1 << 12;
+    /// Larger directories are listed page by page without a snapshot.
+    pub const MAX_DIR_SNAPSHOT: usize = 1 << 16;
+    /// `PATH_MAX` of Linux without the trailing NUL.
+    pub const MAX_SYMLINK_LEN: usize = 4095;
+    pub const DEFAULT_INLINE_DATA_THRESHOLD: u64 = 1 << 12;
+    /// Bytes copied by a transaction of `copy_file_range`, a multiple of any block size.
+    pub const COPY_CHUNK_SIZE: u64 = 1 << 26;
+    pub const CLUSTER_SPACE_TTL: Duration = Duration::from_secs(10);
+    pub const PD_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+    pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+    /// Least delay of a retry after a connection error, even by policies retrying at once.
+    pub const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+    /// Warming up regions at mount is given up after it, not to delay mounting on huge clusters.
+    pub const WARM_UP_TIMEOUT: Duration = Duration::from_secs(2);
+    /// Inode numbers leased from the meta at a time.
+    pub const INODE_LEASE_SIZE: u64 = 1 << 10;
+    /// Usage counters are changed in shards of inodes by their numbers, summed by `statfs`.
+    pub const USAGE_SHARDS: u64 = 64;
+    /// Directories stored as a single value larger than it are migrated once they're read.
+    pub const LEGACY_DIR_MIGRATE_SIZE: usize = 1 << 20;
+    /// Writes buffered by a handler of a mount with `write_back` are flushed beyond it.
+    pub const WRITE_BACK_THRESHOLD: usize = 1 << 22;
 
     #[instrument]
     pub async fn construct<S>(
@@ -53,19 +137,124 @@ impl TiFs {
     where
         S: Clone + Debug + Into<String>,
     {
+        let name = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::Name(name) => Some(name.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        if name.len() > Self::MAX_NAME_LEN as usize {
+            return Err(anyhow!("name of filesystem({}) is too long", name));
+        }
+
+        let mount_config = RuntimeConfig::from_mount_options(&options)?;
+        info!("runtime config: {:?}", mount_config);
+        #[cfg(feature = "metrics")]
+        let metrics = Metrics::new(&name).map_err(|err| anyhow!("{}", err))?;
+
         let client = TransactionClient::new_with_config(pd_endpoints.clone(), cfg.clone())
             .await
             .map_err(|err| anyhow!("{}", err))?;
         info!("connected to pd endpoints: {:?}", pd_endpoints);
-        Ok(TiFs {
-            client,
+        let block_cache = BlockCache::new(mount_config.block_cache_size, mount_config.block_size);
+        #[cfg(feature = "metrics")]
+        let block_cache = block_cache.with_metrics(metrics.clone());
+        let read_only = options
+            .iter()
+            .any(|option| matches!(option, MountOption::ReadOnly | MountOption::SnapshotTs(_)));
+        let fs = TiFs {
+            client: AsyncRwLock::new(Arc::new(client)),
+            reconnected: Mutex::new(None),
+            prefix: ScopedKey::namespace(&name),
+            name,
+            mount_id: rand::random(),
             pd_endpoints: pd_endpoints.clone().into_iter().map(Into::into).collect(),
             config: cfg,
-            direct_io: options
+            warm_cache: options.iter().find_map(|option| match option {
+                MountOption::WarmCache(path) => Some(path.clone()),
+                _ => None,
+            }),
+            skip_warm_up: options
                 .iter()
-                .find(|option| matches!(option, MountOption::DirectIO))
-                .is_some(),
-        })
+                .any(|option| matches!(option, MountOption::SkipWarmUp)),
+            read_only,
+            snapshot_ts: options.iter().find_map(|option| match option {
+                MountOption::SnapshotTs(ts) => Some(*ts),
+                _ => None,
+            }),
+            config_file: options.iter().find_map(|option| match option {
+                MountOption::ConfigFile(path) => Some(path.clone()),
+                _ => None,
+            }),
+            hub: FileHub::new(),
+            dir_hub: DirHub::new(),
+            write_back: if !read_only
+                && options
+                    .iter()
+                    .any(|option| matches!(option, MountOption::WriteBack))
+            {
+                Some(WriteBack::new())
+            } else {
+                None
+            },
+            id_mapping: IdMapping::from_mount_options(&options),
+            block_cache: Arc::new(block_cache),
+            block_reads: Arc::new(AtomicU64::new(0)),
+            inode_lease: Arc::new(InodeLease::new()),
+            block_size: mount_config.block_size,
+            write_limiter: RateLimiter::new(),
+            portable_names: AtomicBool::new(false),
+            compression: RwLock::new(Compression::None),
+            encryption: options.iter().find_map(|option| match option {
+                MountOption::Encryption(key) => Some(key.clone()),
+                _ => None,
+            }),
+            cluster_available: Mutex::new(None),
+            large_legacy_dirs: Mutex::new(HashSet::new()),
+            #[cfg(feature = "metrics")]
+            metrics,
+            runtime: RwLock::new(Arc::new(mount_config.clone())),
+            mount_config,
+        };
+        if fs.config_file.is_some() {
+            fs.reload_config_file().await?;
+        }
+        Ok(fs)
+    }
+
+    /// Current runtime config, operations should get it once and use the same one.
+    pub fn runtime(&self) -> Arc<RuntimeConfig> {
+        self.runtime.read().unwrap().clone()
+    }
+
+    /// Replace the runtime config by mount options overridden by `options`.
+    pub fn reload(&self, options: &[MountOption]) -> Result<()> {
+        let config = self.mount_config.apply(options)?;
+        self.block_cache.resize(config.block_cache_size);
+        if self.runtime().direct_io && !config.direct_io {
+            // writes with direct_io skip invalidating the cache
+            self.block_cache.clear();
+        }
+        info!("reload runtime config: {:?}", &config);
+        *self.runtime.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Reload options in the config file, one or more comma-separated options per line.
+    pub async fn reload_config_file(&self) -> Result<()> {
+        let path = self
+            .config_file
+            .as_ref()
+            .ok_or_else(|| FsError::InvalidConfig("no config file".to_string()))?;
+        let content = async_std::fs::read_to_string(path).await?;
+        let options = MountOption::to_vec(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        );
+        self.reload(&options)
     }
 
     async fn process_txn<F, T>(&self, txn: &mut Txn, f: F) -> Result<T>
@@ -77,70 +266,491 @@ impl TiFs {
             Ok(v) => {
                 txn.commit().await?;
                 trace!("transaction committed");
+                #[cfg(feature = "metrics")]
+                self.metrics.count_txn("committed");
                 Ok(v)
             }
             Err(e) => {
                 txn.rollback().await?;
                 debug!("transaction rollbacked");
+                #[cfg(feature = "metrics")]
+                self.metrics.count_txn("rolled_back");
                 Err(e)
             }
         }
     }
 
-    async fn with_optimistic<F, T>(&self, f: F) -> Result<T>
+    #[instrument(name = "txn", level = "debug", skip(self, f))]
+    async fn with_txn<F, T>(&self, f: F) -> Result<T>
     where
         T: 'static + Send,
         F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
     {
-        let mut txn = Txn::begin_optimistic(&self.client).await?;
-        self.process_txn(&mut txn, f).await
+        let runtime = self.runtime();
+        let client = self.client.read().await.clone();
+        let mut txn = if let Some(ts) = self.snapshot_ts {
+            Txn::begin_snapshot(&client, self.prefix.clone(), ts)
+        } else if runtime.pessimistic {
+            Txn::begin_pessimistic(&client, self.prefix.clone()).await?
+        } else {
+            Txn::begin_optimistic(&client, self.prefix.clone()).await?
+        };
+        if self.read_only {
+            txn = txn.with_read_only();
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.count_txn("begun");
+        let mut txn = txn
+            .with_inline_threshold(runtime.inline_data_threshold)
+            .with_block_size(self.block_size)
+            .with_compression(*self.compression.read().unwrap())
+            .with_inode_lease(self.inode_lease.clone())
+            .with_block_reads(self.block_reads.clone());
+        if let Some(key) = &self.encryption {
+            txn = txn.with_encryption(key.clone());
+        }
+        // direct_io reads and writes go to TiKV without the block cache
+        if !runtime.direct_io {
+            txn = txn.with_block_cache(self.block_cache.clone());
+        }
+        let result = self.process_txn(&mut txn, f).await;
+        self.observe_legacy_dirs(txn.take_legacy_dirs());
+        result
+    }
+
+    fn observe_legacy_dirs(&self, dirs: Vec<(u64, usize)>) {
+        for (ino, size) in dirs {
+            #[cfg(feature = "metrics")]
+            self.metrics.observe_legacy_dir(size);
+            if size > Self::LEGACY_DIR_MIGRATE_SIZE
+                && self.large_legacy_dirs.lock().unwrap().insert(ino)
+            {
+                warn!(
+                    "directory({}) is stored as a value of {} bytes, migrate it in the background",
+                    ino, size
+                );
+            }
+        }
     }
 
-    async fn spin<F, T>(&self, delay: Option<Duration>, mut f: F) -> Result<T>
+    /// Migrate large directories in the layout before version 2 found by reading them.
+    pub async fn migrate_large_dirs(&self) {
+        if self.read_only {
+            return;
+        }
+        // a directory is removed after its migration, which reads it and finds it again
+        let dirs: Vec<u64> = self
+            .large_legacy_dirs
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect();
+        for ino in dirs {
+            match self
+                .spin_with_policy(move |_, txn| Box::pin(txn.migrate_dir(ino)))
+                .await
+            {
+                Ok(()) => info!("migrate large directory({})", ino),
+                Err(err) => warn!("fail to migrate large directory({}): {}", ino, err),
+            }
+            self.large_legacy_dirs.lock().unwrap().remove(&ino);
+        }
+    }
+
+    /// Run `f` in a transaction, and retry on conflicts and region changes following the `policy`,
+    /// `FsError::TooManyRetries` is returned once the max attempts are exhausted.
+    pub async fn spin<F, T>(&self, policy: RetryPolicy, mut f: F) -> Result<T>
     where
         T: 'static + Send,
         F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
     {
+        let start = Instant::now();
+        let mut attempts = 0;
         loop {
-            match self.with_optimistic(&mut f).await {
+            attempts += 1;
+            match self.with_txn(&mut f).await {
                 Ok(v) => break Ok(v),
-                Err(FsError::KeyError(err)) => {
-                    trace!("spin because of a key error({})", err);
-                    if let Some(time) = delay {
-                        sleep(time).await;
+                Err(err @ FsError::KeyError(_)) | Err(err @ FsError::RegionError(_)) => {
+                    if !policy.allows(attempts, start.elapsed()) {
+                        warn!("give up after {} attempts: {}", attempts, err);
+                        break Err(FsError::TooManyRetries { attempts });
+                    }
+                    trace!("spin after attempt {} because of {}", attempts, err);
+                    #[cfg(feature = "metrics")]
+                    self.metrics.count_retry();
+                    let delay = policy.delay(attempts);
+                    if delay > Duration::default() {
+                        sleep(delay).await;
+                    }
+                }
+                Err(FsError::Disconnected(err)) => {
+                    if !policy.allows(attempts, start.elapsed()) {
+                        warn!("give up after {} attempts: {}", attempts, err);
+                        break Err(FsError::Disconnected(err));
                     }
+                    warn!("reconnect because of a connection error({})", err);
+                    if let Err(err) = self.reconnect().await {
+                        warn!("fail to reconnect: {}", err);
+                    }
+                    // a cluster down fails every attempt at once, don't spin on it
+                    sleep(policy.delay(attempts).max(Self::MIN_RECONNECT_DELAY)).await;
                 }
                 Err(err) => break Err(err),
             }
         }
     }
 
+    /// Replace the client by a new one connected to the PD endpoints,
+    /// unless it's replaced within `RECONNECT_INTERVAL`, as failing requests reconnect at once.
+    pub async fn reconnect(&self) -> Result<()> {
+        {
+            let mut reconnected = self.reconnected.lock().unwrap();
+            if matches!(*reconnected, Some(at) if at.elapsed() < Self::RECONNECT_INTERVAL) {
+                return Ok(());
+            }
+            *reconnected = Some(Instant::now());
+        }
+        let client =
+            TransactionClient::new_with_config(self.pd_endpoints.clone(), self.config.clone())
+                .await?;
+        // transactions begun on the old client go on with it
+        *self.client.write().await = Arc::new(client);
+        info!("reconnected to pd endpoints: {:?}", self.pd_endpoints);
+        Ok(())
+    }
+
+    /// Retry with the policy of the runtime config.
+    pub(super) async fn spin_with_policy<F, T>(&self, f: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
+    {
+        let policy = self.runtime().retry_policy;
+        self.spin(policy, f).await
+    }
+
     async fn spin_no_delay<F, T>(&self, f: F) -> Result<T>
     where
         T: 'static + Send,
         F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
     {
-        self.spin(None, f).await
+        self.spin(RetryPolicy::no_delay(), f).await
     }
 
-    async fn read_dir(&self, ino: u64) -> Result<Directory> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.read_dir(ino)))
-            .await
+    /// Registry of the metrics of this mount, to be served to Prometheus.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_handle(&self) -> prometheus::Registry {
+        self.metrics.registry.clone()
+    }
+
+    /// Available bytes of the cluster queried from PD, divided by the replicas each byte takes,
+    /// cached for `CLUSTER_SPACE_TTL`. Return None if no PD endpoint answers in time.
+    async fn cluster_available(&self) -> Option<u64> {
+        if let Some((queried, available)) = *self.cluster_available.lock().unwrap() {
+            if queried.elapsed() < Self::CLUSTER_SPACE_TTL {
+                return Some(available);
+            }
+        }
+        for endpoint in self.pd_endpoints.iter() {
+            let query = async {
+                let (_, available) = pd::cluster_space(endpoint).await?;
+                Ok::<_, FsError>(available / pd::max_replicas(endpoint).await?)
+            };
+            match async_std::future::timeout(Self::PD_QUERY_TIMEOUT, query).await {
+                Ok(Ok(available)) => {
+                    *self.cluster_available.lock().unwrap() = Some((Instant::now(), available));
+                    return Some(available);
+                }
+                Ok(Err(err)) => debug!("fail to query space from pd({}): {}", endpoint, err),
+                Err(_) => debug!("timeout querying space from pd({})", endpoint),
+            }
+        }
+        None
+    }
+
+    // Entries `..` and `.` after `offset`, with their offsets.
+    fn dot_entries(ino: u64, offset: i64) -> Vec<(i64, DirItem)> {
+        let dots = vec![
+            (
+                1,
+                DirItem {
+                    ino: ROOT_INODE,
+                    name: "..".to_string(),
+                    typ: FileType::Directory,
+                },
+            ),
+            (
+                2,
+                DirItem {
+                    ino,
+                    name: ".".to_string(),
+                    typ: FileType::Directory,
+                },
+            ),
+        ];
+        dots.into_iter()
+            .filter(|(dot_offset, _)| *dot_offset > offset)
+            .collect()
+    }
+
+    // Read entries after `offset` of a directory listed by handler `fh`.
+    // A listing starting from the beginning takes a snapshot of the directory into the handler,
+    // and the following pages of it are read from the snapshot.
+    //
+    // A directory removed by another mount lists no entries, as it was empty when removed.
+    async fn read_dir_page(&self, ino: u64, fh: u64, offset: i64) -> Result<Vec<(u64, DirItem)>> {
+        let offset = offset.max(0) as u64;
+        let snapshot = if offset == 0 && self.dir_hub.save_snapshot(ino, fh, None) {
+            let snapshot = match self
+                .spin_with_policy(move |_, txn| {
+                    Box::pin(txn.read_dir_entries(ino, TiFs::MAX_DIR_SNAPSHOT))
+                })
+                .await
+            {
+                Err(FsError::InodeNotFound { inode }) if inode == ino => {
+                    debug!("list removed dir({}) as empty", ino);
+                    Some(Vec::new())
+                }
+                result => result?,
+            }
+            .map(Arc::new);
+            self.dir_hub.save_snapshot(ino, fh, snapshot.clone());
+            snapshot
+        } else {
+            self.dir_hub.snapshot(ino, fh)
+        };
+
+        match snapshot {
+            Some(entries) => {
+                // entries are sorted by cookies, find the first one after `offset`
+                let start = entries
+                    .binary_search_by(|(cookie, _)| {
+                        cookie.cmp(&offset).then(std::cmp::Ordering::Less)
+                    })
+                    .unwrap_or_else(|index| index);
+                Ok(entries
+                    .iter()
+                    .skip(start)
+                    .take(TiFs::SCAN_LIMIT as usize)
+                    .cloned()
+                    .collect())
+            }
+            None => {
+                match self
+                    .spin_with_policy(move |_, txn| {
+                        Box::pin(txn.read_dir_page(ino, offset, TiFs::SCAN_LIMIT))
+                    })
+                    .await
+                {
+                    Err(FsError::InodeNotFound { inode }) if inode == ino => {
+                        debug!("list removed dir({}) as empty", ino);
+                        Ok(Vec::new())
+                    }
+                    result => result,
+                }
+            }
+        }
     }
 
     async fn read_inode(&self, ino: u64) -> Result<FileAttr> {
         let ino = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
+            .spin_with_policy(move |_, txn| Box::pin(txn.read_inode(ino)))
             .await?;
         Ok(ino.file_attr)
     }
 
-    async fn setlkw(&self, ino: u64, lock_owner: u64, typ: i32) -> Result<bool> {
+    async fn read_data(&self, ino: u64, start: u64, size: u64) -> Result<Vec<u8>> {
+        self.spin_with_policy(move |_, txn| Box::pin(txn.read_data(ino, start, Some(size))))
+            .await
+    }
+
+    // Entry of an inode replied to the kernel, with the owners of this host.
+    fn entry(&self, inode: Inode) -> Entry {
+        Entry::new(
+            self.id_mapping.local_attr(inode.file_attr),
+            inode.generation,
+            self.runtime().entry_ttl(),
+        )
+    }
+
+    // Track a handler opened in TiKV by this mount.
+    async fn track_open(&self, ino: u64, fh: u64, flags: i32) -> Open {
+        self.hub.make(ino, fh);
+        if let Some(write_back) = &self.write_back {
+            write_back.open(ino, fh, flags & O_APPEND != 0).await;
+        }
+        self.open_reply(fh, flags)
+    }
+
+    fn open_reply(&self, fh: u64, flags: i32) -> Open {
+        // the page cache is needed by mmap, and it's invalidated by the kernel
+        // once it sees the mtime changed by other mounts
+        let runtime = self.runtime();
+        let open_flags = if runtime.direct_io || flags & O_DIRECT != 0 {
+            FOPEN_DIRECT_IO
+        } else if runtime.keep_cache {
+            FOPEN_KEEP_CACHE
+        } else {
+            0
+        };
+        Open::new(fh, open_flags)
+    }
+
+    // Open a file created by others after the kernel looked it up, like open(2) would:
+    // the caller needs access to it, and it's truncated by O_TRUNC in the transaction opening it.
+    async fn open_existing(
+        &self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: ByteString,
+        file: String,
+        flags: i32,
+    ) -> Result<Create> {
+        let ino = self
+            .spin_with_policy(move |_, txn| {
+                let name = name.clone();
+                Box::pin(async move { txn.lookup(parent, name).await })
+            })
+            .await?;
+        if flags & O_TRUNC != 0 {
+            self.flush_inode(ino).await?;
+        }
+        let caller_uid = self.id_mapping.stored_uid(uid);
+        let caller_gid = self.id_mapping.stored_gid(gid);
+        let mask = Self::open_mask(flags);
+        let (inode, fh) = self
+            .spin_with_policy(move |_, txn| {
+                let file = file.clone();
+                Box::pin(async move {
+                    let mut inode = txn.lock_inode(ino).await?;
+                    match inode.kind {
+                        FileType::Directory => return Err(FsError::FileExist { file }),
+                        // the kernel follows symlinks before creating, ESTALE makes it walk the
+                        // path again, with the symlink found this time
+                        FileType::Symlink => return Err(FsError::StaleEntry { file }),
+                        _ => (),
+                    }
+                    if !Self::permits(&inode.file_attr, caller_uid, caller_gid, mask) {
+                        return Err(FsError::AccessDenied { ino });
+                    }
+                    if flags & O_TRUNC != 0 && inode.size > 0 {
+                        txn.truncate_data(&mut inode, 0).await?;
+                        inode.truncate_epoch += 1;
+                        inode.set_size(0);
+                        inode.mtime = SystemTime::now();
+                        inode.ctime = inode.mtime;
+                        txn.save_inode(&inode).await?;
+                    }
+                    let fh = txn.open(ino, flags & O_APPEND != 0).await?;
+                    // `open` counts the handler in the stored inode
+                    Ok((txn.read_inode(ino).await?, fh))
+                })
+            })
+            .await?;
+        self.hub.lookup(ino);
+        let entry = self.entry(inode);
+        let open = self.track_open(ino, fh, flags).await;
+        Ok(Create::new(
+            entry.stat,
+            entry.generation,
+            open.fh,
+            open.flags,
+            entry.time,
+        ))
+    }
+
+    // Lease a batch of inode numbers before creating a file if the lease is used up,
+    // the transaction creating it then doesn't need to modify `inode_next` in the meta.
+    async fn renew_inode_lease(&self) -> Result<()> {
+        if !self.inode_lease.is_empty() {
+            return Ok(());
+        }
+        let range = self
+            .spin_no_delay(|_, txn| Box::pin(txn.lease_inodes(Self::INODE_LEASE_SIZE)))
+            .await?;
+        self.inode_lease.renew(range);
+        Ok(())
+    }
+
+    /// Touch the key ranges of this filesystem, so the first operations after mount don't pay
+    /// for loading regions and connecting to their leaders.
+    async fn warm_up_regions(&self) {
+        let start = Instant::now();
+        let result = async_std::future::timeout(
+            Self::WARM_UP_TIMEOUT,
+            self.spin_no_delay(|_, txn| Box::pin(txn.warm_up())),
+        )
+        .await;
+        let elapsed = start.elapsed();
+        match result {
+            Ok(Ok(())) => info!("warmed up regions in {:?}", elapsed),
+            Ok(Err(err)) => warn!("fail to warm up regions: {}", err),
+            Err(_) => warn!("timeout warming up regions after {:?}", elapsed),
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.set_warm_up(elapsed);
+    }
+
+    /// Preload inodes and data of files listed (one path per line) in `list`.
+    async fn warm_up_cache(&self, list: &Path) -> Result<()> {
+        let chunk_size = self.block_size * 64;
+
+        let content = async_std::fs::read_to_string(list).await?;
+        let mut paths = 0;
+        let mut bytes = 0;
+        for path in content
+            .lines()
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+        {
+            let path = path.to_owned();
+            let ino = match self
+                .spin_no_delay(move |_, txn| {
+                    let path = path.clone();
+                    Box::pin(async move { txn.resolve(&path).await })
+                })
+                .await
+            {
+                Ok(ino) => ino,
+                Err(err) => {
+                    warn!("fail to warm cache: {}", err);
+                    continue;
+                }
+            };
+
+            let attr = self.read_inode(ino).await?;
+            if attr.kind == FileType::RegularFile {
+                for start in (0..attr.size).step_by(chunk_size as usize) {
+                    bytes += self.read_data(ino, start, chunk_size).await?.len();
+                }
+            }
+            paths += 1;
+        }
+        info!("warmed {} paths, {} bytes loaded", paths, bytes);
+        Ok(())
+    }
+
+    async fn setlkw(
+        &self,
+        ino: u64,
+        lock_owner: u64,
+        typ: i32,
+        pid: u32,
+        timeout: Option<Duration>,
+    ) -> Result<bool> {
+        let mount = self.mount_id;
+        let mut wait = LockWait::new(timeout);
         loop {
             let res = self
-                .spin_no_delay(move |_, txn| {
+                .spin_with_policy(move |_, txn| {
                     Box::pin(async move {
                         let mut inode = txn.read_inode(ino).await?;
+                        if inode.lock_state.remove_dead_owners(mount) {
+                            warn!("setlkw, remove dead lock owners of inode({})", ino);
+                            txn.save_inode(&inode).await?;
+                        }
                         match typ {
                             F_WRLCK => {
                                 if inode.lock_state.owner_set.len() > 1 {
@@ -148,7 +758,7 @@ impl TiFs {
                                 }
                                 if inode.lock_state.owner_set.is_empty() {
                                     inode.lock_state.lk_type = F_WRLCK;
-                                    inode.lock_state.owner_set.insert(lock_owner);
+                                    inode.lock_state.add_owner(lock_owner, pid, mount);
                                     txn.save_inode(&inode).await?;
                                     return Ok(true);
                                 }
@@ -158,14 +768,15 @@ impl TiFs {
                                     txn.save_inode(&inode).await?;
                                     return Ok(true);
                                 }
-                                Err(FsError::InvalidLock)
+                                // held by another owner, wait for it
+                                Ok(false)
                             }
                             F_RDLCK => {
                                 if inode.lock_state.lk_type == F_WRLCK {
                                     return Ok(false);
                                 } else {
                                     inode.lock_state.lk_type = F_RDLCK;
-                                    inode.lock_state.owner_set.insert(lock_owner);
+                                    inode.lock_state.add_owner(lock_owner, pid, mount);
                                     txn.save_inode(&inode).await?;
                                     return Ok(true);
                                 }
@@ -178,12 +789,263 @@ impl TiFs {
             if res {
                 break;
             }
+            match wait.next_delay() {
+                Some(delay) => sleep(delay).await,
+                None => return Err(FsError::LockTimeout),
+            }
         }
 
         Ok(true)
     }
 
+    async fn reap_handle(&self, ino: u64, fh: u64) {
+        // handlers of read-only mounts are only kept in the hub
+        if !self.hub.close(ino, fh) || self.read_only {
+            return;
+        }
+        self.close_buffer(ino, fh).await;
+        match self
+            .spin_no_delay(move |_, txn| Box::pin(txn.close(ino, fh)))
+            .await
+        {
+            Ok(()) => {
+                self.hub.count_reaped();
+                #[cfg(feature = "metrics")]
+                self.metrics.count_reaped();
+                info!(
+                    "reap file handler({}) of inode({}), {} reaped in total",
+                    fh,
+                    ino,
+                    self.hub.reaped()
+                );
+            }
+            Err(err) => warn!(
+                "fail to reap file handler({}) of inode({}): {}",
+                fh, ino, err
+            ),
+        }
+    }
+
+    /// Reap handlers idle for longer than `handle_idle_timeout`, run every second by the mount.
+    pub async fn reap_idle_handles(&self) {
+        if let Some(timeout) = self.runtime().handle_idle_timeout {
+            for (ino, fh) in self.hub.idle(timeout) {
+                self.reap_handle(ino, fh).await;
+            }
+        }
+    }
+
+    // Commit the data buffered by a handler, it's kept in the buffer if the commit fails.
+    async fn flush_buffer(&self, ino: u64, fh: u64, buffer: &mut WriteBuffer) -> Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let (start, data) = buffer.take();
+        let data: Bytes = data.into();
+        let result = {
+            let data = data.clone();
+            self.spin_with_policy(move |_, txn| {
+                Box::pin(txn.write(ino, fh, start as i64, data.clone()))
+            })
+            .await
+        };
+        if result.is_err() {
+            buffer.start = start;
+            buffer.data = data.to_vec();
+        }
+        result.map(|_| ())
+    }
+
+    // Flush the data buffered for `ino` by handlers of this mount that may overlap `start..end`,
+    // before an operation reading or changing the data stored in TiKV.
+    async fn flush_range(&self, ino: u64, start: u64, end: u64) -> Result<()> {
+        let buffers = match self.write_back.as_ref().and_then(|w| w.get(ino)) {
+            Some(buffers) => buffers,
+            None => return Ok(()),
+        };
+        let mut buffers = buffers.lock().await;
+        for (fh, buffer) in buffers.handles.iter_mut() {
+            if buffer.overlaps(start, end) {
+                self.flush_buffer(ino, *fh, buffer).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush_inode(&self, ino: u64) -> Result<()> {
+        self.flush_range(ino, 0, u64::MAX).await
+    }
+
+    async fn flush_handle(&self, ino: u64, fh: u64) -> Result<()> {
+        let buffers = match self.write_back.as_ref().and_then(|w| w.get(ino)) {
+            Some(buffers) => buffers,
+            None => return Ok(()),
+        };
+        let mut buffers = buffers.lock().await;
+        match buffers.handles.get_mut(&fh) {
+            Some(buffer) => self.flush_buffer(ino, fh, buffer).await,
+            None => Ok(()),
+        }
+    }
+
+    // Flush the buffer of a handler being closed and drop it, the data is lost if it fails.
+    async fn close_buffer(&self, ino: u64, fh: u64) {
+        let write_back = match &self.write_back {
+            Some(write_back) => write_back,
+            None => return,
+        };
+        if let Some(buffers) = write_back.get(ino) {
+            let mut buffers = buffers.lock().await;
+            if let Some(mut buffer) = buffers.handles.remove(&fh) {
+                if let Err(err) = self.flush_buffer(ino, fh, &mut buffer).await {
+                    warn!(
+                        "lose {} bytes buffered by file handler({}) of inode({}): {}",
+                        buffer.data.len(),
+                        fh,
+                        ino,
+                        err
+                    );
+                }
+            }
+        }
+        write_back.remove_idle(ino);
+    }
+
+    // Merge a write into the buffer of its handler, which is flushed once it's not contiguous
+    // with the write or grows beyond `WRITE_BACK_THRESHOLD`.
+    async fn write_buffered(
+        &self,
+        write_back: &WriteBack,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Write> {
+        let buffers = write_back.get(ino).ok_or(FsError::FhNotFound { ino, fh })?;
+        let mut buffers = buffers.lock().await;
+        // overlapping data buffered by other handlers is written before this write
+        let end = offset + data.len() as u64;
+        for (other, buffer) in buffers.handles.iter_mut() {
+            if *other != fh && (buffer.overlaps(offset, end) || buffer.append_mode) {
+                self.flush_buffer(ino, *other, buffer).await?;
+            }
+        }
+        let buffer = buffers
+            .handles
+            .get_mut(&fh)
+            .ok_or(FsError::FhNotFound { ino, fh })?;
+        if !buffer.merge(offset, &data) {
+            self.flush_buffer(ino, fh, buffer).await?;
+            buffer.merge(offset, &data);
+        }
+        if buffer.data.len() >= Self::WRITE_BACK_THRESHOLD {
+            self.flush_buffer(ino, fh, buffer).await?;
+        }
+        Ok(Write::new(data.len() as u32))
+    }
+
+    // Fail a mutating operation before it begins a transaction on a read-only mount.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Fail an operation tifs cannot perform, unless it's mounted with `pretend_legacy`
+    // for applications that depend on such operations being ignored.
+    fn unsupported(&self, operation: &str) -> Result<()> {
+        if self.runtime().pretend_legacy {
+            warn!("ignore unsupported {}", operation);
+            Ok(())
+        } else {
+            Err(FsError::NotSupported(operation.to_owned()))
+        }
+    }
+
+    // POSIX rules of changing attributes, which the kernel applies as well with
+    // `default_permissions`. FUSE requests carry only the primary group of the caller,
+    // so its membership of the target group of chgrp is left to the kernel, and only
+    // the primary group is matched against the group that may write the file.
+    #[allow(clippy::too_many_arguments)]
+    fn check_setattr(
+        attr: &FileAttr,
+        caller_uid: u32,
+        caller_gid: u32,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<()> {
+        if caller_uid == 0 {
+            return Ok(());
+        }
+        let owner = attr.uid == caller_uid;
+        let operation = if mode.is_some() && !owner {
+            "chmod"
+        } else if uid.map_or(false, |uid| uid != attr.uid) {
+            "chown"
+        } else if gid.map_or(false, |gid| gid != attr.gid) && !owner {
+            "chgrp"
+        } else if (matches!(atime, Some(TimeOrNow::SpecificTime(_)))
+            || matches!(mtime, Some(TimeOrNow::SpecificTime(_))))
+            && !owner
+        {
+            "setting times"
+        } else if (atime.is_some() || mtime.is_some())
+            && !owner
+            && !Self::permits(attr, caller_uid, caller_gid, W_OK)
+        {
+            "touching"
+        } else {
+            return Ok(());
+        };
+        Err(FsError::NotPermitted {
+            ino: attr.ino,
+            operation,
+        })
+    }
+
+    // Whether the caller may access `attr` for `mask` of `R_OK`, `W_OK` and `X_OK`, by the bits
+    // of the class it falls in: the owner, the group, or others. Root may read and write
+    // anything, and execute what anyone may. Only the primary group of the caller is known.
+    fn permits(attr: &FileAttr, caller_uid: u32, caller_gid: u32, mask: i32) -> bool {
+        if caller_uid == 0 {
+            return mask & X_OK == 0 || attr.perm & 0o111 != 0;
+        }
+        let class = if attr.uid == caller_uid {
+            attr.perm >> 6
+        } else if attr.gid == caller_gid {
+            attr.perm >> 3
+        } else {
+            attr.perm
+        };
+        class as i32 & mask == mask
+    }
+
+    // Access needed to open a file with `flags`.
+    fn open_mask(flags: i32) -> i32 {
+        let mask = match flags & O_ACCMODE {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            O_RDWR => R_OK | W_OK,
+            _ => 0,
+        };
+        if flags & O_TRUNC != 0 {
+            mask | W_OK
+        } else {
+            mask
+        }
+    }
+
     fn check_file_name(name: &str) -> Result<()> {
+        if name.contains('\0') {
+            return Err(FsError::InvalidName {
+                name: name.to_string(),
+            });
+        }
         if name.len() <= Self::MAX_NAME_LEN as usize {
             Ok(())
         } else {
@@ -192,28 +1054,152 @@ impl TiFs {
             })
         }
     }
-}
 
-impl Debug for TiFs {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_fmt(format_args!("tifs({:?})", self.pd_endpoints))
+    // Check a name to be created, existing names are only checked by `check_file_name`.
+    fn check_new_name(&self, name: &str) -> Result<()> {
+        Self::check_file_name(name)?;
+        if self.portable_names.load(Ordering::Relaxed) {
+            Self::check_portable_name(name)?;
+        }
+        Ok(())
     }
-}
 
-#[async_trait]
-impl AsyncFileSystem for TiFs {
-    #[tracing::instrument]
-    async fn init(&self, gid: u32, uid: u32, config: &mut KernelConfig) -> Result<()> {
-        // config
-        //     .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS)
-        //     .expect("kernel config failed to add cap_fuse FUSE_POSIX_LOCKS");
-        config
-            .add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS)
-            .expect("kernel config failed to add cap_fuse FUSE_CAP_FLOCK_LOCKS");
+    /// Check that `name` is valid on Windows.
+    pub fn check_portable_name(name: &str) -> Result<()> {
+        const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+        const RESERVED_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL"];
 
-        self.spin_no_delay(move |fs, txn| {
+        let invalid = |reason: String| FsError::NonPortableName {
+            name: name.to_string(),
+            reason,
+        };
+        if let Some(c) = name
+            .chars()
+            .find(|c| RESERVED_CHARS.contains(c) || c.is_ascii_control())
+        {
+            return Err(invalid(format!("reserved character {:?}", c)));
+        }
+        if let Some(c) = name.chars().last().filter(|c| *c == '.' || *c == ' ') {
+            return Err(invalid(format!("trailing {:?}", c)));
+        }
+        // device names are reserved with any extension
+        let stem = name
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        let numbered_device = stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && matches!(stem.as_bytes()[3], b'1'..=b'9');
+        if RESERVED_NAMES.contains(&stem.as_str()) || numbered_device {
+            return Err(invalid(format!("reserved device name {:?}", stem)));
+        }
+        Ok(())
+    }
+
+    fn check_symlink_target(target: &[u8]) -> Result<()> {
+        if target.len() <= Self::MAX_SYMLINK_LEN {
+            Ok(())
+        } else {
+            Err(FsError::NameTooLong {
+                file: String::from_utf8_lossy(target).into_owned(),
+            })
+        }
+    }
+
+    /// Initialize the filesystem for this mount: verify or create the meta, count the mount
+    /// as live and make the root directory owned by `uid` and `gid` if it's missing.
+    pub async fn initialize(&self, gid: u32, uid: u32) -> Result<()> {
+        let (gid, uid) = (
+            self.id_mapping.stored_gid(gid),
+            self.id_mapping.stored_uid(uid),
+        );
+        if !self.skip_warm_up {
+            self.warm_up_regions().await;
+        }
+
+        self.spin_with_policy(move |fs, txn| {
             Box::pin(async move {
-                info!("initializing tifs on {:?} ...", &fs.pd_endpoints);
+                info!(
+                    "initializing tifs({}) on {:?} ...",
+                    &fs.name, &fs.pd_endpoints
+                );
+                match txn.read_meta().await? {
+                    None => {
+                        let mut meta = Meta::new();
+                        meta.name = fs.name.clone();
+                        meta.block_size = fs.block_size;
+                        txn.save_meta(&meta).await?;
+                    }
+                    Some(meta) if meta.layout_version > Meta::LAYOUT_VERSION => {
+                        return Err(FsError::UnsupportedLayout {
+                            version: meta.layout_version,
+                        });
+                    }
+                    // blocks would be indexed wrongly with another size
+                    Some(meta) if meta.block_size() != fs.block_size => {
+                        return Err(FsError::BlockSizeMismatch {
+                            expected: fs.block_size,
+                            found: meta.block_size(),
+                        });
+                    }
+                    Some(mut meta) if meta.layout_version < Meta::LAYOUT_VERSION => {
+                        // names are recorded since version 1
+                        if meta.layout_version > 0 && meta.name != fs.name {
+                            return Err(FsError::NameMismatch {
+                                expected: fs.name.clone(),
+                                found: meta.name,
+                            });
+                        }
+                        meta.name = fs.name.clone();
+                        meta.layout_version = Meta::LAYOUT_VERSION;
+                        txn.save_meta(&meta).await?;
+                    }
+                    Some(meta) if meta.name != fs.name => {
+                        return Err(FsError::NameMismatch {
+                            expected: fs.name.clone(),
+                            found: meta.name,
+                        });
+                    }
+                    Some(_) => (),
+                }
+                let mut meta = txn.meta().await?;
+                let mut changed = false;
+                if fs.mount_config.portable_names && !meta.portable_names {
+                    meta.portable_names = true;
+                    changed = true;
+                }
+                if let Some(compression) = fs.mount_config.compression {
+                    changed |= meta.compression != compression;
+                    meta.compression = compression;
+                }
+                // data written with a missing or another key would be unreadable
+                match (&fs.encryption, &meta.key_check) {
+                    (Some(key), None) => {
+                        meta.key_check = Some(key.check_value());
+                        changed = true;
+                    }
+                    (Some(key), Some(check)) if key.verify(check) => (),
+                    (None, None) => (),
+                    _ => return Err(FsError::EncryptionKeyMismatch),
+                }
+                if !fs.read_only {
+                    if meta.live_mounts > 0 {
+                        info!(
+                            "{} other mounts are alive or crashed, run tifs-fsck while none is mounted if any crashed",
+                            meta.live_mounts
+                        );
+                    }
+                    meta.live_mounts += 1;
+                    changed = true;
+                }
+                if changed {
+                    txn.save_meta(&meta).await?;
+                }
+                fs.portable_names
+                    .store(meta.portable_names, Ordering::Relaxed);
+                *fs.compression.write().unwrap() = meta.compression;
+
                 let root_inode = txn.read_inode(ROOT_INODE).await;
                 if let Err(FsError::InodeNotFound { inode: _ }) = root_inode {
                     let attr = txn
@@ -232,30 +1218,142 @@ impl AsyncFileSystem for TiFs {
                 }
             })
         })
-        .await
+        .await?;
+
+        if let Some(list) = &self.warm_cache {
+            if let Err(err) = self.warm_up_cache(list).await {
+                warn!("fail to warm cache from {:?}: {}", list, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for TiFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("tifs({:?})", self.pd_endpoints))
+    }
+}
+
+#[async_trait]
+impl AsyncFileSystem for TiFs {
+    #[cfg(feature = "metrics")]
+    fn observe(&self, op: &'static str, elapsed: Duration, errno: Option<libc::c_int>) {
+        self.metrics.observe_op(op, elapsed, errno);
+        self.metrics.set_open_handles(self.hub.len());
+    }
+
+    #[tracing::instrument]
+    async fn init(&self, gid: u32, uid: u32, config: &mut KernelConfig) -> Result<()> {
+        // config
+        //     .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS)
+        //     .expect("kernel config failed to add cap_fuse FUSE_POSIX_LOCKS");
+        config
+            .add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS)
+            .expect("kernel config failed to add cap_fuse FUSE_CAP_FLOCK_LOCKS");
+        if config
+            .add_capabilities(fuser::consts::FUSE_AUTO_INVAL_DATA)
+            .is_err()
+        {
+            warn!("kernel doesn't support FUSE_AUTO_INVAL_DATA, cached pages may be stale");
+        }
+
+        self.initialize(gid, uid).await
+    }
+
+    // Handlers left open by an unmount are released, so that inodes unlinked while open are
+    // removed and their buffered data is flushed, and locks taken through this mount are released.
+    async fn destroy(&self) {
+        for (ino, fh) in self.hub.handles() {
+            if let Err(err) = self.release(ino, fh, 0, None, true).await {
+                warn!(
+                    "fail to release file handler({}) of inode({}): {}",
+                    fh, ino, err
+                );
+            }
+        }
+        if self.read_only {
+            return;
+        }
+        for (ino, owners) in self.hub.take_lock_owners() {
+            let result = self
+                .spin_no_delay(move |_, txn| {
+                    let owners = owners.clone();
+                    Box::pin(async move {
+                        let mut inode = match txn.read_inode(ino).await {
+                            Err(FsError::InodeNotFound { inode: _ }) => return Ok(()),
+                            res => res?,
+                        };
+                        let mut released = false;
+                        for owner in owners {
+                            released |= inode.lock_state.release(owner);
+                        }
+                        if released {
+                            txn.save_inode(&inode).await?;
+                        }
+                        Ok(())
+                    })
+                })
+                .await;
+            if let Err(err) = result {
+                warn!("fail to release locks of inode({}): {}", ino, err);
+            }
+        }
+        let result = self
+            .spin_no_delay(move |_, txn| {
+                Box::pin(txn.update_meta(|meta| {
+                    meta.live_mounts = meta.live_mounts.saturating_sub(1);
+                }))
+            })
+            .await;
+        match result {
+            Ok(()) => info!("tifs({}) is unmounted cleanly", self.name),
+            Err(err) => warn!("fail to record unmount of tifs({}): {}", self.name, err),
+        }
     }
 
     #[tracing::instrument]
     async fn lookup(&self, parent: u64, name: ByteString) -> Result<Entry> {
         Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
-            let name = name.clone();
-            Box::pin(async move {
-                let ino = txn.lookup(parent, name).await?;
-                Ok(Entry::new(txn.read_inode(ino).await?.into(), 0))
+        let inode = self
+            .spin_with_policy(move |_, txn| {
+                let name = name.clone();
+                Box::pin(async move {
+                    let ino = txn.lookup(parent, name).await?;
+                    txn.read_inode(ino).await
+                })
             })
-        })
-        .await
+            .await?;
+        self.hub.lookup(inode.ino);
+        Ok(self.entry(inode))
+    }
+
+    async fn forget(&self, ino: u64, nlookup: u64) {
+        if self.hub.forget(ino, nlookup) {
+            for (ino, fh) in self.hub.handles_of(ino) {
+                self.reap_handle(ino, fh).await;
+            }
+        }
     }
 
     #[tracing::instrument]
     async fn getattr(&self, ino: u64) -> Result<Attr> {
-        Ok(Attr::new(self.read_inode(ino).await?))
+        let mut attr = self.read_inode(ino).await?;
+        // handlers of this mount see the data they buffered
+        if let Some(buffers) = self.write_back.as_ref().and_then(|w| w.get(ino)) {
+            attr.size = buffers.lock().await.size(attr.size);
+        }
+        Ok(Attr::new(
+            self.id_mapping.local_attr(attr),
+            self.runtime().entry_ttl(),
+        ))
     }
 
     #[tracing::instrument]
     async fn setattr(
         &self,
+        caller_uid: u32,
+        caller_gid: u32,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -270,82 +1368,163 @@ impl AsyncFileSystem for TiFs {
         bkuptime: Option<SystemTime>,
         flags: Option<u32>,
     ) -> Result<Attr> {
-        self.spin_no_delay(move |_, txn| {
+        self.check_writable()?;
+        // attributes live in the inode, so `fh` makes no difference
+        if chgtime.is_some() || bkuptime.is_some() {
+            self.unsupported("chgtime and bkuptime")?;
+        }
+        if size.is_some() {
+            self.flush_inode(ino).await?;
+        }
+        // owners are checked and changed as they are stored
+        let caller_uid = self.id_mapping.stored_uid(caller_uid);
+        let caller_gid = self.id_mapping.stored_gid(caller_gid);
+        let uid = uid.map(|uid| self.id_mapping.stored_uid(uid));
+        let gid = gid.map(|gid| self.id_mapping.stored_gid(gid));
+        let ttl = self.runtime().entry_ttl();
+        self.spin_with_policy(move |_, txn| {
             Box::pin(async move {
-                // TODO: how to deal with fh, chgtime, bkuptime?
-                let mut attr = txn.read_inode(ino).await?;
+                let mut attr = txn.lock_inode(ino).await?;
+                Self::check_setattr(&attr, caller_uid, caller_gid, mode, uid, gid, atime, mtime)?;
+                if size.is_some() && attr.kind == FileType::Directory {
+                    return Err(FsError::IsADirectory { ino });
+                }
+                match size {
+                    // only a reduction deletes data written by others, growing keeps it
+                    Some(size) if size < attr.size => {
+                        txn.truncate_data(&mut attr, size).await?;
+                        attr.truncate_epoch += 1;
+                    }
+                    Some(size) if size > attr.size => txn.extend_data(&mut attr, size).await?,
+                    _ => (),
+                }
                 attr.perm = match mode {
-                    Some(m) => m as _,
+                    Some(m) => (m & PERM_MASK) as _,
                     None => attr.perm,
                 };
                 attr.uid = uid.unwrap_or(attr.uid);
                 attr.gid = gid.unwrap_or(attr.gid);
+                // like the kernel, chown by others than root drops the set-user-id bit,
+                // and the set-group-id bit of files executable by the group
+                if caller_uid != 0
+                    && (uid.is_some() || gid.is_some())
+                    && mode.is_none()
+                    && attr.kind != FileType::Directory
+                {
+                    attr.perm &= !(libc::S_ISUID as u16);
+                    if attr.perm & 0o010 != 0 {
+                        attr.perm &= !(libc::S_ISGID as u16);
+                    }
+                }
+                // and chmod by others than root outside the group of the file drops
+                // the set-group-id bit
+                if caller_uid != 0 && mode.is_some() && attr.gid != caller_gid {
+                    attr.perm &= !(libc::S_ISGID as u16);
+                }
                 attr.set_size(size.unwrap_or(attr.size));
                 attr.atime = match atime {
                     None => attr.atime,
                     Some(TimeOrNow::SpecificTime(t)) => t,
                     Some(TimeOrNow::Now) => SystemTime::now(),
                 };
+                // an explicit change of the size modifies the file, other changes keep its mtime
                 attr.mtime = match mtime {
                     Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) | None => SystemTime::now(),
+                    Some(TimeOrNow::Now) => SystemTime::now(),
+                    None if size.is_some() => SystemTime::now(),
+                    None => attr.mtime,
                 };
                 attr.ctime = ctime.unwrap_or(SystemTime::now());
                 attr.crtime = crtime.unwrap_or(attr.crtime);
                 attr.flags = flags.unwrap_or(attr.flags);
                 txn.save_inode(&attr).await?;
-                Ok(Attr {
-                    time: get_time(),
-                    attr: attr.into(),
-                })
+                Ok(Attr::new(attr.file_attr, ttl))
             })
         })
         .await
+        .map(|mut attr| {
+            attr.attr = self.id_mapping.local_attr(attr.attr);
+            attr
+        })
     }
 
     #[tracing::instrument]
-    async fn readdir(&self, ino: u64, _fh: u64, mut offset: i64) -> Result<Dir> {
-        let mut dir = Dir::offset(offset as usize);
-
-        if offset == 0 {
-            dir.push(DirItem {
-                ino: ROOT_INODE,
-                name: "..".to_string(),
-                typ: FileType::Directory,
-            });
+    async fn readdir(&self, ino: u64, fh: u64, offset: i64) -> Result<Dir> {
+        let mut dir = Dir::new();
+        for (offset, item) in Self::dot_entries(ino, offset) {
+            dir.push(offset, item);
         }
 
-        if offset <= 1 {
-            dir.push(DirItem {
-                ino,
-                name: ".".to_string(),
-                typ: FileType::Directory,
-            });
+        for (cookie, item) in self.read_dir_page(ino, fh, offset).await? {
+            dir.push(cookie as i64, item);
         }
+        debug!("read directory {:?}", &dir);
+        Ok(dir)
+    }
 
-        offset -= 2.min(offset);
+    #[tracing::instrument]
+    async fn readdirplus(&self, ino: u64, fh: u64, offset: i64) -> Result<DirPlus> {
+        let mut directory = Self::dot_entries(ino, offset);
+        directory.extend(
+            self.read_dir_page(ino, fh, offset)
+                .await?
+                .into_iter()
+                .map(|(cookie, item)| (cookie as i64, item)),
+        );
+        let ttl = self.runtime().entry_ttl();
+        // inodes of the whole page are read by a single request, retried without copying the page
+        let inos: Arc<Vec<u64>> = Arc::new(directory.iter().map(|(_, item)| item.ino).collect());
+        let inodes = self
+            .spin_with_policy(move |_, txn| {
+                let inos = inos.clone();
+                Box::pin(async move { txn.read_inodes(&inos).await })
+            })
+            .await?;
 
-        let directory = self.read_dir(ino).await?;
-        for (item) in directory.into_iter().skip(offset as usize) {
-            dir.push(item)
+        let mut dir = DirPlus::new();
+        for (offset, mut item) in directory {
+            // the entry may be removed after the page is read
+            let inode = match inodes.get(&item.ino) {
+                Some(inode) => inode,
+                None => {
+                    debug!("skip entry({}) of removed inode({})", item.name, item.ino);
+                    continue;
+                }
+            };
+            if item.typ != inode.kind {
+                warn!(
+                    "type of entry({}) in dir({}) is {:?}, but inode({}) is {:?}",
+                    item.name, ino, item.typ, item.ino, inode.kind
+                );
+                item.typ = inode.kind;
+            }
+            if item.name != "." && item.name != ".." {
+                self.hub.lookup(item.ino);
+            }
+            dir.push(
+                offset,
+                item,
+                Entry::new(
+                    self.id_mapping.local_attr(inode.file_attr),
+                    inode.generation,
+                    ttl,
+                ),
+            );
         }
-        debug!("read directory {:?}", &dir);
         Ok(dir)
     }
 
     #[tracing::instrument]
     async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
         // TODO: deal with flags
+        if self.read_only {
+            self.read_inode(ino).await?;
+            return Ok(self.open_reply(self.hub.make_local(ino), flags));
+        }
         let fh = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.open(ino)))
+            .spin_with_policy(move |_, txn| Box::pin(txn.open(ino, flags & O_APPEND != 0)))
             .await?;
-
-        let mut open_flags = 0;
-        if self.direct_io || flags | O_DIRECT != 0 {
-            open_flags |= FOPEN_DIRECT_IO;
-        }
-
-        Ok(Open::new(fh, open_flags))
+        Ok(self.track_open(ino, fh, flags).await)
     }
 
     #[tracing::instrument]
@@ -358,15 +1537,35 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Data> {
+        self.hub.touch(ino, fh);
+        if self.read_only {
+            self.hub
+                .get(ino, fh)
+                .ok_or(FsError::FhNotFound { ino, fh })?;
+            if offset < 0 {
+                return Err(FsError::InvalidOffset { ino, offset });
+            }
+            let data = self.read_data(ino, offset as u64, size as u64).await?;
+            #[cfg(feature = "metrics")]
+            self.metrics.count_read(data.len());
+            return Ok(Data::new(data));
+        }
+        if offset >= 0 {
+            self.flush_range(ino, offset as u64, offset as u64 + size as u64)
+                .await?;
+        }
         let data = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read(ino, fh, offset, size)))
+            .spin_with_policy(move |_, txn| Box::pin(txn.read(ino, fh, offset, size)))
             .await?;
+        #[cfg(feature = "metrics")]
+        self.metrics.count_read(data.len());
         Ok(Data::new(data))
     }
 
     #[tracing::instrument(skip(data))]
     async fn write(
         &self,
+        pid: u32,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -375,11 +1574,102 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
-        let data: Bytes = data.into();
-        let len = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.write(ino, fh, offset, data.clone())))
-            .await?;
-        Ok(Write::new(len as u32))
+        self.check_writable()?;
+        self.hub.touch(ino, fh);
+        // zero-length writes are used to check for errors, nothing needs to be written
+        if data.is_empty() {
+            return Ok(Write::new(0));
+        }
+        // the data, size and mtime are committed in a single transaction, so a write replied
+        // with an error leaves nothing visible, and a failed request of a large write split
+        // by the kernel leaves the prefix written by the requests before it.
+        if let Some(rate) = self.runtime().max_write_bytes_per_pid {
+            let delay = self.write_limiter.acquire(pid, rate, data.len() as u64);
+            if delay > Duration::default() {
+                trace!("throttle write of pid({}) for {:?}", pid, delay);
+                sleep(delay).await;
+            }
+        }
+        let written = if let Some(write_back) = &self.write_back {
+            if offset < 0 {
+                return Err(FsError::InvalidOffset { ino, offset });
+            }
+            self.write_buffered(write_back, ino, fh, offset as u64, data)
+                .await?
+        } else {
+            let data: Bytes = data.into();
+            let len = self
+                .spin_with_policy(move |_, txn| Box::pin(txn.write(ino, fh, offset, data.clone())))
+                .await?;
+            Write::new(len as u32)
+        };
+        #[cfg(feature = "metrics")]
+        self.metrics.count_written(written.size as usize);
+        Ok(written)
+    }
+
+    #[tracing::instrument]
+    async fn copy_file_range(
+        &self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+    ) -> Result<Write> {
+        self.check_writable()?;
+        self.hub.touch(ino_in, fh_in);
+        self.hub.touch(ino_out, fh_out);
+        self.flush_inode(ino_in).await?;
+        self.flush_inode(ino_out).await?;
+        // large copies are split into transactions of `COPY_CHUNK_SIZE`, to stay in the size limit
+        // of a transaction; a failed chunk after others are copied ends the copy short,
+        // so does a truncation of the destination between chunks
+        let len = len.min(u32::MAX as u64);
+        let mut copied = 0;
+        let mut epoch = None;
+        while copied < len {
+            let offset = copied as i64;
+            let chunk = (len - copied).min(Self::COPY_CHUNK_SIZE);
+            let result = self
+                .spin_with_policy(move |_, txn| {
+                    Box::pin(async move {
+                        let epoch = txn.check_truncate_epoch(ino_out, epoch).await?;
+                        let n = txn
+                            .copy_file_range(
+                                ino_in,
+                                fh_in,
+                                offset_in + offset,
+                                ino_out,
+                                fh_out,
+                                offset_out + offset,
+                                chunk,
+                            )
+                            .await?;
+                        Ok((n, epoch))
+                    })
+                })
+                .await;
+            match result {
+                Ok((n, current)) => {
+                    epoch = Some(current);
+                    copied += n;
+                    // the end of the source
+                    if n < chunk {
+                        break;
+                    }
+                }
+                Err(err) if copied > 0 => {
+                    warn!("copy_file_range ends after {} bytes: {}", copied, err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Write::new(copied as u32))
     }
 
     /// Create a directory.
@@ -393,17 +1683,27 @@ impl AsyncFileSystem for TiFs {
         uid: u32,
         _umask: u32,
     ) -> Result<Entry> {
-        Self::check_file_name(&name)?;
+        self.check_writable()?;
+        self.check_new_name(&name)?;
+        let (gid, uid) = (
+            self.id_mapping.stored_gid(gid),
+            self.id_mapping.stored_uid(uid),
+        );
+        self.renew_inode_lease().await?;
         let attr = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.mkdir(parent, name.clone(), mode, gid, uid)))
+            .spin_with_policy(move |_, txn| {
+                Box::pin(txn.mkdir(parent, name.clone(), mode, gid, uid))
+            })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        self.hub.lookup(attr.ino);
+        Ok(self.entry(attr))
     }
 
     #[tracing::instrument]
     async fn rmdir(&self, parent: u64, raw_name: ByteString) -> Result<()> {
+        self.check_writable()?;
         Self::check_file_name(&raw_name)?;
-        self.spin_no_delay(move |_, txn| Box::pin(txn.rmdir(parent, raw_name.clone())))
+        self.spin_with_policy(move |_, txn| Box::pin(txn.rmdir(parent, raw_name.clone())))
             .await
     }
 
@@ -418,18 +1718,46 @@ impl AsyncFileSystem for TiFs {
         _umask: u32,
         rdev: u32,
     ) -> Result<Entry> {
-        Self::check_file_name(&name)?;
+        self.check_writable()?;
+        self.check_new_name(&name)?;
+        // the kernel checks CAP_MKNOD of the caller already, this keeps other clients of
+        // `mknod` from creating device nodes for unprivileged users. It's checked on the local
+        // uid of the caller, as a mapped or squashed root is no root.
+        if matches!(
+            as_file_kind(mode),
+            FileType::CharDevice | FileType::BlockDevice
+        ) && uid != 0
+        {
+            return Err(FsError::DeviceNotPermitted {
+                file: name.to_string(),
+            });
+        }
+        // `create` makes files by `mknod`, so they are mapped here as well
+        let (gid, uid) = (
+            self.id_mapping.stored_gid(gid),
+            self.id_mapping.stored_uid(uid),
+        );
+        self.renew_inode_lease().await?;
+        // special files (FIFOs, sockets and device nodes) are only stored as inodes with their
+        // type and rdev, I/O on them is handled by the kernel and never reaches tifs.
         let attr = self
-            .spin_no_delay(move |_, txn| {
+            .spin_with_policy(move |_, txn| {
                 Box::pin(txn.make_inode(parent, name.clone(), mode, gid, uid, rdev))
             })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        self.hub.lookup(attr.ino);
+        Ok(self.entry(attr))
     }
 
+    // Permissions are checked by the kernel as tifs is mounted with `default_permissions`,
+    // ENOSYS makes the kernel stop calling it otherwise.
     #[tracing::instrument]
     async fn access(&self, ino: u64, mask: i32) -> Result<()> {
-        Ok(())
+        if self.runtime().pretend_legacy {
+            Ok(())
+        } else {
+            Err(FsError::unimplemented())
+        }
     }
 
     async fn create(
@@ -442,44 +1770,133 @@ impl AsyncFileSystem for TiFs {
         umask: u32,
         flags: i32,
     ) -> Result<Create> {
-        Self::check_file_name(&name)?;
-        let entry = self.mknod(parent, name, mode, gid, uid, umask, 0).await?;
+        self.check_writable()?;
+        self.check_new_name(&name)?;
+        if as_file_kind(mode) == FileType::Socket {
+            return Err(FsError::NotSupported(format!(
+                "cannot create and open socket({}), use mknod instead",
+                name
+            )));
+        }
+        // the existence check and the creation are in the same transaction of `make_inode`
+        let entry = match self
+            .mknod(parent, name.clone(), mode, gid, uid, umask, 0)
+            .await
+        {
+            // created by others after the kernel looked it up, open it unless O_EXCL is set
+            Err(FsError::FileExist { file }) if flags & O_EXCL == 0 => {
+                return self
+                    .open_existing(uid, gid, parent, name, file, flags)
+                    .await;
+            }
+            res => res?,
+        };
         let open = self.open(entry.stat.ino, flags).await?;
         Ok(Create::new(
             entry.stat,
             entry.generation,
             open.fh,
             open.flags,
+            entry.time,
         ))
     }
 
     async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek> {
-        self.spin_no_delay(move |_, txn| {
+        self.hub.touch(ino, fh);
+        if self.read_only {
+            self.hub
+                .get(ino, fh)
+                .ok_or(FsError::FhNotFound { ino, fh })?;
+        }
+        self.flush_inode(ino).await?;
+        self.spin_with_policy(move |fs, txn| {
             Box::pin(async move {
-                let mut file_handler = txn.read_fh(ino, fh).await?;
+                if !fs.read_only {
+                    txn.read_fh(ino, fh).await?;
+                }
                 let inode = txn.read_inode(ino).await?;
-                let target_cursor = match whence {
+                // the kernel resolves SEEK_SET and SEEK_CUR by the file position it tracks,
+                // which is never sent, so SEEK_CUR cannot be resolved here
+                let target = match whence {
                     SEEK_SET => offset,
-                    SEEK_CUR => file_handler.cursor as i64 + offset,
                     SEEK_END => inode.size as i64 + offset,
+                    SEEK_DATA | SEEK_HOLE => {
+                        if offset < 0 {
+                            return Err(FsError::InvalidOffset { ino, offset });
+                        }
+                        let found = if whence == SEEK_DATA {
+                            txn.seek_data(&inode, offset as u64).await?
+                        } else {
+                            txn.seek_hole(&inode, offset as u64).await?
+                        };
+                        found.ok_or(FsError::NoSeekTarget { ino, offset })? as i64
+                    }
                     _ => return Err(FsError::UnknownWhence { whence }),
                 };
 
-                if target_cursor < 0 {
+                if target < 0 {
                     return Err(FsError::InvalidOffset {
                         ino: inode.ino,
-                        offset: target_cursor,
+                        offset: target,
                     });
                 }
-
-                file_handler.cursor = target_cursor as u64;
-                txn.save_fh(ino, fh, &file_handler).await?;
-                Ok(Lseek::new(target_cursor))
+                Ok(Lseek::new(target))
             })
         })
         .await
     }
 
+    // Writes are committed to tikv before they are replied, so there's nothing to flush or sync
+    // but the buffers of a mount with `write_back`.
+    // A flush comes on each close(2), which releases the POSIX locks of the closing owner.
+    // Locks are taken through the mount, so owners not known to the hub hold none.
+    async fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<()> {
+        self.hub.touch(ino, fh);
+        self.flush_handle(ino, fh).await?;
+        if !self.hub.holds_lock(ino, lock_owner) {
+            return Ok(());
+        }
+        self.spin_with_policy(move |_, txn| {
+            Box::pin(async move {
+                let mut inode = txn.read_inode(ino).await?;
+                if inode.lock_state.release(lock_owner) {
+                    debug!("release lock of owner({}) on inode({})", lock_owner, ino);
+                    txn.save_inode(&inode).await?;
+                }
+                Ok(())
+            })
+        })
+        .await?;
+        self.hub.remove_lock_owner(ino, lock_owner);
+        Ok(())
+    }
+
+    async fn fsync(&self, ino: u64, fh: u64, _datasync: bool) -> Result<()> {
+        self.hub.touch(ino, fh);
+        self.flush_inode(ino).await
+    }
+
+    async fn fsyncdir(&self, ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
+        // nothing to flush, but a directory removed by another mount is gone
+        self.read_inode(ino).await?;
+        Ok(())
+    }
+
+    async fn opendir(&self, ino: u64, _flags: i32) -> Result<Open> {
+        let fh = self.dir_hub.make(ino);
+        Ok(Open::new(fh, 0))
+    }
+
+    async fn releasedir(&self, ino: u64, fh: u64, _flags: i32) -> Result<()> {
+        if !self.dir_hub.close(ino, fh) {
+            debug!(
+                "directory handler({}) of inode({}) is already released",
+                fh, ino
+            );
+        }
+        Ok(())
+    }
+
     async fn release(
         &self,
         ino: u64,
@@ -488,21 +1905,41 @@ impl AsyncFileSystem for TiFs {
         _lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.close(ino, fh)))
+        // the kernel may release a handler twice after an interrupted request
+        if !self.hub.close(ino, fh) {
+            debug!("file handler({}) of inode({}) is already released", fh, ino);
+            return Ok(());
+        }
+        if self.read_only {
+            return Ok(());
+        }
+        self.close_buffer(ino, fh).await;
+        match self
+            .spin_with_policy(move |_, txn| Box::pin(txn.close(ino, fh)))
             .await
+        {
+            Err(FsError::FhNotFound { ino, fh }) => {
+                debug!("file handler({}) of inode({}) is already closed", fh, ino);
+                Ok(())
+            }
+            res => res,
+        }
     }
 
     /// Create a hard link.
     async fn link(&self, ino: u64, newparent: u64, newname: ByteString) -> Result<Entry> {
-        Self::check_file_name(&newname)?;
+        self.check_writable()?;
+        self.check_new_name(&newname)?;
         let inode = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.link(ino, newparent, newname.clone())))
+            .spin_with_policy(move |_, txn| Box::pin(txn.link(ino, newparent, newname.clone())))
             .await?;
-        Ok(Entry::new(inode.into(), 0))
+        self.hub.lookup(inode.ino);
+        Ok(self.entry(inode))
     }
 
     async fn unlink(&self, parent: u64, raw_name: ByteString) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.unlink(parent, raw_name.clone())))
+        self.check_writable()?;
+        self.spin_with_policy(move |_, txn| Box::pin(txn.unlink(parent, raw_name.clone())))
             .await
     }
 
@@ -514,13 +1951,20 @@ impl AsyncFileSystem for TiFs {
         new_raw_name: ByteString,
         _flags: u32,
     ) -> Result<()> {
+        self.check_writable()?;
         Self::check_file_name(&raw_name)?;
-        Self::check_file_name(&new_raw_name)?;
-        self.spin_no_delay(move |_, txn| {
+        self.check_new_name(&new_raw_name)?;
+        self.spin_with_policy(move |_, txn| {
             let name = raw_name.clone();
             let new_name = new_raw_name.clone();
             Box::pin(async move {
                 let ino = txn.lookup(parent, name.clone()).await?;
+                // renaming onto another link of the same inode does nothing,
+                // link and unlink would free the inode otherwise
+                if txn.get_index(newparent, new_name.clone()).await? == Some(ino) {
+                    return Ok(());
+                }
+                // the link count goes up by link and back by unlink
                 txn.link(ino, newparent, new_name).await?;
                 txn.unlink(parent, name).await
             })
@@ -537,31 +1981,59 @@ impl AsyncFileSystem for TiFs {
         name: ByteString,
         link: ByteString,
     ) -> Result<Entry> {
-        Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
-            let name = name.clone();
-            let link = link.clone();
-            Box::pin(async move {
-                let mut attr = txn
-                    .make_inode(
-                        parent,
-                        name,
-                        make_mode(FileType::Symlink, 0o777),
-                        gid,
-                        uid,
-                        0,
-                    )
-                    .await?;
-
-                txn.write_link(&mut attr, link.into_bytes()).await?;
-                Ok(Entry::new(attr.into(), 0))
+        self.check_writable()?;
+        self.check_new_name(&name)?;
+        Self::check_symlink_target(link.as_bytes())?;
+        let (gid, uid) = (
+            self.id_mapping.stored_gid(gid),
+            self.id_mapping.stored_uid(uid),
+        );
+        self.renew_inode_lease().await?;
+        let inode = self
+            .spin_with_policy(move |_, txn| {
+                let name = name.clone();
+                let link = link.clone();
+                Box::pin(async move {
+                    let mut attr = txn
+                        .make_inode(
+                            parent,
+                            name,
+                            make_mode(FileType::Symlink, 0o777),
+                            gid,
+                            uid,
+                            0,
+                        )
+                        .await?;
+
+                    txn.write_link(&mut attr, link.into_bytes()).await?;
+                    Ok(attr)
+                })
             })
+            .await?;
+        self.hub.lookup(inode.ino);
+        Ok(self.entry(inode))
+    }
+
+    // Extended attributes are not stored, but the kernel asks for `security.capability` on each
+    // write and exec, and for ACLs; ENOSYS would turn them off for the whole mount, so every
+    // inode is replied as having none.
+    async fn getxattr(&self, ino: u64, name: ByteString, _size: u32) -> Result<Xattr> {
+        Err(FsError::XattrNotFound {
+            ino,
+            name: name.to_string(),
         })
-        .await
+    }
+
+    async fn listxattr(&self, _ino: u64, size: u32) -> Result<Xattr> {
+        if size == 0 {
+            Ok(Xattr::size(0))
+        } else {
+            Ok(Xattr::data(Vec::new()))
+        }
     }
 
     async fn readlink(&self, ino: u64) -> Result<Data> {
-        self.spin(None, move |_, txn| {
+        self.spin_with_policy(move |_, txn| {
             Box::pin(async move { Ok(Data::new(txn.read_link(ino).await?)) })
         })
         .await
@@ -574,53 +2046,42 @@ impl AsyncFileSystem for TiFs {
         fh: u64,
         offset: i64,
         length: i64,
-        _mode: i32,
+        mode: i32,
     ) -> Result<()> {
-        self.spin_no_delay(move |_, txn| {
+        self.check_writable()?;
+        // punching holes, zeroing or collapsing ranges and keeping the size are not supported
+        if mode != 0 {
+            self.unsupported(&format!("fallocate mode({:#x})", mode))?;
+        }
+        self.hub.touch(ino, fh);
+        self.flush_inode(ino).await?;
+        self.spin_with_policy(move |_, txn| {
             Box::pin(async move {
-                let mut inode = txn.read_inode(ino).await?;
+                let mut inode = txn.lock_inode(ino).await?;
                 txn.fallocate(&mut inode, offset, length).await
             })
         })
         .await?;
         Ok(())
     }
-    // TODO: Find an api to calculate total and available space on tikv.
     async fn statfs(&self, _ino: u64) -> Result<StatFs> {
-        let bsize = Self::BLOCK_SIZE as u32;
-        let namelen = Self::MAX_NAME_LEN;
-        let (ffree, blocks, files) = self
-            .spin_no_delay(move |_, txn| {
-                Box::pin(async move {
-                    let next_inode = txn
-                        .read_meta()
-                        .await?
-                        .map(|meta| meta.inode_next)
-                        .unwrap_or(ROOT_INODE);
-                    let (b, f) = txn
-                        .scan(
-                            ScopedKey::inode_range(ROOT_INODE..next_inode),
-                            (next_inode - ROOT_INODE) as u32,
-                        )
-                        .await?
-                        .map(|pair| Inode::deserialize(pair.value()))
-                        .try_fold((0, 0), |(blocks, files), inode| {
-                            Ok::<_, FsError>((blocks + inode?.blocks, files + 1))
-                        })?;
-                    Ok((std::u64::MAX - next_inode, b, f))
-                })
-            })
+        let (usage, next_inode) = self
+            .spin_with_policy(move |_, txn| Box::pin(txn.read_usage()))
             .await?;
+        let total_inodes = usage.files.saturating_add(std::u64::MAX - next_inode);
+        let total_blocks = match self.cluster_available().await {
+            Some(available) => usage.blocks + available / self.block_size,
+            None => std::u64::MAX,
+        };
         Ok(StatFs::new(
-            blocks,
-            std::u64::MAX,
-            std::u64::MAX,
-            files,
-            ffree,
-            bsize,
-            namelen,
-            0,
-        ))
+            usage.blocks,
+            total_blocks,
+            usage.files,
+            total_inodes,
+            self.runtime().min_free_bytes,
+            self.block_size as u32,
+        )
+        .with_namelen(Self::MAX_NAME_LEN))
     }
 
     #[tracing::instrument]
@@ -635,7 +2096,14 @@ impl AsyncFileSystem for TiFs {
         pid: u32,
         sleep: bool,
     ) -> Result<()> {
-        let not_again = self.spin_no_delay(move |_, txn| {
+        self.check_writable()?;
+        let mount = self.mount_id;
+        // recorded before the lock is taken, so that a racing flush doesn't skip releasing it.
+        // An owner failing to lock costs its flush a transaction, which forgets it.
+        if typ != F_UNLCK {
+            self.hub.add_lock_owner(ino, lock_owner);
+        }
+        let not_again = self.spin_with_policy(move |_, txn| {
             Box::pin(async move {
                 let mut inode = txn.read_inode(ino).await?;
                 warn!("setlk, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
@@ -651,7 +2119,7 @@ impl AsyncFileSystem for TiFs {
                             }
                             return Err(FsError::InvalidLock);
                         }
-                        inode.lock_state.owner_set.insert(lock_owner);
+                        inode.lock_state.add_owner(lock_owner, pid, mount);
                         inode.lock_state.lk_type = F_RDLCK;
                         txn.save_inode(&inode).await?;
                         warn!("setlk F_RDLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
@@ -674,8 +2142,8 @@ impl AsyncFileSystem for TiFs {
                             return Err(FsError::InvalidLock);
                         },
                         F_UNLCK => {
-                            inode.lock_state.owner_set.clear();
-                            inode.lock_state.owner_set.insert(lock_owner);
+                            inode.lock_state = LockState::new(HashSet::new(), F_UNLCK);
+                            inode.lock_state.add_owner(lock_owner, pid, mount);
                             inode.lock_state.lk_type = F_WRLCK;
                             warn!("setlk F_WRLCK on F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
                             txn.save_inode(&inode).await?;
@@ -691,10 +2159,7 @@ impl AsyncFileSystem for TiFs {
                         _ => return Err(FsError::InvalidLock)
                     },
                     F_UNLCK => {
-                        inode.lock_state.owner_set.remove(&lock_owner);
-                        if inode.lock_state.owner_set.is_empty() {
-                            inode.lock_state.lk_type = F_UNLCK;
-                        }
+                        inode.lock_state.release(lock_owner);
                         txn.save_inode(&inode).await?;
                         warn!("setlk F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
                         Ok(true)
@@ -704,13 +2169,17 @@ impl AsyncFileSystem for TiFs {
             })
         })
         .await?;
-        if !not_again {
-            if self.setlkw(ino, lock_owner, typ).await? {
-                return Ok(());
-            }
+        if !not_again
+            && !self
+                .setlkw(ino, lock_owner, typ, pid, self.runtime().lock_timeout)
+                .await?
+        {
             return Err(FsError::InvalidLock);
         }
-        return Ok(());
+        if typ == F_UNLCK {
+            self.hub.remove_lock_owner(ino, lock_owner);
+        }
+        Ok(())
     }
 
     #[tracing::instrument]
@@ -725,7 +2194,7 @@ impl AsyncFileSystem for TiFs {
         pid: u32,
     ) -> Result<Lock> {
         // TODO: read only operation need not txn?
-        self.spin_no_delay(move |_, txn| {
+        self.spin_with_policy(move |_, txn| {
             Box::pin(async move {
                 let inode = txn.read_inode(ino).await?;
                 warn!("getlk, inode:{:?}, pid:{:?}", inode, pid);
@@ -735,3 +2204,175 @@ impl AsyncFileSystem for TiFs {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use fuser::{FileAttr, FileType, TimeOrNow};
+
+    use super::TiFs;
+    use crate::fs::error::FsError;
+
+    const OWNER: u32 = 1000;
+    const OTHER: u32 = 1001;
+
+    fn attr(perm: u16) -> FileAttr {
+        let now = SystemTime::now();
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm,
+            nlink: 1,
+            uid: OWNER,
+            gid: OWNER,
+            rdev: 0,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
+    fn not_permitted(result: super::Result<()>) -> Option<&'static str> {
+        match result {
+            Err(FsError::NotPermitted { ino: 2, operation }) => Some(operation),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn only_owner_or_root_chmods() {
+        let attr = attr(0o644);
+        let chmod = |caller| {
+            TiFs::check_setattr(&attr, caller, caller, Some(0o777), None, None, None, None)
+        };
+        assert_eq!(not_permitted(chmod(OTHER)), Some("chmod"));
+        assert!(chmod(OWNER).is_ok());
+        assert!(chmod(0).is_ok());
+    }
+
+    #[test]
+    fn only_root_changes_owner() {
+        let attr = attr(0o644);
+        let chown = |caller, uid| {
+            TiFs::check_setattr(&attr, caller, caller, None, Some(uid), None, None, None)
+        };
+        assert_eq!(not_permitted(chown(OWNER, OTHER)), Some("chown"));
+        assert!(chown(OWNER, OWNER).is_ok());
+        assert!(chown(0, OTHER).is_ok());
+    }
+
+    #[test]
+    fn others_touch_writable_files_only() {
+        let set_times = |perm, time| {
+            TiFs::check_setattr(
+                &attr(perm),
+                OTHER,
+                OTHER,
+                None,
+                None,
+                None,
+                Some(time),
+                None,
+            )
+        };
+        assert_eq!(
+            not_permitted(set_times(0o666, TimeOrNow::SpecificTime(SystemTime::now()))),
+            Some("setting times")
+        );
+        assert_eq!(
+            not_permitted(set_times(0o644, TimeOrNow::Now)),
+            Some("touching")
+        );
+        assert!(set_times(0o666, TimeOrNow::Now).is_ok());
+    }
+
+    #[test]
+    fn touching_checks_the_class_of_the_caller() {
+        const GROUP: u32 = 2000;
+        let mut file = attr(0o602);
+        file.gid = GROUP;
+        let touch = |attr: &FileAttr, uid, gid| {
+            TiFs::check_setattr(attr, uid, gid, None, None, None, Some(TimeOrNow::Now), None)
+        };
+        // the group class applies to members, even if others may write
+        assert_eq!(not_permitted(touch(&file, OTHER, GROUP)), Some("touching"));
+        assert!(touch(&file, OTHER, OTHER).is_ok());
+        file.perm = 0o620;
+        assert!(touch(&file, OTHER, GROUP).is_ok());
+        assert_eq!(not_permitted(touch(&file, OTHER, OTHER)), Some("touching"));
+        // owners touch their files, and root touches any
+        file.perm = 0o004;
+        assert!(touch(&file, OWNER, OTHER).is_ok());
+        assert!(touch(&file, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn symlink_targets_fit_in_a_page() {
+        let target = |len| vec![b'a'; len];
+        assert!(TiFs::check_symlink_target(&target(TiFs::MAX_SYMLINK_LEN)).is_ok());
+        for len in &[4096, 4097] {
+            assert!(matches!(
+                TiFs::check_symlink_target(&target(*len)),
+                Err(FsError::NameTooLong { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn names_with_nul_are_invalid() {
+        for name in &["\0", "a\0b", "a\0"] {
+            assert!(matches!(
+                TiFs::check_file_name(name),
+                Err(FsError::InvalidName { .. })
+            ));
+        }
+        assert!(TiFs::check_file_name("a b").is_ok());
+        assert!(matches!(
+            TiFs::check_file_name(&"a".repeat(TiFs::MAX_NAME_LEN as usize + 1)),
+            Err(FsError::NameTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn portable_names_avoid_windows_reservations() {
+        let reason = |name: &str| match TiFs::check_portable_name(name) {
+            Err(FsError::NonPortableName { reason, .. }) => Some(reason),
+            _ => None,
+        };
+        for name in &["a<b", "a:b", "a\\b", "a?", "a\tb", "\u{7f}"] {
+            assert!(
+                reason(name).unwrap().starts_with("reserved character"),
+                "{:?}",
+                name
+            );
+        }
+        assert_eq!(reason("a."), Some("trailing '.'".to_string()));
+        assert_eq!(reason("a "), Some("trailing ' '".to_string()));
+        for name in &["con", "NUL.txt", "Com1", "lpt9.tar.gz"] {
+            assert!(
+                reason(name).unwrap().starts_with("reserved device name"),
+                "{:?}",
+                name
+            );
+        }
+        for name in &[
+            "a.b",
+            "console",
+            "COM0",
+            "COM10",
+            "LPT",
+            "nul_",
+            ".hidden",
+            "日本語",
+        ] {
+            assert_eq!(reason(name), None, "{:?}", name);
+        }
+    }
+}
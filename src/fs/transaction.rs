@@ -1,35 +1,309 @@
-use std::ops::{Deref, DerefMut};
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::{FileAttr, FileType};
-use tikv_client::{Transaction, TransactionClient};
-use tracing::{debug, trace};
-
-use super::block::empty_block;
-use super::dir::Directory;
+use tikv_client::{
+    Key, KvPair, Snapshot, Timestamp, TimestampExt, Transaction, TransactionClient, Value,
+};
+use tracing::{debug, error, instrument, trace};
+
+use super::block::{empty_block, BlockCache};
+use super::block_map::BlockMap;
+use super::compression::{decode_block, encode_block, is_encrypted, Compression};
+use super::dir::{decode_item, encode_item, Directory};
+use super::encryption::{EncryptionKey, FileKey, INLINE_INDEX};
 use super::error::{FsError, Result};
 use super::file_handler::FileHandler;
 use super::index::Index;
 use super::inode::Inode;
+use super::inode_lease::InodeLease;
 use super::key::{ScopedKey, ROOT_INODE};
-use super::meta::Meta;
+use super::meta::{Meta, Usage, UsageDelta};
 use super::mode::{as_file_kind, as_file_perm, make_mode};
 use super::reply::DirItem;
+use super::serialize::{deserialize, serialize, ENCODING};
 use super::tikv_fs::TiFs;
 
-pub struct Txn(Transaction);
+// A snapshot reads at a past timestamp and cannot write.
+enum TxnKind {
+    Transaction(Transaction),
+    Snapshot(Snapshot),
+}
+
+pub struct Txn {
+    txn: TxnKind,
+    read_only: bool,
+    prefix: Vec<u8>,
+    block_cache: Option<Arc<BlockCache>>,
+    // counts block reads sent to TiKV
+    block_reads: Option<Arc<AtomicU64>>,
+    inode_lease: Option<Arc<InodeLease>>,
+    inline_data_threshold: u64,
+    block_size: u64,
+    compression: Compression,
+    encryption: Option<EncryptionKey>,
+    // directories read in the layout before version 2, with the size of their values
+    legacy_dirs: Mutex<Vec<(u64, usize)>>,
+    // blocks of inodes as read or written by this transaction, None if they are not stored
+    stored_blocks: Mutex<HashMap<u64, Option<u64>>>,
+}
 
 impl Txn {
-    pub async fn begin_optimistic(client: &TransactionClient) -> Result<Self> {
-        Ok(Txn(client.begin_optimistic().await?))
+    /// Begin an optimistic transaction on the filesystem whose keys start with `prefix`.
+    pub async fn begin_optimistic(client: &TransactionClient, prefix: Vec<u8>) -> Result<Self> {
+        Ok(Txn {
+            txn: TxnKind::Transaction(client.begin_optimistic().await?),
+            read_only: false,
+            prefix,
+            block_cache: None,
+            block_reads: None,
+            inode_lease: None,
+            inline_data_threshold: TiFs::DEFAULT_INLINE_DATA_THRESHOLD,
+            block_size: TiFs::DEFAULT_BLOCK_SIZE,
+            compression: Compression::None,
+            encryption: None,
+            legacy_dirs: Default::default(),
+            stored_blocks: Default::default(),
+        })
+    }
+
+    pub fn with_inline_threshold(mut self, threshold: u64) -> Self {
+        self.inline_data_threshold = threshold;
+        self
+    }
+
+    /// Begin a pessimistic transaction on the filesystem whose keys start with `prefix`.
+    pub async fn begin_pessimistic(client: &TransactionClient, prefix: Vec<u8>) -> Result<Self> {
+        Ok(Txn {
+            txn: TxnKind::Transaction(client.begin_pessimistic().await?),
+            read_only: false,
+            prefix,
+            block_cache: None,
+            block_reads: None,
+            inode_lease: None,
+            inline_data_threshold: TiFs::DEFAULT_INLINE_DATA_THRESHOLD,
+            block_size: TiFs::DEFAULT_BLOCK_SIZE,
+            compression: Compression::None,
+            encryption: None,
+            legacy_dirs: Default::default(),
+            stored_blocks: Default::default(),
+        })
+    }
+
+    /// Begin a read-only transaction reading the filesystem as it was at the timestamp `ts`.
+    pub fn begin_snapshot(client: &TransactionClient, prefix: Vec<u8>, ts: u64) -> Self {
+        Txn {
+            txn: TxnKind::Snapshot(client.snapshot(Timestamp::from_version(ts))),
+            read_only: true,
+            prefix,
+            block_cache: None,
+            block_reads: None,
+            inode_lease: None,
+            inline_data_threshold: TiFs::DEFAULT_INLINE_DATA_THRESHOLD,
+            block_size: TiFs::DEFAULT_BLOCK_SIZE,
+            compression: Compression::None,
+            encryption: None,
+            legacy_dirs: Default::default(),
+            stored_blocks: Default::default(),
+        }
+    }
+
+    /// Fail all writes of this transaction with `FsError::ReadOnly`.
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn writable(&mut self) -> Result<&mut Transaction> {
+        match &mut self.txn {
+            TxnKind::Transaction(txn) if !self.read_only => Ok(txn),
+            _ => Err(FsError::ReadOnly),
+        }
+    }
+
+    /// Commit the writes of this transaction, a snapshot has nothing to commit.
+    pub async fn commit(&mut self) -> Result<()> {
+        if let TxnKind::Transaction(txn) = &mut self.txn {
+            txn.commit().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn rollback(&mut self) -> Result<()> {
+        if let TxnKind::Transaction(txn) = &mut self.txn {
+            txn.rollback().await?;
+        }
+        Ok(())
+    }
+
+    /// Take directories in the layout before version 2 read by this transaction,
+    /// with the size of their values.
+    pub fn take_legacy_dirs(&mut self) -> Vec<(u64, usize)> {
+        std::mem::take(self.legacy_dirs.get_mut().unwrap())
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypt blocks and inline data written, and decrypt those read, by keys derived from `key`.
+    pub fn with_encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    fn file_key(&self, ino: u64) -> Option<FileKey> {
+        self.encryption.as_ref().map(|key| key.file_key(ino))
+    }
+
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Use the block size stored in the meta, for tools working on the filesystem without mounting it.
+    pub async fn with_stored_block_size(self) -> Result<Self> {
+        let block_size = self.meta().await?.block_size();
+        Ok(self.with_block_size(block_size))
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn with_block_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some(cache);
+        self
+    }
+
+    /// Take numbers of new inodes from `lease` before leasing them from the meta one by one.
+    pub fn with_inode_lease(mut self, lease: Arc<InodeLease>) -> Self {
+        self.inode_lease = Some(lease);
+        self
+    }
+
+    /// Count block reads sent to TiKV, which the block cache spares, in `counter`.
+    pub fn with_block_reads(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.block_reads = Some(counter);
+        self
+    }
+
+    fn count_block_read(&self) {
+        if let Some(counter) = &self.block_reads {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    pub async fn open(&mut self, ino: u64) -> Result<u64> {
+    fn invalidate_blocks(&self, ino: u64, range: Range<u64>) {
+        if let Some(cache) = &self.block_cache {
+            cache.invalidate(ino, range);
+        }
+    }
+
+    fn prefixed(&self, key: impl Into<Key>) -> Key {
+        let key: Key = key.into();
+        let key: Vec<u8> = key.into();
+        let mut data = Vec::with_capacity(self.prefix.len() + key.len());
+        data.extend_from_slice(&self.prefix);
+        data.extend(key);
+        data.into()
+    }
+
+    #[instrument(level = "trace", skip(self, key))]
+    pub async fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let key = self.prefixed(key);
+        Ok(match &self.txn {
+            TxnKind::Transaction(txn) => txn.get(key).await?,
+            TxnKind::Snapshot(snapshot) => snapshot.get(key).await?,
+        })
+    }
+
+    /// Get values of `keys` in a single request, keys not found are left out.
+    #[instrument(level = "trace", skip(self, keys))]
+    pub async fn batch_get(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        let prefix_len = self.prefix.len();
+        let keys: Vec<Key> = keys.into_iter().map(|key| self.prefixed(key)).collect();
+        let pairs: Vec<KvPair> = match &self.txn {
+            TxnKind::Transaction(txn) => txn.batch_get(keys).await?.collect(),
+            TxnKind::Snapshot(snapshot) => snapshot.batch_get(keys).await?.collect(),
+        };
+        Ok(pairs.into_iter().map(move |pair| {
+            let key: &[u8] = pair.key().into();
+            KvPair::new(key[prefix_len..].to_vec(), pair.value().clone())
+        }))
+    }
+
+    #[instrument(level = "trace", skip(self, key, value))]
+    pub async fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let key = self.prefixed(key);
+        Ok(self.writable()?.put(key, value).await?)
+    }
+
+    #[instrument(level = "trace", skip(self, key))]
+    pub async fn delete(&mut self, key: impl Into<Key>) -> Result<()> {
+        let key = self.prefixed(key);
+        Ok(self.writable()?.delete(key).await?)
+    }
+
+    /// Scan keys of this filesystem, the prefix is stripped from the returned keys.
+    #[instrument(level = "trace", skip(self, range))]
+    pub async fn scan(
+        &self,
+        range: Range<Key>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        let prefix_len = self.prefix.len();
+        let range = self.prefixed(range.start)..self.prefixed(range.end);
+        let pairs: Vec<KvPair> = match &self.txn {
+            TxnKind::Transaction(txn) => txn.scan(range, limit).await?.collect(),
+            TxnKind::Snapshot(snapshot) => snapshot.scan(range, limit).await?.collect(),
+        };
+        Ok(pairs.into_iter().map(move |pair| {
+            let key: &[u8] = pair.key().into();
+            KvPair::new(key[prefix_len..].to_vec(), pair.value().clone())
+        }))
+    }
+
+    /// Delete at most `limit` keys of this filesystem, return the number of deleted keys.
+    /// This is the way to destroy a named filesystem, it refuses to work without a prefix.
+    pub async fn clear_namespace(&mut self, limit: u32) -> Result<usize> {
+        if self.prefix.is_empty() {
+            return Err(FsError::UnknownError(
+                "refuse to clear keys of the unnamed filesystem".to_string(),
+            ));
+        }
+        let range =
+            Key::from(self.prefix.clone())..Key::from(ScopedKey::namespace_end(&self.prefix));
+        let txn = self.writable()?;
+        let keys: Vec<Key> = txn
+            .scan(range, limit)
+            .await?
+            .map(KvPair::into_key)
+            .collect();
+        for key in keys.iter() {
+            txn.delete(key.clone()).await?;
+        }
+        Ok(keys.len())
+    }
+
+    pub async fn open(&mut self, ino: u64, append_mode: bool) -> Result<u64> {
         let mut inode = self.read_inode(ino).await?;
         let fh = inode.next_fh;
-        self.save_fh(ino, fh, &FileHandler::default()).await?;
+        self.save_fh(ino, fh, &FileHandler::new(append_mode))
+            .await?;
         inode.next_fh += 1;
         inode.opened_fh += 1;
         self.save_inode(&inode).await?;
@@ -59,29 +333,200 @@ impl Txn {
             .await?)
     }
 
+    // Offsets from FUSE are absolute, the kernel resolves the file position itself.
     pub async fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+        self.read_fh(ino, fh).await?;
+        if offset < 0 {
+            return Err(FsError::InvalidOffset { ino, offset });
+        }
+        self.read_data(ino, offset as u64, Some(size as u64)).await
+    }
+
+    pub async fn write(&mut self, ino: u64, fh: u64, offset: i64, data: Bytes) -> Result<usize> {
         let handler = self.read_fh(ino, fh).await?;
-        let start = handler.cursor as i64 + offset;
-        if start < 0 {
+        if offset < 0 {
+            return Err(FsError::InvalidOffset { ino, offset });
+        }
+        // the size is read in the transaction writing the data, so concurrent appends conflict
+        // and are retried instead of writing to the same offset
+        let start = if handler.append_mode {
+            self.read_inode(ino).await?.size
+        } else {
+            offset as u64
+        };
+        self.write_data(ino, start, data).await
+    }
+
+    pub async fn copy_file_range(
+        &mut self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+    ) -> Result<u64> {
+        self.read_fh(ino_in, fh_in).await?;
+        if offset_in < 0 {
             return Err(FsError::InvalidOffset {
-                ino: ino,
-                offset: start,
+                ino: ino_in,
+                offset: offset_in,
             });
         }
-        self.read_data(ino, start as u64, Some(size as u64)).await
+        self.read_fh(ino_out, fh_out).await?;
+        if offset_out < 0 {
+            return Err(FsError::InvalidOffset {
+                ino: ino_out,
+                offset: offset_out,
+            });
+        }
+        self.copy_data(ino_in, offset_in as u64, ino_out, offset_out as u64, len)
+            .await
     }
 
-    pub async fn write(&mut self, ino: u64, fh: u64, offset: i64, data: Bytes) -> Result<usize> {
-        let handler = self.read_fh(ino, fh).await?;
-        let start = handler.cursor as i64 + offset;
-        if start < 0 {
+    /// Offset of the first data at or after `offset`, None if there is no data after it.
+    /// Data is found by the stored blocks, a block is never read to tell whether it's zeroes.
+    pub async fn seek_data(&self, inode: &Inode, offset: u64) -> Result<Option<u64>> {
+        if offset >= inode.size {
+            return Ok(None);
+        }
+        if inode.inline_data.is_some() {
+            return Ok(Some(offset));
+        }
+        let blocks = offset / self.block_size..(inode.size + self.block_size - 1) / self.block_size;
+        let block = match &inode.block_map {
+            Some(map) => map.next_stored(blocks),
+            None => match self
+                .scan(ScopedKey::block_range(inode.ino, blocks), 1)
+                .await?
+                .next()
+            {
+                Some(pair) => Some(parse_block(&pair)?),
+                None => None,
+            },
+        };
+        Ok(block.map(|block| (block * self.block_size).max(offset)))
+    }
+
+    /// Offset of the first hole at or after `offset`, the size if the rest of the file is dense,
+    /// or None if `offset` is past the end.
+    pub async fn seek_hole(&self, inode: &Inode, offset: u64) -> Result<Option<u64>> {
+        if offset >= inode.size {
+            return Ok(None);
+        }
+        if inode.inline_data.is_some() {
+            return Ok(Some(inode.size));
+        }
+        let end_block = (inode.size + self.block_size - 1) / self.block_size;
+        let mut block = offset / self.block_size;
+        match &inode.block_map {
+            Some(map) => block = map.next_missing(block..end_block),
+            None => loop {
+                let pairs: Vec<KvPair> = self
+                    .scan(
+                        ScopedKey::block_range(inode.ino, block..end_block),
+                        TiFs::SCAN_LIMIT,
+                    )
+                    .await?
+                    .collect();
+                let mut contiguous = true;
+                for pair in pairs.iter() {
+                    if parse_block(pair)? != block {
+                        contiguous = false;
+                        break;
+                    }
+                    block += 1;
+                }
+                if !contiguous || pairs.len() < TiFs::SCAN_LIMIT as usize {
+                    break;
+                }
+            },
+        }
+        Ok(Some((block * self.block_size).max(offset).min(inode.size)))
+    }
+
+    /// Copy `len` bytes of `ino_in` from `start_in` to `ino_out` at `start_out`, return the copied size.
+    ///
+    /// Whole blocks are copied key by key when both offsets are aligned to blocks,
+    /// so holes stay holes; the rest is copied by reading and writing the data.
+    pub async fn copy_data(
+        &mut self,
+        ino_in: u64,
+        start_in: u64,
+        ino_out: u64,
+        start_out: u64,
+        len: u64,
+    ) -> Result<u64> {
+        let src = self.read_inode(ino_in).await?;
+        if start_in >= src.size {
+            return Ok(0);
+        }
+        let len = len.min(src.size - start_in).min(u32::MAX as u64);
+        if ino_in == ino_out && start_in < start_out + len && start_out < start_in + len {
             return Err(FsError::InvalidOffset {
-                ino: ino,
-                offset: start,
+                ino: ino_out,
+                offset: start_out as i64,
             });
         }
 
-        self.write_data(ino, start as u64, data).await
+        let mut copied = 0;
+        let full_blocks = len / self.block_size;
+        let aligned = start_in % self.block_size == 0 && start_out % self.block_size == 0;
+        let mut dst = self.lock_inode(ino_out).await?;
+        // encrypted values are bound to the file and the block, so they cannot be copied as they are
+        if aligned
+            && full_blocks > 0
+            && src.inline_data.is_none()
+            && dst.inline_data.is_none()
+            && self.encryption.is_none()
+        {
+            let src_block = start_in / self.block_size;
+            let dst_block = start_out / self.block_size;
+            let pairs: Vec<KvPair> = self
+                .scan(
+                    ScopedKey::block_range(ino_in, src_block..src_block + full_blocks),
+                    full_blocks as u32,
+                )
+                .await?
+                .collect();
+
+            let mut holes: Vec<bool> = vec![true; full_blocks as usize];
+            for pair in pairs {
+                let block = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::Block { ino: _, block } => block,
+                    _ => unreachable!("the keys from scanning should be always valid block keys"),
+                };
+                holes[(block - src_block) as usize] = false;
+                let out_block = block - src_block + dst_block;
+                self.put(ScopedKey::block(ino_out, out_block), pair.into_value())
+                    .await?;
+                dst.mark_blocks(out_block..out_block + 1);
+            }
+            for (i, _) in holes.into_iter().enumerate().filter(|(_, hole)| *hole) {
+                let out_block = dst_block + i as u64;
+                self.delete(ScopedKey::block(ino_out, out_block)).await?;
+                dst.unmark_blocks(out_block..out_block + 1);
+            }
+            self.invalidate_blocks(ino_out, dst_block..dst_block + full_blocks);
+
+            copied = full_blocks * self.block_size;
+            dst.mtime = SystemTime::now();
+            dst.ctime = SystemTime::now();
+            dst.set_size(dst.size.max(start_out + copied));
+            dst.data_version += 1;
+            self.save_inode(&dst).await?;
+        }
+        drop(dst);
+
+        if copied < len {
+            let data = self
+                .read_data(ino_in, start_in + copied, Some(len - copied))
+                .await?;
+            self.write_data(ino_out, start_out + copied, data.into())
+                .await?;
+        }
+        Ok(len)
     }
 
     pub async fn make_inode(
@@ -93,62 +538,171 @@ impl Txn {
         uid: u32,
         rdev: u32,
     ) -> Result<Inode> {
-        let mut meta = self.read_meta().await?.unwrap_or_default();
-        let ino = meta.inode_next;
-        meta.inode_next += 1;
-
+        // numbers leased by the mount come first, so creates don't conflict on the free-list
+        let ino = match self.inode_lease.as_ref().and_then(|lease| lease.take()) {
+            Some(ino) => ino,
+            None => self.lease_inodes(1).await?.start,
+        };
         debug!("get ino({})", ino);
-        self.save_meta(&meta).await?;
+        // leased numbers are not stored, saving the inode needs no read to tell it's new
+        self.stored_blocks.get_mut().unwrap().insert(ino, None);
 
         let file_type = as_file_kind(mode);
-        if parent >= ROOT_INODE {
-            if self.get_index(parent, name.clone()).await?.is_some() {
-                return Err(FsError::FileExist {
-                    file: name.to_string(),
-                });
-            }
-            self.set_index(parent, name.clone(), ino).await?;
-
-            let mut dir = self.read_dir(parent).await?;
-            debug!("read dir({:?})", &dir);
-
-            dir.push(DirItem {
-                ino,
-                name: name.to_string(),
-                typ: file_type,
+        if parent >= ROOT_INODE && self.get_index(parent, name.clone()).await?.is_some() {
+            return Err(FsError::FileExist {
+                file: name.to_string(),
             });
-
-            self.save_dir(parent, &dir).await?;
-            // TODO: update attributes of directory
         }
 
-        let inode = FileAttr {
+        // all timestamps of a new inode are the same instant, the birth time included
+        let now = SystemTime::now();
+        let mut inode: Inode = FileAttr {
             ino,
             size: 0,
             blocks: 0,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::now(),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
             kind: file_type,
             perm: as_file_perm(mode),
             nlink: 1,
             uid,
             gid,
             rdev,
-            blksize: TiFs::BLOCK_SIZE as u32,
+            blksize: self.block_size as u32,
             padding: 0,
             flags: 0,
         }
         .into();
+        // the number may be reused, the kernel drops what it caches of a different generation
+        inode.generation = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
 
         debug!("made inode ({:?})", &inode);
 
+        if parent >= ROOT_INODE {
+            self.add_entry(parent, name, &inode).await?;
+            // TODO: update attributes of directory
+        }
+
         self.save_inode(&inode).await?;
-        Ok(inode.into())
+        Ok(inode)
+    }
+
+    /// Add an entry of `inode` into directory `parent`.
+    /// This is the only place to write entries, so the type of an entry always follows the kind of its inode.
+    pub async fn add_entry(&mut self, parent: u64, name: ByteString, inode: &Inode) -> Result<()> {
+        self.migrate_dir(parent).await?;
+        let item = DirItem {
+            ino: inode.ino,
+            name: name.to_string(),
+            typ: inode.kind,
+        };
+        self.put(ScopedKey::dir_entry(parent, &name), encode_item(&item)?)
+            .await?;
+        debug!("add entry({:?}) into dir({})", &item, parent);
+        self.update_dir_count(parent, 1).await?;
+        self.touch_dir(parent).await
+    }
+
+    pub async fn remove_entry(&mut self, parent: u64, name: ByteString) -> Result<()> {
+        self.migrate_dir(parent).await?;
+        self.delete(ScopedKey::dir_entry(parent, &name)).await?;
+        self.update_dir_count(parent, -1).await?;
+        self.touch_dir(parent).await
+    }
+
+    /// Number of entries in directory `parent`, counted by scanning if the counter is absent.
+    pub async fn read_dir_count(&self, parent: u64) -> Result<u64> {
+        match self.get(ScopedKey::dir_count(parent)).await? {
+            Some(value) => decode_dir_count(&value),
+            None => self.count_dir_entries(parent).await,
+        }
+    }
+
+    async fn update_dir_count(&mut self, parent: u64, delta: i64) -> Result<()> {
+        let count = (self.read_dir_count(parent).await? as i64 + delta).max(0) as u64;
+        self.put(ScopedKey::dir_count(parent), encode_dir_count(count)?)
+            .await
+    }
+
+    // Count entries of directory `parent` by scanning them.
+    async fn count_dir_entries(&self, parent: u64) -> Result<u64> {
+        if let Some(dir) = self.read_legacy_dir(parent).await? {
+            return Ok(dir.len() as u64);
+        }
+        let range = ScopedKey::dir_entry_range(parent, ScopedKey::FIRST_DIR_COOKIE);
+        let mut start = range.start;
+        let mut count = 0;
+        loop {
+            let keys: Vec<Key> = self
+                .scan(start..range.end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .map(|pair| pair.into_key())
+                .collect();
+            count += keys.len() as u64;
+            match keys.last() {
+                Some(last) if keys.len() == TiFs::SCAN_LIMIT as usize => {
+                    // the smallest key after the last one
+                    let mut next: Vec<u8> = last.clone().into();
+                    next.push(0);
+                    start = next.into();
+                }
+                _ => return Ok(count),
+            }
+        }
+    }
+
+    /// Reset the entry counter of directory `parent` to the number of its entries.
+    pub async fn repair_dir_entry_count(&mut self, parent: u64) -> Result<()> {
+        let count = self.count_dir_entries(parent).await?;
+        self.put(ScopedKey::dir_count(parent), encode_dir_count(count)?)
+            .await
+    }
+
+    async fn touch_dir(&mut self, ino: u64) -> Result<()> {
+        let mut inode = self.read_inode(ino).await?;
+        inode.mtime = SystemTime::now();
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
+    /// Check types of entries in directory `ino` against kinds of their inodes,
+    /// return the mismatched entries with the kinds of inodes. Mismatched entries are fixed if `repair` is set.
+    pub async fn check_entry_types(
+        &mut self,
+        ino: u64,
+        repair: bool,
+    ) -> Result<Vec<(DirItem, FileType)>> {
+        let dir = self.read_dir(ino).await?;
+        let mut mismatched = Vec::new();
+        for item in dir {
+            let kind = self.read_inode(item.ino).await?.kind;
+            if item.typ != kind {
+                mismatched.push((item, kind));
+            }
+        }
+        if repair && !mismatched.is_empty() {
+            self.migrate_dir(ino).await?;
+            for (item, kind) in mismatched.iter() {
+                let fixed = DirItem {
+                    typ: *kind,
+                    ..item.clone()
+                };
+                self.put(ScopedKey::dir_entry(ino, &item.name), encode_item(&fixed)?)
+                    .await?;
+            }
+        }
+        Ok(mismatched)
     }
 
     pub async fn get_index(&self, parent: u64, name: ByteString) -> Result<Option<u64>> {
+        if let Some(value) = self.get(ScopedKey::dir_entry(parent, &name)).await? {
+            return Ok(Some(decode_item(&value)?.ino));
+        }
+        // directories not migrated yet are indexed by separate keys
         let key = ScopedKey::index(parent, &name);
         self.get(key)
             .await
@@ -160,12 +714,6 @@ impl Txn {
             })
     }
 
-    pub async fn set_index(&mut self, parent: u64, name: ByteString, ino: u64) -> Result<()> {
-        let key = ScopedKey::index(parent, &name);
-        let value = Index::new(ino).serialize()?;
-        Ok(self.put(key, value).await?)
-    }
-
     pub async fn remove_index(&mut self, parent: u64, name: ByteString) -> Result<()> {
         let key = ScopedKey::index(parent, &name);
         Ok(self.delete(key).await?)
@@ -176,42 +724,333 @@ impl Txn {
             .get(ScopedKey::inode(ino))
             .await?
             .ok_or_else(|| FsError::InodeNotFound { inode: ino })?;
-        Ok(Inode::deserialize(&value)?)
+        self.open_inode(&value)
+    }
+
+    /// Read inodes of `inos` by a single request, inodes not found are left out.
+    pub async fn read_inodes(&self, inos: &[u64]) -> Result<HashMap<u64, Inode>> {
+        let mut inodes = HashMap::with_capacity(inos.len());
+        for pair in self
+            .batch_get(inos.iter().map(|ino| ScopedKey::inode(*ino)))
+            .await?
+        {
+            let inode = self.open_inode(pair.value())?;
+            inodes.insert(inode.ino, inode);
+        }
+        Ok(inodes)
+    }
+
+    // Deserialize an inode, with its inline data decrypted if this transaction holds the key.
+    fn open_inode(&self, value: &[u8]) -> Result<Inode> {
+        let mut inode = Inode::deserialize(value)?;
+        let ino = inode.ino;
+        self.stored_blocks
+            .lock()
+            .unwrap()
+            .insert(ino, Some(inode.blocks));
+        // without the key the inline data is left sealed, and reading or writing it fails
+        if inode.inline_encrypted {
+            if let Some(key) = self.file_key(ino) {
+                let sealed = inode.inline_data.take().unwrap_or_default();
+                let data = key
+                    .open(INLINE_INDEX, &sealed)
+                    .ok_or(FsError::Undecryptable { ino, block: 0 })?;
+                inode.inline_data = Some(data);
+                inode.inline_encrypted = false;
+            }
+        }
+        Ok(inode)
+    }
+
+    /// Lock the inode for a multi-step modification and read it after the lock is acquired.
+    ///
+    /// In a pessimistic transaction the lock is acquired at once, in an optimistic one
+    /// the commit fails if the inode is modified by others after this transaction begins.
+    pub async fn lock_inode(&mut self, ino: u64) -> Result<InodeLockGuard> {
+        let key = self.prefixed(ScopedKey::inode(ino));
+        self.writable()?.lock_keys(vec![key]).await?;
+        trace!("lock inode({})", ino);
+        Ok(InodeLockGuard {
+            inode: self.read_inode(ino).await?,
+        })
     }
 
     pub async fn save_inode(&mut self, inode: &Inode) -> Result<()> {
         let key = ScopedKey::inode(inode.ino);
+        // the inode may be removed already in this transaction, or not read by it
+        let cached = self
+            .stored_blocks
+            .get_mut()
+            .unwrap()
+            .get(&inode.ino)
+            .copied();
+        let stored = match cached {
+            Some(stored) => stored,
+            None => match self.get(key).await? {
+                Some(value) => Some(Inode::deserialize(&value)?.blocks),
+                None => None,
+            },
+        };
 
         if inode.nlink == 0 && inode.opened_fh == 0 {
-            self.delete(key).await?;
+            if let Some(blocks) = stored {
+                self.delete(key).await?;
+                self.free_inode(inode).await?;
+                self.update_usage(inode.ino, -(blocks as i64), -1).await?;
+                self.stored_blocks
+                    .get_mut()
+                    .unwrap()
+                    .insert(inode.ino, None);
+            }
         } else {
-            self.put(key, inode.serialize()?).await?;
+            self.put(key, self.seal_inode(inode)?).await?;
             debug!("save inode: {:?}", inode);
+            let new_files = if stored.is_none() { 1 } else { 0 };
+            self.update_usage(
+                inode.ino,
+                inode.blocks as i64 - stored.unwrap_or(0) as i64,
+                new_files,
+            )
+            .await?;
+            self.stored_blocks
+                .get_mut()
+                .unwrap()
+                .insert(inode.ino, Some(inode.blocks));
+        }
+        Ok(())
+    }
+
+    // Serialize an inode, with its inline data sealed if this transaction holds the key.
+    fn seal_inode(&self, inode: &Inode) -> Result<Vec<u8>> {
+        match (&inode.inline_data, self.file_key(inode.ino)) {
+            (Some(data), Some(key)) if !inode.inline_encrypted => {
+                let mut sealed = inode.clone();
+                sealed.inline_data = Some(key.seal(INLINE_INDEX, data));
+                sealed.inline_encrypted = true;
+                sealed.serialize()
+            }
+            _ => inode.serialize(),
+        }
+    }
+
+    fn check_inline_sealed(inode: &Inode) -> Result<()> {
+        if inode.inline_encrypted {
+            return Err(FsError::Undecryptable {
+                ino: inode.ino,
+                block: 0,
+            });
         }
         Ok(())
     }
 
     pub async fn remove_inode(&mut self, ino: u64) -> Result<()> {
+        let inode = self.read_inode(ino).await?;
         self.delete(ScopedKey::inode(ino)).await?;
+        self.free_inode(&inode).await?;
+        self.update_usage(ino, -(inode.blocks as i64), -1).await?;
+        self.stored_blocks.get_mut().unwrap().insert(ino, None);
         Ok(())
     }
 
+    // Apply changes of blocks and inodes to the shard of inode `ino`, only touched on changes
+    // to keep conflicts on it rare.
+    async fn update_usage(&mut self, ino: u64, blocks: i64, files: i64) -> Result<()> {
+        if blocks == 0 && files == 0 {
+            return Ok(());
+        }
+        let key = ScopedKey::usage_shard(ino % TiFs::USAGE_SHARDS);
+        let mut delta = match self.get(key).await? {
+            Some(value) => UsageDelta::deserialize(&value)?,
+            None => UsageDelta::default(),
+        };
+        delta.add(UsageDelta { blocks, files });
+        self.put(key, delta.serialize()?).await
+    }
+
+    // Sum of the usage changes of all shards.
+    async fn usage_deltas(&self) -> Result<UsageDelta> {
+        let mut sum = UsageDelta::default();
+        for pair in self
+            .scan(ScopedKey::usage_shard_range(), TiFs::USAGE_SHARDS as u32)
+            .await?
+        {
+            sum.add(UsageDelta::deserialize(pair.value())?);
+        }
+        Ok(sum)
+    }
+
+    /// Usage counters, None for filesystems created before them until `read_usage` counts them.
+    pub async fn stored_usage(&self) -> Result<Option<Usage>> {
+        let mut usage = match self.meta().await?.usage {
+            Some(usage) => usage,
+            None => return Ok(None),
+        };
+        let delta = self.usage_deltas().await?;
+        usage.apply(delta.blocks, delta.files);
+        Ok(Some(usage))
+    }
+
+    /// Read the usage counters and the next inode number.
+    /// Counters of filesystems created before them are initialized by scanning all inodes.
+    pub async fn read_usage(&mut self) -> Result<(Usage, u64)> {
+        let mut meta = self.meta().await?;
+        if let Some(usage) = self.stored_usage().await? {
+            return Ok((usage, meta.inode_next));
+        }
+
+        let next_inode = meta.inode_next;
+        let mut usage = Usage::new();
+        for inode in self
+            .scan(
+                ScopedKey::inode_range(ROOT_INODE..next_inode),
+                (next_inode - ROOT_INODE) as u32,
+            )
+            .await?
+            .map(|pair| Inode::deserialize(pair.value()))
+        {
+            usage.apply(inode?.blocks as i64, 1);
+        }
+        debug!("initialize usage counters: {:?}", usage);
+        // counted again by each statfs until the filesystem is mounted writable
+        if !self.read_only {
+            // the count includes the changes of the shards so far
+            let shards: Vec<Key> = self
+                .scan(ScopedKey::usage_shard_range(), TiFs::USAGE_SHARDS as u32)
+                .await?
+                .map(|pair| pair.into_key())
+                .collect();
+            for key in shards {
+                self.delete(key).await?;
+            }
+            meta.usage = Some(usage);
+            self.save_meta(&meta).await?;
+        }
+        Ok((usage, next_inode))
+    }
+
+    // Delete the blocks left by a removed inode and push its number onto the free-list.
+    //
+    // The free-list is a sorted set of runs `[start, end)`, stored as `end -> start`.
+    // A freed number is merged with the runs right before and after it.
+    async fn free_inode(&mut self, inode: &Inode) -> Result<()> {
+        // blocks of sparse files or left by older versions may lie beyond the size
+        self.delete_blocks(inode.ino, 0..u64::MAX).await?;
+        self.invalidate_blocks(inode.ino, 0..u64::MAX);
+        if inode.kind == FileType::Directory {
+            self.delete(ScopedKey::dir_count(inode.ino)).await?;
+        }
+
+        let mut start = inode.ino;
+        if let Some(value) = self.get(ScopedKey::free_inode(start)).await? {
+            self.delete(ScopedKey::free_inode(start)).await?;
+            start = decode_free_run(&value)?;
+        }
+        let mut end = inode.ino + 1;
+        // the first run ending beyond the freed number is right after it if it starts at `end`
+        if let Some(pair) = self
+            .scan(ScopedKey::free_inode_range(end + 1), 1)
+            .await?
+            .next()
+        {
+            if decode_free_run(pair.value())? == end {
+                end = parse_free_run_end(pair.key())?;
+                self.delete(ScopedKey::free_inode(end)).await?;
+            }
+        }
+        self.put(ScopedKey::free_inode(end), encode_free_run(start)?)
+            .await?;
+        debug!("free ino({})", inode.ino);
+        Ok(())
+    }
+
+    /// Reserve up to `count` inode numbers, from the lowest run of freed numbers if any,
+    /// otherwise `count` numbers from the meta.
+    pub async fn lease_inodes(&mut self, count: u64) -> Result<Range<u64>> {
+        if let Some(range) = self.pop_free_inodes(count).await? {
+            debug!("lease freed inodes [{}, {})", range.start, range.end);
+            return Ok(range);
+        }
+        let range = self
+            .update_meta(|meta| {
+                let start = meta.inode_next;
+                meta.inode_next += count;
+                start..meta.inode_next
+            })
+            .await?;
+        debug!("lease inodes [{}, {})", range.start, range.end);
+        Ok(range)
+    }
+
+    // Take up to `count` numbers from the start of the lowest free run.
+    async fn pop_free_inodes(&mut self, count: u64) -> Result<Option<Range<u64>>> {
+        let pair = match self.scan(ScopedKey::free_inode_range(0), 1).await?.next() {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        let end = parse_free_run_end(pair.key())?;
+        let start = decode_free_run(pair.value())?;
+
+        let taken = start..end.min(start + count);
+        if taken.end < end {
+            self.put(ScopedKey::free_inode(end), encode_free_run(taken.end)?)
+                .await?;
+        } else {
+            self.delete(ScopedKey::free_inode(end)).await?;
+        }
+        Ok(Some(taken))
+    }
+
+    /// Read the meta, None if the filesystem is not initialized yet, which only `init` expects.
     pub async fn read_meta(&self) -> Result<Option<Meta>> {
         let opt_data = self.get(ScopedKey::meta()).await?;
         opt_data.map(|data| Meta::deserialize(&data)).transpose()
     }
 
+    /// Read the meta of an initialized filesystem.
+    pub async fn meta(&self) -> Result<Meta> {
+        self.read_meta().await?.ok_or(FsError::Uninitialized)
+    }
+
+    /// Modify the meta by `f` and save it in this transaction.
+    pub async fn update_meta<F, T>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Meta) -> T,
+    {
+        let mut meta = self.meta().await?;
+        let result = f(&mut meta);
+        self.save_meta(&meta).await?;
+        Ok(result)
+    }
+
+    /// Read the first keys of each scope in parallel, so that the client caches their regions
+    /// and connects to their leaders before the first operations need them.
+    pub async fn warm_up(&self) -> Result<()> {
+        futures::try_join!(
+            self.get(ScopedKey::meta()),
+            self.get(ScopedKey::inode(ROOT_INODE)),
+            self.scan(ScopedKey::inode_range(ROOT_INODE..u64::MAX), 1),
+            self.scan(
+                ScopedKey::dir_entry_range(ROOT_INODE, ScopedKey::FIRST_DIR_COOKIE),
+                1
+            ),
+            self.scan(ScopedKey::block_range(ROOT_INODE, 0..u64::MAX), 1),
+        )?;
+        Ok(())
+    }
+
     pub async fn save_meta(&mut self, meta: &Meta) -> Result<()> {
         self.put(ScopedKey::meta(), meta.serialize()?).await?;
         Ok(())
     }
 
     async fn transfer_inline_data_to_block(&mut self, inode: &mut Inode) -> Result<()> {
-        debug_assert!(inode.size <= TiFs::INLINE_DATA_THRESHOLD);
-        let key = ScopedKey::block(inode.ino, 0);
+        debug_assert!(inode.size <= self.block_size);
+        Self::check_inline_sealed(inode)?;
         let mut data = inode.inline_data.clone().unwrap();
-        data.resize(TiFs::BLOCK_SIZE as usize, 0);
-        self.put(key, data).await?;
+        data.resize(self.block_size as usize, 0);
+        self.write_block(inode.ino, 0, data).await?;
+        self.invalidate_blocks(inode.ino, 0..1);
+        inode.mark_blocks(0..1);
         inode.inline_data = None;
         Ok(())
     }
@@ -222,9 +1061,12 @@ impl Txn {
         start: u64,
         data: &[u8],
     ) -> Result<usize> {
-        debug_assert!(inode.size <= TiFs::INLINE_DATA_THRESHOLD);
         let size = data.len() as u64;
-        debug_assert!(start + size <= TiFs::INLINE_DATA_THRESHOLD);
+        // symlinks are always inlined, the block size fits the longest target
+        debug_assert!(
+            inode.kind == FileType::Symlink || start + size <= self.inline_data_threshold
+        );
+        Self::check_inline_sealed(inode)?;
 
         let size = data.len();
         let start = start as usize;
@@ -240,6 +1082,7 @@ impl Txn {
         inode.ctime = SystemTime::now();
         inode.set_size(inlined.len() as u64);
         inode.inline_data = Some(inlined);
+        inode.data_version += 1;
         self.save_inode(inode).await?;
 
         Ok(size)
@@ -251,7 +1094,8 @@ impl Txn {
         start: u64,
         size: u64,
     ) -> Result<Vec<u8>> {
-        debug_assert!(inode.size <= TiFs::INLINE_DATA_THRESHOLD);
+        debug_assert!(inode.size <= self.block_size);
+        Self::check_inline_sealed(inode)?;
 
         let start = start as usize;
         let size = size as usize;
@@ -265,12 +1109,20 @@ impl Txn {
             data[..to_copy].copy_from_slice(&inlined[start..start + to_copy]);
         }
 
-        inode.atime = SystemTime::now();
-        self.save_inode(inode).await?;
+        self.touch_atime(inode).await?;
 
         Ok(data)
     }
 
+    // Record the access of a read, which read-only and snapshot transactions cannot store.
+    async fn touch_atime(&mut self, inode: &mut Inode) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        inode.atime = SystemTime::now();
+        self.save_inode(inode).await
+    }
+
     pub async fn read_data(
         &mut self,
         ino: u64,
@@ -290,118 +1142,288 @@ impl Txn {
         }
 
         let target = start + size;
-        let start_block = start / TiFs::BLOCK_SIZE;
-        let end_block = (target + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        let start_block = start / self.block_size;
+        let end_block = (target + self.block_size - 1) / self.block_size;
 
+        let cached = self.block_cache.as_ref().and_then(|cache| {
+            cache.get_range(
+                ino,
+                attr.generation,
+                attr.data_version,
+                start_block..end_block,
+            )
+        });
+        let blocks = match cached {
+            Some(blocks) => blocks,
+            None => self.read_blocks(&attr, start_block..end_block).await?,
+        };
+
+        let mut data = blocks.into_iter().enumerate().fold(
+            Vec::with_capacity(
+                ((end_block - start_block) * self.block_size - start % self.block_size) as usize,
+            ),
+            |mut data, (i, value)| {
+                let mut slice = value.as_slice();
+                if i == 0 {
+                    slice = &slice[(start % self.block_size) as usize..]
+                }
+
+                data.extend_from_slice(slice);
+                data
+            },
+        );
+
+        data.resize(size as usize, 0);
+        self.touch_atime(&mut attr).await?;
+        Ok(data)
+    }
+
+    // Read blocks in `range`, holes are filled with empty blocks.
+    // A missing block recorded as stored in the `block_map` fails the read instead.
+    async fn read_blocks(&self, inode: &Inode, range: Range<u64>) -> Result<Vec<Vec<u8>>> {
+        let (ino, block_map) = (inode.ino, inode.block_map.as_ref());
+        self.count_block_read();
         let pairs = self
             .scan(
-                ScopedKey::block_range(ino, start_block..end_block),
-                (end_block - start_block) as u32,
+                ScopedKey::block_range(ino, range.clone()),
+                (range.end - range.start) as u32,
             )
             .await?;
 
-        let mut data = pairs
-            .enumerate()
-            .flat_map(|(i, pair)| {
-                let key = if let Ok(ScopedKey::Block { ino: _, block }) =
-                    ScopedKey::parse(pair.key().into())
-                {
-                    block
-                } else {
-                    unreachable!("the keys from scanning should be always valid block keys")
-                };
-                let value = pair.into_value();
-                (start_block as usize + i..key as usize)
-                    .map(|_| empty_block())
-                    .chain(vec![value])
-            })
-            .enumerate()
-            .fold(
-                Vec::with_capacity(
-                    ((end_block - start_block) * TiFs::BLOCK_SIZE - start % TiFs::BLOCK_SIZE)
-                        as usize,
-                ),
-                |mut data, (i, value)| {
-                    let mut slice = value.as_slice();
-                    if i == 0 {
-                        slice = &slice[(start % TiFs::BLOCK_SIZE) as usize..]
-                    }
+        let block_size = self.block_size;
+        let key = self.file_key(ino);
+        let mut blocks = Vec::with_capacity((range.end - range.start) as usize);
+        for pair in pairs {
+            let block = match ScopedKey::parse(pair.key().into())? {
+                ScopedKey::Block { ino: _, block } => block,
+                _ => unreachable!("the keys from scanning should be always valid block keys"),
+            };
+            check_hole(ino, range.start + blocks.len() as u64..block, block_map)?;
+            blocks.resize_with((block - range.start) as usize, || empty_block(block_size));
+            blocks.push(decode_block(
+                pair.into_value(),
+                block_size,
+                ino,
+                block,
+                key.as_ref(),
+            )?);
+        }
+        check_hole(ino, range.start + blocks.len() as u64..range.end, block_map)?;
+        blocks.resize_with((range.end - range.start) as usize, || {
+            empty_block(block_size)
+        });
 
-                    data.extend_from_slice(slice);
-                    data
-                },
-            );
+        if let Some(cache) = &self.block_cache {
+            for (block, data) in range.zip(blocks.iter()) {
+                cache.put(
+                    ino,
+                    block,
+                    inode.generation,
+                    inode.data_version,
+                    data.clone(),
+                );
+            }
+        }
+        Ok(blocks)
+    }
 
-        data.resize(size as usize, 0);
-        attr.atime = SystemTime::now();
-        self.save_inode(&attr).await?;
-        Ok(data)
+    // Read a block, a hole is read as an empty block.
+    async fn read_block(&self, ino: u64, block: u64) -> Result<Vec<u8>> {
+        self.count_block_read();
+        match self.get(ScopedKey::block(ino, block)).await? {
+            Some(value) => decode_block(
+                value,
+                self.block_size,
+                ino,
+                block,
+                self.file_key(ino).as_ref(),
+            ),
+            None => Ok(empty_block(self.block_size)),
+        }
+    }
+
+    async fn write_block(&mut self, ino: u64, block: u64, data: Vec<u8>) -> Result<()> {
+        debug_assert_eq!(data.len() as u64, self.block_size);
+        let value = encode_block(data, self.compression, block, self.file_key(ino).as_ref());
+        self.put(ScopedKey::block(ino, block), value).await
+    }
+
+    /// Put the stored `value` of a block of inode `src` as the same block of inode `dst`.
+    /// Values encrypted by the key of `src` are encrypted again by the key of `dst`,
+    /// other values are put as they are.
+    pub async fn copy_block(
+        &mut self,
+        src: u64,
+        dst: u64,
+        block: u64,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        if !is_encrypted(&value, self.block_size) {
+            return self.put(ScopedKey::block(dst, block), value).await;
+        }
+        let key = self
+            .file_key(src)
+            .ok_or(FsError::Undecryptable { ino: src, block })?;
+        let data = decode_block(value, self.block_size, src, block, Some(&key))?;
+        self.write_block(dst, block, data).await
+    }
+
+    /// Return the truncation epoch of `ino`,
+    /// or `FsError::Truncated` if it has changed from the `expected` one.
+    pub async fn check_truncate_epoch(&self, ino: u64, expected: Option<u64>) -> Result<u64> {
+        let epoch = self.read_inode(ino).await?.truncate_epoch;
+        match expected {
+            Some(expected) if expected != epoch => Err(FsError::Truncated { ino }),
+            _ => Ok(epoch),
+        }
     }
 
     pub async fn clear_data(&mut self, ino: u64) -> Result<u64> {
         let mut attr = self.read_inode(ino).await?;
-        let end_block = (attr.size + TiFs::BLOCK_SIZE - 1) / TiFs::BLOCK_SIZE;
+        let end_block = (attr.size + self.block_size - 1) / self.block_size;
 
         for block in 0..end_block {
             self.delete(ScopedKey::block(ino, block)).await?;
         }
+        self.invalidate_blocks(ino, 0..end_block);
+        attr.unmark_blocks(0..u64::MAX);
 
         let clear_size = attr.size;
         attr.size = 0;
+        attr.truncate_epoch += 1;
+        attr.data_version += 1;
         attr.atime = SystemTime::now();
         self.save_inode(&attr).await?;
         Ok(clear_size)
     }
 
+    /// Grow `inode` to `new_size` with zeroes: inline data is padded as long as it fits in the
+    /// threshold, otherwise it's moved to the first block, and the rest is left as a hole.
+    /// The size is set by the caller.
+    pub async fn extend_data(&mut self, inode: &mut Inode, new_size: u64) -> Result<()> {
+        Self::check_inline_sealed(inode)?;
+        inode.data_version += 1;
+        match inode.inline_data.as_mut() {
+            Some(inlined) if new_size <= self.inline_data_threshold => {
+                inlined.resize(new_size as usize, 0);
+                Ok(())
+            }
+            Some(_) => self.transfer_inline_data_to_block(inode).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Drop data of `inode` beyond `new_size`: blocks after it are deleted,
+    /// and the tail of the block containing it is zeroed, so growing the file again reads zeroes.
+    pub async fn truncate_data(&mut self, inode: &mut Inode, new_size: u64) -> Result<()> {
+        Self::check_inline_sealed(inode)?;
+        inode.data_version += 1;
+        if let Some(inlined) = inode.inline_data.as_mut() {
+            inlined.truncate(new_size as usize);
+            return Ok(());
+        }
+
+        let boundary = new_size / self.block_size;
+        let first_deleted = (new_size + self.block_size - 1) / self.block_size;
+        // scan to the end, blocks left beyond the size by older versions are deleted as well
+        self.delete_blocks(inode.ino, first_deleted..u64::MAX)
+            .await?;
+        self.invalidate_blocks(inode.ino, boundary..u64::MAX);
+        inode.unmark_blocks(first_deleted..u64::MAX);
+
+        let offset = (new_size % self.block_size) as usize;
+        if offset != 0 {
+            if let Some(value) = self.get(ScopedKey::block(inode.ino, boundary)).await? {
+                let mut data = decode_block(
+                    value,
+                    self.block_size,
+                    inode.ino,
+                    boundary,
+                    self.file_key(inode.ino).as_ref(),
+                )?;
+                data[offset..].iter_mut().for_each(|byte| *byte = 0);
+                self.write_block(inode.ino, boundary, data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete stored blocks of `ino` in the range by scanning them.
+    pub async fn delete_blocks(&mut self, ino: u64, blocks: Range<u64>) -> Result<()> {
+        let range = ScopedKey::block_range(ino, blocks);
+        let mut start = range.start;
+        loop {
+            let keys: Vec<Key> = self
+                .scan(start..range.end.clone(), TiFs::SCAN_LIMIT)
+                .await?
+                .map(|pair| pair.into_key())
+                .collect();
+            for key in keys.iter() {
+                self.delete(key.clone()).await?;
+            }
+            match keys.last() {
+                Some(last) if keys.len() == TiFs::SCAN_LIMIT as usize => {
+                    let mut next: Vec<u8> = last.clone().into();
+                    next.push(0);
+                    start = next.into();
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
     pub async fn write_data(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
         debug!("write data at ({})[{}]", ino, start);
-        let mut inode = self.read_inode(ino).await?;
+        let mut inode = self.lock_inode(ino).await?;
         let size = data.len();
         let target = start + size as u64;
 
-        if inode.inline_data.is_some() && target > TiFs::INLINE_DATA_THRESHOLD {
+        if inode.inline_data.is_some() && target > self.inline_data_threshold {
             self.transfer_inline_data_to_block(&mut inode).await?;
         }
 
-        if (inode.inline_data.is_some() || inode.size == 0) && target <= TiFs::INLINE_DATA_THRESHOLD
+        if (inode.inline_data.is_some() || inode.size == 0) && target <= self.inline_data_threshold
         {
             return self.write_inline_data(&mut inode, start, &data).await;
         }
 
-        let mut block_index = start / TiFs::BLOCK_SIZE;
-        let start_key = ScopedKey::block(ino, block_index);
-        let start_index = (start % TiFs::BLOCK_SIZE) as usize;
+        let mut block_index = start / self.block_size;
+        let start_index = (start % self.block_size) as usize;
 
-        let first_block_size = TiFs::BLOCK_SIZE as usize - start_index;
+        let first_block_size = self.block_size as usize - start_index;
 
         let (first_block, mut rest) = data.split_at(first_block_size.min(data.len()));
 
-        let mut start_value = self.get(start_key).await?.unwrap_or_else(empty_block);
+        let mut start_value = self.read_block(ino, block_index).await?;
 
         start_value[start_index..start_index + first_block.len()].copy_from_slice(first_block);
 
-        self.put(start_key, start_value).await?;
+        self.write_block(ino, block_index, start_value).await?;
 
         while rest.len() != 0 {
             block_index += 1;
-            let key = ScopedKey::block(ino, block_index);
-            let (curent_block, current_rest) =
-                rest.split_at((TiFs::BLOCK_SIZE as usize).min(rest.len()));
-            let mut value = curent_block.to_vec();
-            if value.len() < TiFs::BLOCK_SIZE as usize {
-                let mut last_value = self.get(key).await?.unwrap_or_else(empty_block);
-                last_value[..value.len()].copy_from_slice(&value);
-                value = last_value;
-            }
-            self.put(key, value).await?;
+            let (current_block, current_rest) =
+                rest.split_at((self.block_size as usize).min(rest.len()));
+            // a partial block is merged into the stored one, which is the only copy of it
+            let value = if current_block.len() < self.block_size as usize {
+                let mut value = self.read_block(ino, block_index).await?;
+                value[..current_block.len()].copy_from_slice(current_block);
+                value
+            } else {
+                current_block.to_vec()
+            };
+            self.write_block(ino, block_index, value).await?;
             rest = current_rest;
         }
+        self.invalidate_blocks(ino, start / self.block_size..block_index + 1);
+        inode.mark_blocks(start / self.block_size..block_index + 1);
 
         inode.atime = SystemTime::now();
         inode.mtime = SystemTime::now();
         inode.ctime = SystemTime::now();
         inode.set_size(inode.size.max(target));
-        self.save_inode(&inode.into()).await?;
+        inode.data_version += 1;
+        self.save_inode(&inode).await?;
         trace!("write data: {}", String::from_utf8_lossy(&data));
         Ok(size)
     }
@@ -415,7 +1437,10 @@ impl Txn {
 
     pub async fn read_link(&mut self, ino: u64) -> Result<Vec<u8>> {
         let mut inode = self.read_inode(ino).await?;
-        debug_assert!(inode.file_attr.kind == FileType::Symlink);
+        // readlink(2) fails with EINVAL on other files, whose data may not be inlined
+        if inode.file_attr.kind != FileType::Symlink {
+            return Err(FsError::NotSymlink { ino });
+        }
         let size = inode.size;
         self.read_inline_data(&mut inode, 0, size).await
     }
@@ -428,22 +1453,16 @@ impl Txn {
                 _ => self.unlink(newparent, newname.clone()).await?,
             }
         }
-        self.set_index(newparent, newname.clone(), ino).await?;
-
-        let mut inode = self.read_inode(ino).await?;
-        let mut dir = self.read_dir(newparent).await?;
-
-        dir.push(DirItem {
-            ino,
-            name: newname.to_string(),
-            typ: inode.kind,
-        });
-
-        self.save_dir(newparent, &dir).await?;
+        let mut inode = self.lock_inode(ino).await?;
+        self.add_entry(newparent, newname, &inode).await?;
+        if inode.kind == FileType::Directory {
+            // only by rename, the `..` of the directory points to the new parent
+            self.update_nlink(newparent, 1).await?;
+        }
         inode.nlink += 1;
         inode.ctime = SystemTime::now();
         self.save_inode(&inode).await?;
-        Ok(inode)
+        Ok(inode.clone())
     }
 
     pub async fn unlink(&mut self, parent: u64, name: ByteString) -> Result<()> {
@@ -452,16 +1471,15 @@ impl Txn {
                 file: name.to_string(),
             }),
             Some(ino) => {
-                self.remove_index(parent, name.clone()).await?;
-                let parent_dir = self.read_dir(parent).await?;
-                let new_parent_dir: Directory = parent_dir
-                    .into_iter()
-                    .filter(|item| item.name != &*name)
-                    .collect();
-                self.save_dir(parent, &new_parent_dir).await?;
+                self.remove_entry(parent, name.clone()).await?;
 
-                let mut inode = self.read_inode(ino).await?;
-                inode.nlink -= 1;
+                let mut inode = self.lock_inode(ino).await?;
+                if inode.kind == FileType::Directory {
+                    self.update_nlink(parent, -1).await?;
+                }
+                // the inode and its data are freed by `save_inode` once the last link is
+                // removed, or by `close` of the last handler if it's still opened
+                inode.nlink = inode.nlink.saturating_sub(1);
                 inode.ctime = SystemTime::now();
                 self.save_inode(&inode).await?;
                 Ok(())
@@ -475,26 +1493,30 @@ impl Txn {
                 file: name.to_string(),
             }),
             Some(ino) => {
-                let target_dir = self.read_dir(ino).await?;
-                if target_dir.len() != 0 {
+                if !self.dir_is_empty(ino).await? {
                     let name_str = name.to_string();
                     debug!("dir({}) not empty", &name_str);
                     return Err(FsError::DirNotEmpty { dir: name_str });
                 }
-                self.remove_index(parent, name.clone()).await?;
-                self.remove_inode(ino).await?;
-
-                let parent_dir = self.read_dir(parent).await?;
-                let new_parent_dir: Directory = parent_dir
-                    .into_iter()
-                    .filter(|item| item.name != &*name)
-                    .collect();
-                self.save_dir(parent, &new_parent_dir).await?;
-                Ok(())
+                self.remove_entry(parent, name.clone()).await?;
+                self.update_nlink(parent, -1).await?;
+                self.remove_inode(ino).await
             }
         }
     }
 
+    // Apply a change of subdirectories to the link count of directory `ino`,
+    // which is 2 (its entry and its `.`) plus the `..` of each subdirectory.
+    async fn update_nlink(&mut self, ino: u64, delta: i32) -> Result<()> {
+        if ino < ROOT_INODE {
+            return Ok(());
+        }
+        let mut inode = self.lock_inode(ino).await?;
+        inode.nlink = (inode.nlink as i64 + delta as i64).max(1) as u32;
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
     pub async fn lookup(&self, parent: u64, name: ByteString) -> Result<u64> {
         self.get_index(parent, name.clone())
             .await?
@@ -503,6 +1525,18 @@ impl Txn {
             })
     }
 
+    /// Resolve an absolute path into an inode number, without following symlinks.
+    pub async fn resolve(&self, path: &str) -> Result<u64> {
+        let mut ino = ROOT_INODE;
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            ino = self.lookup(ino, name.to_string().into()).await?;
+        }
+        Ok(ino)
+    }
+
     pub async fn fallocate(&mut self, inode: &mut Inode, offset: i64, length: i64) -> Result<()> {
         let target_size = (offset + length) as u64;
         if target_size <= inode.size {
@@ -510,7 +1544,7 @@ impl Txn {
         }
 
         if inode.inline_data.is_some() {
-            if target_size <= TiFs::INLINE_DATA_THRESHOLD {
+            if target_size <= self.inline_data_threshold {
                 let original_size = inode.size;
                 let data = vec![0; (target_size - original_size) as usize];
                 self.write_inline_data(inode, original_size, &data).await?;
@@ -520,8 +1554,13 @@ impl Txn {
             }
         }
 
+        self.invalidate_blocks(
+            inode.ino,
+            inode.size / self.block_size..(target_size + self.block_size - 1) / self.block_size,
+        );
         inode.set_size(target_size);
         inode.mtime = SystemTime::now();
+        inode.data_version += 1;
         self.save_inode(inode).await?;
         Ok(())
     }
@@ -537,11 +1576,89 @@ impl Txn {
         let dir_mode = make_mode(FileType::Directory, mode as _);
         let mut inode = self.make_inode(parent, name, dir_mode, gid, uid, 0).await?;
         inode.perm = mode as _;
+        inode.nlink = 2;
         self.save_inode(&inode).await?;
-        self.save_dir(inode.ino, &Directory::new()).await
+        self.update_nlink(parent, 1).await?;
+        Ok(inode)
     }
 
+    /// Read all entries of directory `ino`.
     pub async fn read_dir(&mut self, ino: u64) -> Result<Directory> {
+        let entries = self.read_dir_entries(ino, usize::MAX).await?;
+        Ok(entries
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect())
+    }
+
+    /// Read all entries of directory `ino` with their cookies, or None if there are more than `limit`.
+    pub async fn read_dir_entries(
+        &mut self,
+        ino: u64,
+        limit: usize,
+    ) -> Result<Option<Vec<(u64, DirItem)>>> {
+        let mut entries = Vec::new();
+        let mut cookie = 0;
+        loop {
+            let page = self.read_dir_page(ino, cookie, TiFs::SCAN_LIMIT).await?;
+            let last_page = page.len() < TiFs::SCAN_LIMIT as usize;
+            if let Some((last, _)) = page.last() {
+                cookie = *last;
+            }
+            entries.extend(page);
+            if entries.len() > limit {
+                return Ok(None);
+            }
+            if last_page {
+                return Ok(Some(entries));
+            }
+        }
+    }
+
+    /// Read at most `limit` entries of directory `ino` after the `cookie`, with their cookies.
+    pub async fn read_dir_page(
+        &mut self,
+        ino: u64,
+        cookie: u64,
+        limit: u32,
+    ) -> Result<Vec<(u64, DirItem)>> {
+        if let Some(dir) = self.read_legacy_dir(ino).await? {
+            // list in the same order as migrated directories, so a listing survives the migration
+            let mut items: Vec<_> = dir
+                .into_iter()
+                .map(|item| (ScopedKey::dir_cookie(&item.name), item))
+                .filter(|(item_cookie, _)| *item_cookie > cookie)
+                .collect();
+            items.sort_by(|(lc, litem), (rc, ritem)| (lc, &litem.name).cmp(&(rc, &ritem.name)));
+            items.truncate(limit as usize);
+            return Ok(items);
+        }
+
+        let start = cookie.max(ScopedKey::FIRST_DIR_COOKIE - 1) + 1;
+        self.scan(ScopedKey::dir_entry_range(ino, start), limit)
+            .await?
+            .map(|pair| {
+                let item = decode_item(pair.value())?;
+                Ok((ScopedKey::dir_cookie(&item.name), item))
+            })
+            .collect()
+    }
+
+    pub async fn dir_is_empty(&mut self, ino: u64) -> Result<bool> {
+        if let Some(dir) = self.read_legacy_dir(ino).await? {
+            return Ok(dir.is_empty());
+        }
+        let range = ScopedKey::dir_entry_range(ino, ScopedKey::FIRST_DIR_COOKIE);
+        Ok(self.scan(range, 1).await?.next().is_none())
+    }
+
+    // Read entries of a directory in the layout before version 2, return None if it's migrated.
+    async fn read_legacy_dir(&self, ino: u64) -> Result<Option<Directory>> {
+        let inode = self.read_inode(ino).await?;
+        if inode.kind != FileType::Directory || inode.keyed_entries {
+            return Ok(None);
+        }
         let data =
             self.get(ScopedKey::block(ino, 0))
                 .await?
@@ -550,32 +1667,127 @@ impl Txn {
                     block: 0,
                 })?;
         trace!("read data: {}", String::from_utf8_lossy(&data));
-        super::dir::decode(&data)
+        self.legacy_dirs.lock().unwrap().push((ino, data.len()));
+        Ok(Some(super::dir::decode(&data)?))
     }
 
-    pub async fn save_dir(&mut self, ino: u64, dir: &Directory) -> Result<Inode> {
-        let data = super::dir::encode(dir)?;
+    /// Move entries of a directory stored as a single value into separate keys,
+    /// directories are migrated once they are modified.
+    pub async fn migrate_dir(&mut self, ino: u64) -> Result<()> {
+        let dir = match self.read_legacy_dir(ino).await? {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        debug!("migrate dir({}) with {} entries", ino, dir.len());
+        for item in dir.iter() {
+            self.remove_index(ino, item.name.clone().into()).await?;
+            self.put(ScopedKey::dir_entry(ino, &item.name), encode_item(item)?)
+                .await?;
+        }
+        self.put(
+            ScopedKey::dir_count(ino),
+            encode_dir_count(dir.len() as u64)?,
+        )
+        .await?;
+        self.delete(ScopedKey::block(ino, 0)).await?;
+        self.invalidate_blocks(ino, 0..1);
+
         let mut inode = self.read_inode(ino).await?;
-        inode.set_size(data.len() as u64);
-        inode.atime = SystemTime::now();
-        inode.mtime = SystemTime::now();
-        inode.ctime = SystemTime::now();
-        self.save_inode(&inode).await?;
-        self.put(ScopedKey::block(ino, 0), data).await?;
-        Ok(inode)
+        inode.keyed_entries = true;
+        inode.set_size(0);
+        self.save_inode(&inode).await
+    }
+}
+
+fn parse_free_run_end(key: &Key) -> Result<u64> {
+    match ScopedKey::parse(key.into())? {
+        ScopedKey::FreeInode(end) => Ok(end),
+        _ => unreachable!("the keys from scanning should be always valid free inode keys"),
+    }
+}
+
+fn encode_free_run(start: u64) -> Result<Vec<u8>> {
+    serialize(&start).map_err(|err| FsError::Serialize {
+        target: "free inodes",
+        typ: ENCODING,
+        msg: err.to_string(),
+    })
+}
+
+fn decode_free_run(bytes: &[u8]) -> Result<u64> {
+    deserialize(bytes).map_err(|err| FsError::Serialize {
+        target: "free inodes",
+        typ: ENCODING,
+        msg: err.to_string(),
+    })
+}
+
+// Fail if any block of `holes` is recorded as stored in the `block_map`.
+fn check_hole(ino: u64, holes: Range<u64>, block_map: Option<&BlockMap>) -> Result<()> {
+    let map = match block_map {
+        Some(map) => map,
+        None => return Ok(()),
+    };
+    match holes.clone().find(|block| map.contains(*block)) {
+        Some(block) => {
+            error!("block({}) of inode({}) is stored but missing", block, ino);
+            Err(FsError::Corruption(format!(
+                "block({}) of inode({}) is missing",
+                block, ino
+            )))
+        }
+        None => Ok(()),
     }
 }
 
-impl Deref for Txn {
-    type Target = Transaction;
+// Index of the block in a pair scanned from a block range.
+fn parse_block(pair: &KvPair) -> Result<u64> {
+    match ScopedKey::parse(pair.key().into())? {
+        ScopedKey::Block { ino: _, block } => Ok(block),
+        _ => unreachable!("the keys from scanning should be always valid block keys"),
+    }
+}
+
+fn encode_dir_count(count: u64) -> Result<Vec<u8>> {
+    serialize(&count).map_err(|err| FsError::Serialize {
+        target: "dir count",
+        typ: ENCODING,
+        msg: err.to_string(),
+    })
+}
+
+pub fn decode_dir_count(bytes: &[u8]) -> Result<u64> {
+    deserialize(bytes).map_err(|err| FsError::Serialize {
+        target: "dir count",
+        typ: ENCODING,
+        msg: err.to_string(),
+    })
+}
+
+/// Inode locked by `Txn::lock_inode`.
+///
+/// The lock in TiKV is held until the transaction commits or rolls back,
+/// dropping the guard only ends the modification in this transaction.
+pub struct InodeLockGuard {
+    inode: Inode,
+}
+
+impl Deref for InodeLockGuard {
+    type Target = Inode;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inode
     }
 }
 
-impl DerefMut for Txn {
+impl DerefMut for InodeLockGuard {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.inode
+    }
+}
+
+impl Drop for InodeLockGuard {
+    fn drop(&mut self) {
+        trace!("release lock guard of inode({})", self.inode.ino);
     }
 }
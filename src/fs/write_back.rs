@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use async_std::sync::Mutex as AsyncMutex;
+
+/// Data written through a file handler and not flushed to TiKV yet, a single contiguous range.
+#[derive(Debug, Default)]
+pub struct WriteBuffer {
+    /// Opened with `O_APPEND`, the data is appended to the end of the file when it's flushed.
+    pub append_mode: bool,
+    pub start: u64,
+    pub data: Vec<u8>,
+}
+
+impl WriteBuffer {
+    pub fn new(append_mode: bool) -> Self {
+        Self {
+            append_mode,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Whether the buffered data may overlap `start..end`, the offset of appended data
+    /// is unknown until it's flushed.
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        !self.is_empty() && (self.append_mode || (start < self.end() && self.start < end))
+    }
+
+    /// Merge a write into the buffer, return false if it's not contiguous with the buffered range.
+    pub fn merge(&mut self, offset: u64, data: &[u8]) -> bool {
+        if self.is_empty() {
+            self.start = offset;
+        } else if !self.append_mode && (offset < self.start || offset > self.end()) {
+            return false;
+        }
+        let at = if self.append_mode {
+            self.data.len()
+        } else {
+            (offset - self.start) as usize
+        };
+        let overlap = (self.data.len() - at).min(data.len());
+        self.data[at..at + overlap].copy_from_slice(&data[..overlap]);
+        self.data.extend_from_slice(&data[overlap..]);
+        true
+    }
+
+    /// Take the buffered data to flush it, leaving the buffer empty.
+    pub fn take(&mut self) -> (u64, Vec<u8>) {
+        (self.start, mem::take(&mut self.data))
+    }
+}
+
+/// Buffers of the handlers of an inode opened by this mount.
+#[derive(Debug, Default)]
+pub struct InodeBuffers {
+    pub handles: HashMap<u64, WriteBuffer>,
+}
+
+impl InodeBuffers {
+    /// Size of the file with the buffered data, given the size stored in TiKV.
+    pub fn size(&self, stored: u64) -> u64 {
+        let (appended, written) = self
+            .handles
+            .values()
+            .partition::<Vec<_>, _>(|buffer| buffer.append_mode);
+        let size = written
+            .into_iter()
+            .filter(|buffer| !buffer.is_empty())
+            .map(WriteBuffer::end)
+            .fold(stored, u64::max);
+        size + appended
+            .into_iter()
+            .map(|buffer| buffer.data.len() as u64)
+            .sum::<u64>()
+    }
+}
+
+/// Writes buffered by file handlers of a mount with `write_back`, by inode.
+///
+/// The buffers of an inode are locked while a write is merged into them or while they are
+/// flushed, so that the data of an inode is committed in the order it's written.
+#[derive(Debug, Default)]
+pub struct WriteBack {
+    inodes: Mutex<HashMap<u64, Arc<AsyncMutex<InodeBuffers>>>>,
+}
+
+impl WriteBack {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, ino: u64) -> Option<Arc<AsyncMutex<InodeBuffers>>> {
+        self.inodes.lock().unwrap().get(&ino).cloned()
+    }
+
+    pub async fn open(&self, ino: u64, fh: u64, append_mode: bool) {
+        let buffers = self.inodes.lock().unwrap().entry(ino).or_default().clone();
+        buffers
+            .lock()
+            .await
+            .handles
+            .insert(fh, WriteBuffer::new(append_mode));
+    }
+
+    /// Drop the buffers of an inode once none of its handlers is open and nobody holds them,
+    /// others get them from the map, so they cannot be acquired in the meantime.
+    pub fn remove_idle(&self, ino: u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+        let idle = inodes.get(&ino).map_or(false, |buffers| {
+            Arc::strong_count(buffers) == 1
+                && buffers
+                    .try_lock()
+                    .map_or(false, |buffers| buffers.handles.is_empty())
+        });
+        if idle {
+            inodes.remove(&ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InodeBuffers, WriteBuffer};
+
+    #[test]
+    fn merges_contiguous_writes() {
+        let mut buffer = WriteBuffer::new(false);
+        assert!(buffer.merge(10, b"hello"));
+        assert!(buffer.merge(15, b" world"));
+        assert!(buffer.merge(10, b"J"));
+        assert!(buffer.merge(20, b"d!!"));
+        assert_eq!(buffer.take(), (10, b"Jello world!!"[..].to_vec()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_writes_out_of_the_range() {
+        let mut buffer = WriteBuffer::new(false);
+        assert!(buffer.merge(10, b"hello"));
+        assert!(!buffer.merge(16, b"gap"));
+        assert!(!buffer.merge(9, b"before"));
+        assert_eq!(buffer.data, b"hello");
+        assert!(buffer.overlaps(14, 20));
+        assert!(!buffer.overlaps(15, 20));
+    }
+
+    #[test]
+    fn appends_at_any_offset() {
+        let mut buffer = WriteBuffer::new(true);
+        assert!(buffer.merge(100, b"a"));
+        assert!(buffer.merge(0, b"b"));
+        assert_eq!(buffer.data, b"ab");
+        assert!(buffer.overlaps(0, 1));
+    }
+
+    #[test]
+    fn sizes_files_with_buffered_data() {
+        let mut buffers = InodeBuffers::default();
+        let mut written = WriteBuffer::new(false);
+        written.merge(100, &[0; 10]);
+        let mut appended = WriteBuffer::new(true);
+        appended.merge(0, &[0; 5]);
+        buffers.handles.insert(1, written);
+        buffers.handles.insert(2, appended);
+        assert_eq!(buffers.size(50), 115);
+        assert_eq!(buffers.size(200), 205);
+    }
+}
@@ -4,24 +4,128 @@
 
 pub mod fs;
 
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
 use fs::async_fs::AsyncFs;
+use fs::check::CheckReport;
+use fs::compression::Compression;
+use fs::encryption::EncryptionKey;
+use fs::error::FsError;
+use fs::id_map::IdMap;
+use fs::key::ScopedKey;
+use fs::retry::RetryPolicy;
+use fs::runtime::Consistency;
 use fs::tikv_fs::TiFs;
+use fs::transaction::Txn;
 
 use fuser::MountOption as FuseMountOption;
 use paste::paste;
+use tikv_client::{Config, TransactionClient};
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Value of a mount option in the form of `key=value`.
+pub trait OptionValue: Sized {
+    fn parse_value(value: &str) -> Option<Self>;
+    fn format_value(&self) -> String;
+}
+
+/// Durations are written in milliseconds, an `ms` or `s` suffix is also accepted.
+impl OptionValue for Duration {
+    fn parse_value(value: &str) -> Option<Self> {
+        if let Some(millis) = value.strip_suffix("ms") {
+            millis.parse().ok().map(Duration::from_millis)
+        } else if let Some(secs) = value.strip_suffix("s") {
+            secs.parse().ok().map(Duration::from_secs)
+        } else {
+            value.parse().ok().map(Duration::from_millis)
+        }
+    }
+
+    fn format_value(&self) -> String {
+        format!("{}ms", self.as_millis())
+    }
+}
+
+/// Sizes are written in bytes, a `K`, `M` or `G` suffix is also accepted.
+macro_rules! impl_size_value {
+    ($($type: ty),*) => {
+        $(
+            impl OptionValue for $type {
+                fn parse_value(value: &str) -> Option<Self> {
+                    let (number, shift) = match value.chars().last()? {
+                        'K' | 'k' => (&value[..value.len() - 1], 10),
+                        'M' | 'm' => (&value[..value.len() - 1], 20),
+                        'G' | 'g' => (&value[..value.len() - 1], 30),
+                        _ => (value, 0),
+                    };
+                    number
+                        .parse::<$type>()
+                        .ok()?
+                        .checked_mul(1 << shift)
+                }
+
+                fn format_value(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+impl_size_value!(usize, u64, u32);
+
+impl OptionValue for String {
+    fn parse_value(value: &str) -> Option<Self> {
+        Some(value.to_owned())
+    }
+
+    fn format_value(&self) -> String {
+        self.clone()
+    }
+}
+
+impl OptionValue for SocketAddr {
+    fn parse_value(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+
+    fn format_value(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl OptionValue for PathBuf {
+    fn parse_value(value: &str) -> Option<Self> {
+        Some(value.into())
+    }
+
+    fn format_value(&self) -> String {
+        self.to_string_lossy().into_owned()
+    }
+}
 
 macro_rules! define_options {
-    { $name: ident, [ $($newopt: ident),* $(,)? ], [ $($opt: ident),* $(,)? ] } =>
+    { $name: ident, [ $($newopt: ident),* $(,)? ], [ $($valopt: ident ($valtype: ty)),* $(,)? ], [ $($opt: ident),* $(,)? ] } =>
     {
-        define_options!{ $name(FuseMountOption), [ $($newopt,)* ], [ $($opt,)* ]}
+        define_options!{ $name(FuseMountOption), [ $($newopt,)* ], [ $($valopt($valtype),)* ], [ $($opt,)* ]}
     };
-    { $name: ident ($type: ident), [ $($newopt: ident),* $(,)? ], [ $($opt: ident),* $(,)? ] } =>
+    { $name: ident ($type: ident), [ $($newopt: ident),* $(,)? ], [ $($valopt: ident ($valtype: ty)),* $(,)? ], [ $($opt: ident),* $(,)? ] } =>
     {
         #[derive(Debug,Clone)]
         pub enum $name {
             Unknown(String),
             $($opt,)*
             $($newopt,)*
+            $($valopt($valtype),)*
         }
         impl $name {
             pub fn to_vec<'a, I: Iterator<Item=&'a str>>(iter: I) -> Vec<Self> {
@@ -43,27 +147,34 @@ macro_rules! define_options {
                 T: ToString
             {
                 fn from(v: T) -> Self {
-                    match &v.to_string() as &str {
-                        $(stringify!([<$opt:lower>]) => Self::$opt,)*
-                        $(stringify!([<$newopt:snake>]) => Self::$newopt,)*
-                        k => Self::Unknown(k.to_owned()),
+                    let v = v.to_string();
+                    let mut pair = v.splitn(2, '=');
+                    match (pair.next().unwrap_or_default(), pair.next()) {
+                        $((stringify!([<$opt:lower>]), None) => Self::$opt,)*
+                        $((stringify!([<$newopt:snake>]), None) => Self::$newopt,)*
+                        $((stringify!([<$valopt:snake>]), Some(value)) => match OptionValue::parse_value(value) {
+                            Some(value) => Self::$valopt(value),
+                            None => Self::Unknown(v.clone()),
+                        },)*
+                        _ => Self::Unknown(v.clone()),
                     }
                 }
             }
             impl From<&$name> for String {
                 fn from(v: &$name) -> Self {
                     match v {
-                        $($name::$opt => stringify!([<$opt:lower>]),)*
-                        $($name::$newopt => stringify!([<$newopt:snake>]),)*
-                        $name::Unknown(v) => v,
-                    }.to_owned()
+                        $($name::$opt => stringify!([<$opt:lower>]).to_owned(),)*
+                        $($name::$newopt => stringify!([<$newopt:snake>]).to_owned(),)*
+                        $($name::$valopt(value) => format!("{}={}", stringify!([<$valopt:snake>]), value.format_value()),)*
+                        $name::Unknown(v) => v.to_owned(),
+                    }
                 }
             }
         }
     };
 }
 
-define_options! { MountOption, [DirectIO], [
+define_options! { MountOption, [DirectIO, Pessimistic, PretendLegacy, PortableNames, SkipWarmUp, PageCache, ReadOnly, WriteBack], [LockTimeout(Duration), HandleIdleTimeout(Duration), WarmCache(PathBuf), Name(String), BlockCache(usize), DirCache(usize), InodeCache(usize), InlineThreshold(u64), ConfigFile(PathBuf), OtlpEndpoint(String), RetryPolicy(RetryPolicy), MinFreeBytes(u64), BlkSize(u64), Compression(Compression), MaxWriteBytesPerSecondPerPid(u64), Encryption(EncryptionKey), TlsCa(PathBuf), TlsCert(PathBuf), TlsKey(PathBuf), GrpcTimeout(Duration), Consistency(Consistency), AttrTtl(Duration), SnapshotTs(u64), MetricsAddr(SocketAddr), RetryDeadline(Duration), SquashUid(u32), SquashGid(u32), UidMap(IdMap), GidMap(IdMap)], [
     Dev,
     NoDev,
     Suid,
@@ -75,6 +186,83 @@ define_options! { MountOption, [DirectIO], [
     DirSync,
 ]}
 
+/// Guard of tracing, keep it alive until exit so that all spans are exported.
+#[derive(Default)]
+pub struct TracingGuard {
+    #[cfg(feature = "otlp")]
+    _uninstall: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Initialize logging, and exporting spans to the OpenTelemetry collector given by `otlp_endpoint`.
+pub fn init_tracing(options: &[MountOption]) -> anyhow::Result<TracingGuard> {
+    let subscriber = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer());
+
+    let endpoint = options.iter().find_map(|option| match option {
+        MountOption::OtlpEndpoint(endpoint) => Some(endpoint.clone()),
+        _ => None,
+    });
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = endpoint {
+        let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .install()
+            .map_err(|err| anyhow!("{}", err))?;
+        subscriber
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()
+            .map_err(|err| anyhow!("{}", err))?;
+        return Ok(TracingGuard {
+            _uninstall: Some(uninstall),
+        });
+    }
+
+    subscriber.try_init().map_err(|err| anyhow!("{}", err))?;
+
+    #[cfg(not(feature = "otlp"))]
+    if let Some(endpoint) = endpoint {
+        tracing::warn!(
+            "ignore otlp_endpoint({}), tifs is built without the `otlp` feature",
+            endpoint
+        );
+    }
+    Ok(TracingGuard::default())
+}
+
+/// Build the config of the tikv client from mount options,
+/// fail if TLS files are missing or unreadable instead of timing out on connecting.
+pub fn client_config(options: &[MountOption]) -> anyhow::Result<Config> {
+    let mut config = Config::default();
+    let (mut ca, mut cert, mut key) = (None, None, None);
+    for option in options {
+        match option {
+            MountOption::TlsCa(path) => ca = Some(path),
+            MountOption::TlsCert(path) => cert = Some(path),
+            MountOption::TlsKey(path) => key = Some(path),
+            MountOption::GrpcTimeout(timeout) => config = config.with_timeout(*timeout),
+            _ => (),
+        }
+    }
+    match (ca, cert, key) {
+        (None, None, None) => (),
+        (Some(ca), Some(cert), Some(key)) => {
+            for (option, path) in [("tls_ca", ca), ("tls_cert", cert), ("tls_key", key)].iter() {
+                std::fs::read(path)
+                    .map_err(|err| anyhow!("cannot read {}({:?}): {}", option, path, err))?;
+            }
+            config = config.with_security(ca.clone(), cert.clone(), key.clone());
+        }
+        _ => {
+            return Err(anyhow!(
+                "tls_ca, tls_cert and tls_key should be given together"
+            ))
+        }
+    }
+    Ok(config)
+}
+
 pub async fn mount_tifs_daemonize<F>(
     mountpoint: String,
     endpoints: Vec<&str>,
@@ -94,16 +282,144 @@ where
     fuse_options.push(FuseMountOption::AutoUnmount);
 
     fuse_options.extend(MountOption::to_builtin(options.iter()));
+    if options
+        .iter()
+        .any(|option| matches!(option, MountOption::ReadOnly | MountOption::SnapshotTs(_)))
+    {
+        fuse_options.push(FuseMountOption::RO);
+    }
 
-    let fs_impl = TiFs::construct(endpoints, Default::default(), options).await?;
+    let metrics_addr = options.iter().find_map(|option| match option {
+        MountOption::MetricsAddr(addr) => Some(*addr),
+        _ => None,
+    });
+    let config = client_config(&options)?;
+    let fs_impl = AsyncFs::from(TiFs::construct(endpoints, config, options).await?);
 
     make_daemon()?;
 
-    fuser::mount2(AsyncFs::from(fs_impl), mountpoint, &fuse_options)?;
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = metrics_addr {
+        let registry = fs_impl.inner().metrics_handle();
+        async_std::task::spawn(async move {
+            if let Err(err) = fs::metrics::serve(registry, addr).await {
+                error!("fail to serve metrics at {}: {}", addr, err);
+            }
+        });
+    }
+    #[cfg(not(feature = "metrics"))]
+    if let Some(addr) = metrics_addr {
+        warn!(
+            "ignore metrics_addr({}), tifs is built without the `metrics` feature",
+            addr
+        );
+    }
+
+    if fs_impl.inner().config_file.is_some() {
+        async_std::task::spawn(reload_on_sighup(fs_impl.inner()));
+    }
+    async_std::task::spawn(migrate_large_dirs(fs_impl.inner()));
+    async_std::task::spawn(reap_idle_handles(fs_impl.inner()));
+    async_std::task::spawn(unmount_on_sigterm(mountpoint.clone()));
+
+    fuser::mount2(fs_impl, mountpoint, &fuse_options)?;
 
     Ok(())
 }
 
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Reload the config file of `fs` on every SIGHUP.
+async fn reload_on_sighup(fs: Arc<TiFs>) {
+    unsafe {
+        libc::signal(libc::SIGHUP, request_reload as libc::sighandler_t);
+    }
+    loop {
+        async_std::task::sleep(Duration::from_secs(1)).await;
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            if let Err(err) = fs.reload_config_file().await {
+                error!("fail to reload config file: {}", err);
+            }
+        }
+    }
+}
+
+static UNMOUNT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_unmount(_: libc::c_int) {
+    UNMOUNT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Unmount `mountpoint` on SIGTERM, so that the session ends and open handlers are released
+/// by `destroy` instead of being left in TiKV.
+///
+/// A busy mountpoint is detached lazily, the session ends once the files open in it are closed.
+/// If neither works the process exits, as SIGTERM would do by default.
+async fn unmount_on_sigterm(mountpoint: String) {
+    unsafe {
+        libc::signal(libc::SIGTERM, request_unmount as libc::sighandler_t);
+    }
+    loop {
+        async_std::task::sleep(Duration::from_secs(1)).await;
+        if UNMOUNT_REQUESTED.swap(false, Ordering::SeqCst) {
+            info!("unmount {} on SIGTERM", mountpoint);
+            if let Err(err) = unmount(&mountpoint, false) {
+                warn!("fail to unmount, detach it lazily: {}", err);
+                if let Err(err) = unmount(&mountpoint, true) {
+                    error!("fail to unmount lazily, exit: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+// Mounts of unprivileged users can only be unmounted by the setuid helper of fuse.
+fn unmount(mountpoint: &str, lazy: bool) -> anyhow::Result<()> {
+    let commands: &[&[&str]] = match (cfg!(target_os = "linux"), lazy) {
+        (true, false) => &[&["fusermount", "-u"], &["fusermount3", "-u"], &["umount"]],
+        (true, true) => &[
+            &["fusermount", "-u", "-z"],
+            &["fusermount3", "-u", "-z"],
+            &["umount", "-l"],
+        ],
+        (false, false) => &[&["umount"]],
+        (false, true) => &[&["umount", "-f"]],
+    };
+    for command in commands {
+        match Command::new(command[0])
+            .args(&command[1..])
+            .arg(mountpoint)
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => warn!("{} exits with {}", command.join(" "), status),
+            Err(err) => warn!("fail to run {}: {}", command[0], err),
+        }
+    }
+    Err(anyhow!("cannot unmount {}", mountpoint))
+}
+
+/// Migrate large directories in the layout before version 2 found by `fs` every second.
+async fn migrate_large_dirs(fs: Arc<TiFs>) {
+    loop {
+        async_std::task::sleep(Duration::from_secs(1)).await;
+        fs.migrate_large_dirs().await;
+    }
+}
+
+/// Reap file handlers of `fs` idle for longer than `handle_idle_timeout` every second.
+async fn reap_idle_handles(fs: Arc<TiFs>) {
+    loop {
+        async_std::task::sleep(Duration::from_secs(1)).await;
+        fs.reap_idle_handles().await;
+    }
+}
+
 pub async fn mount_tifs(
     mountpoint: String,
     endpoints: Vec<&str>,
@@ -111,3 +427,56 @@ pub async fn mount_tifs(
 ) -> anyhow::Result<()> {
     mount_tifs_daemonize(mountpoint, endpoints, options, || Ok(())).await
 }
+
+/// Check consistency of the filesystem given by mount `options` without mounting it,
+/// the inconsistencies found are repaired if `repair` is set.
+pub async fn check_tifs(
+    endpoints: Vec<&str>,
+    options: Vec<MountOption>,
+    repair: bool,
+    force: bool,
+) -> anyhow::Result<CheckReport> {
+    let config = client_config(&options)?;
+    let fs = TiFs::construct(endpoints, config, options).await?;
+    Ok(fs.check(repair, force).await?)
+}
+
+/// Delete all keys of the filesystem named `name`, connecting by the TLS and timeout settings
+/// of mount `options`.
+///
+/// It fails with `FsError::Mounted` while mounts are counted in the meta, unless `force` tells
+/// they crashed.
+pub async fn destroy_tifs(
+    endpoints: Vec<&str>,
+    options: &[MountOption],
+    name: &str,
+    force: bool,
+) -> anyhow::Result<()> {
+    let client = TransactionClient::new_with_config(endpoints, client_config(options)?)
+        .await
+        .map_err(|err| anyhow!("{}", err))?;
+    let prefix = ScopedKey::namespace(name);
+    let mut total = 0;
+    loop {
+        let mut txn = Txn::begin_optimistic(&client, prefix.clone()).await?;
+        // the meta is deleted by the first batch
+        match txn.read_meta().await? {
+            Some(meta) if !force && meta.live_mounts > 0 => {
+                txn.rollback().await?;
+                return Err(FsError::Mounted {
+                    mounts: meta.live_mounts,
+                }
+                .into());
+            }
+            _ => (),
+        }
+        let deleted = txn.clear_namespace(TiFs::SCAN_LIMIT).await?;
+        txn.commit().await.map_err(FsError::from)?;
+        total += deleted;
+        if deleted == 0 {
+            break;
+        }
+    }
+    info!("destroy filesystem({}), {} keys deleted", name, total);
+    Ok(())
+}
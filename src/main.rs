@@ -1,14 +1,18 @@
-use clap::{crate_version, App, Arg};
-use tracing_subscriber::EnvFilter;
+use anyhow::anyhow;
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use tikv_client::TransactionClient;
 
-use tifs::mount_tifs;
+use tifs::fs::copy::{copy_tree, CopyOptions};
+use tifs::fs::key::ScopedKey;
 use tifs::MountOption;
+use tifs::{client_config, destroy_tifs, init_tracing, mount_tifs};
 
 #[async_std::main]
 async fn main() {
     let matches = App::new("TiFS")
         .version(crate_version!())
         .author("Hexi Lee")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("pd")
                 .long("pd-endpoints")
@@ -29,16 +33,71 @@ async fn main() {
         .arg(
             Arg::with_name("options")
                 .value_name("OPTION")
+                .short("o")
                 .long("option")
                 .multiple(true)
                 .help("filesystem mount options"),
         )
+        .subcommand(
+            SubCommand::with_name("cp-r")
+                .about("copy a directory tree within the filesystem without mounting it")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("SOURCE")
+                        .required(true)
+                        .help("absolute path of the source tree")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("DESTINATION")
+                        .required(true)
+                        .help("absolute path of the destination tree")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("bwlimit")
+                        .long("bwlimit")
+                        .value_name("MB/s")
+                        .help("limit the copy bandwidth in MB/s")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("replace files that already exist at the destination"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .value_name("NAME")
+                        .help("name of the filesystem")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("destroy")
+                .about("delete all data of a named filesystem")
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .value_name("NAME")
+                        .required(true)
+                        .help("name of the filesystem to destroy")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("destroy the filesystem even if mounts of it are counted"),
+                ),
+        )
         .get_matches();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init()
-        .unwrap();
+    let options = MountOption::to_vec(matches.values_of("options").unwrap_or_default());
+    let _guard = init_tracing(&options).unwrap();
 
     let endpoints: Vec<&str> = matches
         .values_of("pd")
@@ -46,8 +105,71 @@ async fn main() {
         .to_owned()
         .collect();
 
+    if let Some(matches) = matches.subcommand_matches("cp-r") {
+        copy(endpoints, &options, matches).await.unwrap();
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("destroy") {
+        destroy_tifs(
+            endpoints,
+            &options,
+            matches.value_of("name").unwrap(),
+            matches.is_present("force"),
+        )
+        .await
+        .unwrap();
+        return;
+    }
+
     let mountpoint: String = matches.value_of("mount-point").unwrap().to_string();
-    let options = MountOption::to_vec(matches.values_of("options").unwrap_or_default());
 
     mount_tifs(mountpoint, endpoints, options).await.unwrap();
 }
+
+async fn copy(
+    endpoints: Vec<&str>,
+    options: &[MountOption],
+    matches: &ArgMatches<'_>,
+) -> anyhow::Result<()> {
+    let client = TransactionClient::new_with_config(endpoints, client_config(options)?)
+        .await
+        .map_err(|err| anyhow!("{}", err))?;
+    let encryption = options.iter().find_map(|option| match option {
+        MountOption::Encryption(key) => Some(key.clone()),
+        _ => None,
+    });
+
+    let options = CopyOptions {
+        prefix: ScopedKey::namespace(matches.value_of("name").unwrap_or_default()),
+        bwlimit: matches
+            .value_of("bwlimit")
+            .map(|limit| -> anyhow::Result<u64> {
+                match limit.parse::<u64>()? {
+                    0 => Err(anyhow!("--bwlimit must be positive")),
+                    limit => limit
+                        .checked_mul(1 << 20)
+                        .ok_or_else(|| anyhow!("--bwlimit {} is too large", limit)),
+                }
+            })
+            .transpose()?,
+        force: matches.is_present("force"),
+        encryption,
+    };
+
+    let progress = copy_tree(
+        &client,
+        matches.value_of("from").unwrap(),
+        matches.value_of("to").unwrap(),
+        options,
+        |progress| {
+            eprint!(
+                "\r{} dirs, {} files, {} symlinks, {} bytes copied",
+                progress.dirs, progress.files, progress.symlinks, progress.bytes
+            )
+        },
+    )
+    .await?;
+    eprintln!("\ndone: {:?}", progress);
+    Ok(())
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::TimeOrNow;
+
+use common::{TestFs, GID, ROOT, UID};
+use tifs::fs::async_fs::AsyncFileSystem;
+
+#[async_std::test]
+#[ignore]
+async fn chmod_and_chown_keep_timestamps() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, b"hello").await;
+    fs.close(ino, fh).await;
+    let before = fs.getattr(ino).await.unwrap().attr;
+
+    let chmod = fs
+        .setattr(
+            UID,
+            GID,
+            ino,
+            Some(0o600),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+        .attr;
+    let chown = fs
+        .setattr(
+            UID,
+            GID,
+            ino,
+            None,
+            Some(UID),
+            Some(GID),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+        .attr;
+    let after = fs.getattr(ino).await.unwrap().attr;
+
+    assert_eq!(chmod.perm, 0o600);
+    // `SystemTime` compares nanoseconds
+    for attr in &[chmod, chown, after] {
+        assert_eq!(attr.atime, before.atime);
+        assert_eq!(attr.mtime, before.mtime);
+        assert!(attr.ctime >= before.ctime);
+    }
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn chmod_outside_the_group_drops_setgid() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.close(ino, fh).await;
+
+    for (gid, perm) in &[(GID, 0o2755), (GID + 1, 0o755)] {
+        let attr = fs
+            .setattr(
+                UID,
+                *gid,
+                ino,
+                Some(0o2755),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.perm, *perm, "chmod with gid {}", gid);
+    }
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn timestamps_keep_nanoseconds() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.close(ino, fh).await;
+
+    let atime = UNIX_EPOCH + Duration::new(1_500_000_000, 987_654_321);
+    let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+    fs.setattr(
+        UID,
+        GID,
+        ino,
+        None,
+        None,
+        None,
+        None,
+        Some(TimeOrNow::SpecificTime(atime)),
+        Some(TimeOrNow::SpecificTime(mtime)),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    // another mount reads the inode from tikv rather than a cache
+    let other = fs.remount(vec![]).await;
+    for attr in &[
+        fs.getattr(ino).await.unwrap().attr,
+        other.getattr(ino).await.unwrap().attr,
+    ] {
+        assert_eq!(attr.atime, atime);
+        assert_eq!(attr.mtime, mtime);
+    }
+    other.unmount().await;
+    fs.cleanup().await;
+}
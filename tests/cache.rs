@@ -0,0 +1,72 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use fuser::TimeOrNow;
+
+use common::{TestFs, GID, ROOT, UID};
+use tifs::fs::async_fs::AsyncFileSystem;
+
+const MIB: usize = 1 << 20;
+
+#[async_std::test]
+#[ignore]
+async fn cached_reads_issue_no_block_gets() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "large").await;
+    for i in 0..8 {
+        fs.write_at(ino, fh, (i * MIB) as u64, &vec![i as u8; MIB])
+            .await;
+    }
+    fs.close(ino, fh).await;
+
+    let first = fs.read_all(ino).await;
+    assert_eq!(first.len(), 8 * MIB);
+    let reads = fs.block_reads.load(Ordering::Relaxed);
+    assert!(reads > 0);
+
+    assert_eq!(fs.read_all(ino).await, first);
+    assert_eq!(fs.block_reads.load(Ordering::Relaxed), reads);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn restored_mtime_does_not_hide_writes() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, &vec![1; MIB]).await;
+    fs.close(ino, fh).await;
+    assert_eq!(fs.read_all(ino).await, vec![1; MIB]);
+    let mtime = fs.getattr(ino).await.unwrap().attr.mtime;
+
+    // another mount rewrites the file and sets the mtime back, as `touch -d` would
+    let other = fs.remount(vec![]).await;
+    let fh = other.open_file(ino, libc::O_WRONLY).await;
+    other.write_at(ino, fh, 0, &vec![2; MIB]).await;
+    other.close(ino, fh).await;
+    other
+        .setattr(
+            UID,
+            GID,
+            ino,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(TimeOrNow::SpecificTime(mtime)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    other.unmount().await;
+
+    assert_eq!(fs.read_all(ino).await, vec![2; MIB]);
+    fs.cleanup().await;
+}
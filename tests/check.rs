@@ -0,0 +1,158 @@
+mod common;
+
+use bytestring::ByteString;
+
+use common::{client, TestFs, ROOT};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::check::{
+    CheckReport, DanglingEntry, DirCountMismatch, Mismatch, NlinkMismatch, OrphanedBlocks,
+    SizeMismatch,
+};
+use tifs::fs::key::ScopedKey;
+use tifs::fs::transaction::Txn;
+
+// Create a file of `len` bytes, 70000 bytes take two blocks of the default size.
+async fn make_file(fs: &TestFs, name: &str, len: usize) -> u64 {
+    let (ino, fh) = fs.create_file(ROOT, name).await;
+    if len > 0 {
+        fs.write_at(ino, fh, 0, &vec![1; len]).await;
+    }
+    fs.close(ino, fh).await;
+    ino
+}
+
+#[async_std::test]
+#[ignore]
+async fn corruptions_are_found_and_repaired() {
+    let fs = TestFs::new(vec![]).await;
+    let gone = make_file(&fs, "gone", 70000).await;
+    let lost = make_file(&fs, "lost", 0).await;
+    let links = make_file(&fs, "links", 100).await;
+    let counted = make_file(&fs, "counted", 100).await;
+    let beyond = make_file(&fs, "beyond", 70000).await;
+
+    let client = client().await;
+    let mut txn = Txn::begin_optimistic(&client, fs.prefix()).await.unwrap();
+    // the inode is deleted without its entry and blocks, nor the usage counters
+    txn.delete(ScopedKey::inode(gone)).await.unwrap();
+    txn.remove_entry(ROOT, ByteString::from("lost"))
+        .await
+        .unwrap();
+    let mut inode = txn.read_inode(links).await.unwrap();
+    inode.file_attr.nlink = 5;
+    txn.save_inode(&inode).await.unwrap();
+    let mut inode = txn.read_inode(counted).await.unwrap();
+    inode.file_attr.blocks = 7;
+    txn.save_inode(&inode).await.unwrap();
+    // the second block is left beyond the size
+    let mut inode = txn.read_inode(beyond).await.unwrap();
+    inode.set_size(100);
+    txn.save_inode(&inode).await.unwrap();
+    txn.update_meta(|meta| meta.inode_next = ROOT)
+        .await
+        .unwrap();
+    txn.commit().await.unwrap();
+
+    // this mount is counted as alive
+    let report = fs.check(true, true).await.unwrap();
+    let usage = report.usage.clone().unwrap();
+    assert_eq!(usage.stored.files, usage.actual.files + 1);
+    assert_eq!(usage.stored.blocks, usage.actual.blocks + 2);
+    let mut size_mismatches = vec![
+        SizeMismatch {
+            ino: counted,
+            size: 100,
+            blocks: 7,
+            expected_blocks: 1,
+            blocks_beyond_size: 0,
+        },
+        SizeMismatch {
+            ino: beyond,
+            size: 100,
+            blocks: 1,
+            expected_blocks: 1,
+            blocks_beyond_size: 1,
+        },
+    ];
+    size_mismatches.sort_by_key(|mismatch| mismatch.ino);
+    let inode_next = [lost, links, counted, beyond].iter().max().unwrap() + 1;
+    assert_eq!(
+        report,
+        CheckReport {
+            inodes: 5,
+            entries: 4,
+            blocks: 4,
+            inode_next: Some(Mismatch {
+                stored: ROOT,
+                actual: inode_next,
+            }),
+            usage: Some(usage),
+            live_mounts: Some(1),
+            orphaned_blocks: vec![OrphanedBlocks {
+                ino: gone,
+                blocks: 2,
+            }],
+            dangling_entries: vec![DanglingEntry {
+                parent: ROOT,
+                name: "gone".to_string(),
+                ino: gone,
+            }],
+            unreferenced_inodes: vec![lost],
+            nlink_mismatches: vec![NlinkMismatch {
+                ino: links,
+                nlink: 5,
+                links: 1,
+            }],
+            size_mismatches,
+            dir_count_mismatches: vec![],
+        }
+    );
+
+    let report = fs.check(false, false).await.unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    assert_eq!(report.blocks, 1);
+    assert!(fs.lookup(ROOT, ByteString::from("gone")).await.is_err());
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn dir_count_mismatches_are_found_and_repaired() {
+    let fs = TestFs::new(vec![]).await;
+    let full = fs.mkdir_at(ROOT, "full").await;
+    let sparse = fs.mkdir_at(ROOT, "sparse").await;
+    for name in &["a", "b", "c"] {
+        let (ino, fh) = fs.create_file(full, name).await;
+        fs.close(ino, fh).await;
+    }
+    let (ino, fh) = fs.create_file(sparse, "a").await;
+    fs.close(ino, fh).await;
+
+    // the counter of `full` is stored as the one of `sparse`
+    let client = client().await;
+    let mut txn = Txn::begin_optimistic(&client, fs.prefix()).await.unwrap();
+    let count = txn.get(ScopedKey::dir_count(full)).await.unwrap().unwrap();
+    txn.put(ScopedKey::dir_count(sparse), count).await.unwrap();
+    txn.commit().await.unwrap();
+
+    // this mount is counted as alive
+    let report = fs.check(true, true).await.unwrap();
+    assert_eq!(
+        report.dir_count_mismatches,
+        vec![DirCountMismatch {
+            ino: sparse,
+            count: 3,
+            entries: 1,
+        }]
+    );
+    assert_eq!(report.entries, 6);
+    let others = CheckReport {
+        dir_count_mismatches: vec![],
+        ..report
+    };
+    assert!(others.is_clean(), "{:?}", others);
+
+    let report = fs.check(false, false).await.unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+    fs.cleanup().await;
+}
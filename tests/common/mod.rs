@@ -0,0 +1,242 @@
+//! Filesystems for the tests that need a tikv cluster, they're ignored by default:
+//!
+//! ```bash
+//! TIFS_PD_ENDPOINTS=127.0.0.1:2379 cargo test -- --ignored --skip core_suites_pass
+//! ```
+//!
+//! Each test works on a filesystem of its own name through `TiFs` directly, without FUSE.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::ops::Deref;
+
+use bytestring::ByteString;
+use tikv_client::{Key, TransactionClient};
+
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::key::{ScopedKey, ROOT_INODE};
+use tifs::fs::tikv_fs::TiFs;
+use tifs::fs::transaction::Txn;
+use tifs::{client_config, destroy_tifs, MountOption};
+
+pub const ROOT: u64 = ROOT_INODE;
+pub const UID: u32 = 1000;
+pub const GID: u32 = 1000;
+
+pub fn endpoints() -> Vec<String> {
+    env::var("TIFS_PD_ENDPOINTS")
+        .unwrap_or_else(|_| "127.0.0.1:2379".to_string())
+        .split(',')
+        .map(str::to_string)
+        .collect()
+}
+
+pub async fn client() -> TransactionClient {
+    TransactionClient::new_with_config(endpoints(), Default::default())
+        .await
+        .unwrap()
+}
+
+/// All keys of the filesystem named `name`, without the prefix.
+pub async fn keys(name: &str) -> Vec<Vec<u8>> {
+    let client = client().await;
+    let mut txn = Txn::begin_optimistic(&client, ScopedKey::namespace(name))
+        .await
+        .unwrap();
+    let keys = txn
+        .scan(Key::from(vec![])..Key::from(vec![u8::MAX]), u32::MAX)
+        .await
+        .unwrap()
+        .map(|pair| Vec::<u8>::from(pair.into_key()))
+        .collect();
+    txn.rollback().await.unwrap();
+    keys
+}
+
+/// Number of keys owned by inode `ino` of the filesystem named `name`:
+/// the inode, its blocks and its file handlers.
+pub async fn keys_of(name: &str, ino: u64) -> usize {
+    keys(name)
+        .await
+        .iter()
+        .filter(|key| match ScopedKey::parse(key) {
+            Ok(ScopedKey::Inode(owner))
+            | Ok(ScopedKey::Block { ino: owner, .. })
+            | Ok(ScopedKey::FileHandler { ino: owner, .. }) => owner == ino,
+            _ => false,
+        })
+        .count()
+}
+
+pub struct TestFs {
+    fs: TiFs,
+    pub name: String,
+}
+
+impl Deref for TestFs {
+    type Target = TiFs;
+
+    fn deref(&self) -> &TiFs {
+        &self.fs
+    }
+}
+
+impl TestFs {
+    /// Mount a new filesystem.
+    pub async fn new(options: Vec<MountOption>) -> Self {
+        Self::mount(&format!("test-{:016x}", rand::random::<u64>()), options).await
+    }
+
+    /// Mount the filesystem `name`, which may be mounted by others.
+    pub async fn mount(name: &str, mut options: Vec<MountOption>) -> Self {
+        options.push(MountOption::Name(name.to_string()));
+        options.push(MountOption::SkipWarmUp);
+        let config = client_config(&options).unwrap();
+        let fs = TiFs::construct(endpoints(), config, options).await.unwrap();
+        fs.initialize(GID, UID).await.unwrap();
+        Self {
+            fs,
+            name: name.to_string(),
+        }
+    }
+
+    /// Another mount of this filesystem, as another client would do.
+    pub async fn remount(&self, options: Vec<MountOption>) -> Self {
+        Self::mount(&self.name, options).await
+    }
+
+    pub fn prefix(&self) -> Vec<u8> {
+        ScopedKey::namespace(&self.name)
+    }
+
+    pub async fn mkdir_at(&self, parent: u64, name: &str) -> u64 {
+        self.mkdir(parent, ByteString::from(name), 0o755, GID, UID, 0)
+            .await
+            .unwrap()
+            .stat
+            .ino
+    }
+
+    /// Create and open a regular file, return its inode number and file handler.
+    pub async fn create_file(&self, parent: u64, name: &str) -> (u64, u64) {
+        let created = self
+            .create(
+                UID,
+                GID,
+                parent,
+                ByteString::from(name),
+                libc::S_IFREG | 0o644,
+                0,
+                libc::O_RDWR,
+            )
+            .await
+            .unwrap();
+        (created.attr.ino, created.fh)
+    }
+
+    pub async fn open_file(&self, ino: u64, flags: i32) -> u64 {
+        self.open(ino, flags).await.unwrap().fh
+    }
+
+    pub async fn write_at(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) {
+        let written = self
+            .write(0, ino, fh, offset as i64, data.to_vec(), 0, 0, None)
+            .await
+            .unwrap();
+        assert_eq!(written.size as usize, data.len());
+    }
+
+    pub async fn read_at(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Vec<u8> {
+        self.read(ino, fh, offset as i64, size, 0, None)
+            .await
+            .unwrap()
+            .data
+    }
+
+    /// Read the whole file through a new handler.
+    pub async fn read_all(&self, ino: u64) -> Vec<u8> {
+        let fh = self.open_file(ino, libc::O_RDONLY).await;
+        let mut data = Vec::new();
+        loop {
+            let chunk = self.read_at(ino, fh, data.len() as u64, 1 << 20).await;
+            if chunk.is_empty() {
+                break;
+            }
+            data.extend(chunk);
+        }
+        self.close(ino, fh).await;
+        data
+    }
+
+    pub async fn close(&self, ino: u64, fh: u64) {
+        self.flush(ino, fh, 0).await.unwrap();
+        self.release(ino, fh, 0, None, true).await.unwrap();
+    }
+
+    /// Change the size of a file as truncate(2) does.
+    pub async fn truncate(&self, ino: u64, size: u64) {
+        self.setattr(
+            UID,
+            GID,
+            ino,
+            None,
+            None,
+            None,
+            Some(size),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Truncation epoch of a file, which is bumped by every reduction of its size.
+    pub async fn truncate_epoch(&self, ino: u64) -> u64 {
+        let client = client().await;
+        let mut txn = Txn::begin_optimistic(&client, self.prefix()).await.unwrap();
+        let epoch = txn.read_inode(ino).await.unwrap().truncate_epoch;
+        txn.rollback().await.unwrap();
+        epoch
+    }
+
+    pub async fn size_of(&self, ino: u64) -> u64 {
+        self.getattr(ino).await.unwrap().attr.size
+    }
+
+    /// All keys of this filesystem, without the prefix.
+    pub async fn keys(&self) -> Vec<Vec<u8>> {
+        keys(&self.name).await
+    }
+
+    /// Number of keys owned by inode `ino`: the inode, its blocks and its file handlers.
+    pub async fn keys_of(&self, ino: u64) -> usize {
+        keys_of(&self.name, ino).await
+    }
+
+    /// Unmount without deleting the filesystem.
+    pub async fn unmount(self) {
+        self.fs.destroy().await;
+    }
+
+    /// Unmount and delete all keys of the filesystem, other mounts left by the test are ignored.
+    pub async fn cleanup(self) {
+        let name = self.name.clone();
+        self.unmount().await;
+        let endpoints = endpoints();
+        destroy_tifs(
+            endpoints.iter().map(String::as_str).collect(),
+            &[],
+            &name,
+            true,
+        )
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+//! Mount tifs in a child process and run the core suites of pjdfstest against it.
+//!
+//! It needs fuse, sudo, a built pjdfstest and docker or a running tikv cluster, so it's ignored by default:
+//!
+//! ```bash
+//! PJDFSTEST=/path/to/pjdfstest cargo test --test conformance -- --ignored --nocapture
+//! ```
+//!
+//! A tikv cluster is started by `ci/docker-compose.yaml`, unless `TIFS_PD_ENDPOINTS` points at one.
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const SUITES: &[&str] = &[
+    "mkdir", "rmdir", "open", "unlink", "rename", "link", "symlink", "chmod", "chown", "truncate",
+];
+
+// tikv takes a while to elect leaders after it's started
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Cluster {
+    compose_dir: Option<PathBuf>,
+}
+
+impl Cluster {
+    fn start() -> (Self, String) {
+        if let Ok(endpoints) = env::var("TIFS_PD_ENDPOINTS") {
+            return (Self { compose_dir: None }, endpoints);
+        }
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("ci");
+        for sub in &["data", "logs"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let status = Command::new("docker-compose")
+            .args(&["up", "-d"])
+            .current_dir(&dir)
+            .status()
+            .expect("fail to run docker-compose");
+        assert!(status.success(), "docker-compose exits with {}", status);
+        (
+            Self {
+                compose_dir: Some(dir),
+            },
+            "127.0.0.1:2379".to_string(),
+        )
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.compose_dir {
+            let _ = Command::new("docker-compose")
+                .arg("down")
+                .current_dir(dir)
+                .status();
+        }
+    }
+}
+
+struct Mount {
+    child: Child,
+    mount_point: PathBuf,
+}
+
+impl Mount {
+    fn start(endpoints: &str, mount_point: PathBuf) -> Self {
+        fs::create_dir_all(&mount_point).unwrap();
+        let mut mount = Self {
+            child: Self::spawn(endpoints, &mount_point),
+            mount_point,
+        };
+        let parent_dev = fs::metadata(mount.mount_point.parent().unwrap())
+            .unwrap()
+            .dev();
+        let start = Instant::now();
+        loop {
+            if fs::metadata(&mount.mount_point).unwrap().dev() != parent_dev {
+                return mount;
+            }
+            assert!(
+                start.elapsed() < MOUNT_TIMEOUT,
+                "{} is not mounted after {:?}",
+                mount.mount_point.display(),
+                MOUNT_TIMEOUT
+            );
+            // the mount fails until the cluster is ready
+            if let Some(status) = mount.child.try_wait().unwrap() {
+                eprintln!("mount exits with {}, retry", status);
+                mount.child = Self::spawn(endpoints, &mount.mount_point);
+            }
+            sleep(Duration::from_secs(1));
+        }
+    }
+
+    fn spawn(endpoints: &str, mount_point: &Path) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_mount"))
+            .arg(format!("tifs:{}", endpoints))
+            .arg(mount_point)
+            .arg("--foreground")
+            .spawn()
+            .expect("fail to spawn mount")
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let unmounted = Command::new("fusermount")
+            .arg("-u")
+            .arg(&self.mount_point)
+            .status()
+            .map_or(false, |status| status.success());
+        if !unmounted {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+        let _ = fs::remove_dir(&self.mount_point);
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SuiteResult {
+    tests: u64,
+    failed: u64,
+    // lines of failed cases and dubious test files
+    failures: Vec<String>,
+}
+
+impl SuiteResult {
+    fn parse(output: &str) -> Self {
+        let mut result = Self::default();
+        for line in output.lines() {
+            if let Some(tests) = line
+                .split(", ")
+                .find_map(|field| field.trim().strip_prefix("Tests="))
+            {
+                result.tests = tests.parse().unwrap_or(0);
+            }
+            if let Some(failed) = line
+                .split_whitespace()
+                .skip_while(|word| *word != "Failed")
+                .nth(1)
+                .filter(|_| line.ends_with("subtests"))
+                .and_then(|ratio| ratio.split('/').next())
+            {
+                result.failed += failed.parse::<u64>().unwrap_or(0);
+            }
+            if line.starts_with("not ok") || line.contains("Wstat") {
+                result.failures.push(line.to_string());
+            }
+        }
+        result
+    }
+}
+
+fn run_suite(pjdfstest: &Path, mount_point: &Path, suite: &str) -> (bool, SuiteResult) {
+    // pjdfstest changes owners, which needs root
+    let output = Command::new("sudo")
+        .arg("prove")
+        .arg("-r")
+        .arg(pjdfstest.join("tests").join(suite))
+        .current_dir(mount_point)
+        .output()
+        .expect("fail to run prove");
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let result = SuiteResult::parse(&text);
+    (output.status.success() && result.failed == 0, result)
+}
+
+#[test]
+#[ignore]
+fn core_suites_pass() {
+    let pjdfstest =
+        PathBuf::from(env::var("PJDFSTEST").expect("PJDFSTEST must point at a built pjdfstest"));
+    let (_cluster, endpoints) = Cluster::start();
+    let mount_point = env::temp_dir().join(format!("tifs-conformance-{}", process::id()));
+    let mount = Mount::start(&endpoints, mount_point);
+
+    let mut failed_suites = Vec::new();
+    for suite in SUITES {
+        let (passed, result) = run_suite(&pjdfstest, &mount.mount_point, suite);
+        println!(
+            "{}: {} tests, {} failed",
+            suite, result.tests, result.failed
+        );
+        for line in &result.failures {
+            println!("    {}", line);
+        }
+        if !passed {
+            failed_suites.push(*suite);
+        }
+    }
+    assert!(
+        failed_suites.is_empty(),
+        "failed suites: {:?}",
+        failed_suites
+    );
+}
+
+#[test]
+fn parse_prove_output() {
+    let output = "\
+/pjdfstest/tests/chmod/00.t .. ok
+/pjdfstest/tests/chmod/01.t ..
+not ok 3 - tried 'chmod pjdfstest_a 0644', expected 0, got ENOENT
+Failed 2/12 subtests
+/pjdfstest/tests/chmod/02.t .. Dubious, test returned 1 (wstat 256, 0x100)
+Failed 1/5 subtests
+
+Test Summary Report
+-------------------
+/pjdfstest/tests/chmod/01.t (Wstat: 0 Tests: 12 Failed: 2)
+Files=3, Tests=136,  2 wallclock secs ( 0.05 usr  0.01 sys +  0.55 cusr  0.71 csys =  1.32 CPU)
+Result: FAIL
+";
+    let result = SuiteResult::parse(output);
+    assert_eq!(result.tests, 136);
+    assert_eq!(result.failed, 3);
+    assert_eq!(result.failures.len(), 2);
+}
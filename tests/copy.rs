@@ -0,0 +1,169 @@
+mod common;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use bytestring::ByteString;
+
+use common::{client, TestFs, ROOT};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::copy::{copy_tree, CopyOptions};
+use tifs::fs::encryption::EncryptionKey;
+use tifs::fs::key::ScopedKey;
+use tifs::{MountOption, OptionValue};
+
+const MIB: u64 = 1 << 20;
+
+// (path, [(offset, length)]) of files written with patterned data, the gaps are holes
+const FILES: &[(&str, &[(u64, u64)])] = &[
+    ("inline", &[(0, 100)]),
+    ("sparse", &[(0, 5), (10 * MIB + 7, 4096)]),
+    ("sub/dense", &[(0, 3 * MIB + 1)]),
+    ("sub/deep/tail", &[(64 * MIB, 10)]),
+];
+
+fn pattern(offset: u64, len: u64) -> Vec<u8> {
+    (offset..offset + len)
+        .map(|i| (i * 31 % 251) as u8)
+        .collect()
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn resolve(fs: &TestFs, path: &str) -> u64 {
+    let mut ino = ROOT;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        ino = fs
+            .lookup(ino, ByteString::from(name))
+            .await
+            .unwrap()
+            .stat
+            .ino;
+    }
+    ino
+}
+
+async fn make_tree(fs: &TestFs, root: &str) {
+    let root = fs.mkdir_at(ROOT, root).await;
+    let sub = fs.mkdir_at(root, "sub").await;
+    fs.mkdir_at(sub, "deep").await;
+    for (path, extents) in FILES {
+        let mut names: Vec<&str> = path.split('/').collect();
+        let name = names.pop().unwrap();
+        let mut parent = root;
+        for dir in names {
+            parent = fs
+                .lookup(parent, ByteString::from(dir))
+                .await
+                .unwrap()
+                .stat
+                .ino;
+        }
+        let (ino, fh) = fs.create_file(parent, name).await;
+        for (offset, len) in extents.iter() {
+            fs.write_at(ino, fh, *offset, &pattern(*offset, *len)).await;
+        }
+        fs.close(ino, fh).await;
+    }
+}
+
+async fn block_keys(fs: &TestFs, ino: u64) -> Vec<u64> {
+    fs.keys()
+        .await
+        .iter()
+        .filter_map(|key| match ScopedKey::parse(key) {
+            Ok(ScopedKey::Block { ino: owner, block }) if owner == ino => Some(block),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn assert_copied(fs: &TestFs, from: &str, to: &str) {
+    for (path, _) in FILES {
+        let src = resolve(fs, &format!("{}/{}", from, path)).await;
+        let dst = resolve(fs, &format!("{}/{}", to, path)).await;
+        assert_ne!(src, dst);
+        let (src_data, dst_data) = (fs.read_all(src).await, fs.read_all(dst).await);
+        assert_eq!(src_data.len(), dst_data.len(), "size of {}", path);
+        assert_eq!(hash(&src_data), hash(&dst_data), "content of {}", path);
+        // holes are not filled
+        assert_eq!(block_keys(fs, src).await, block_keys(fs, dst).await);
+    }
+}
+
+#[async_std::test]
+#[ignore]
+async fn copy_sparse_tree() {
+    let fs = TestFs::new(vec![]).await;
+    make_tree(&fs, "src").await;
+
+    let options = CopyOptions {
+        prefix: fs.prefix(),
+        ..Default::default()
+    };
+    let progress = copy_tree(&client().await, "/src", "/dst", options, |_| ())
+        .await
+        .unwrap();
+    assert_eq!(progress.dirs, 3);
+    assert_eq!(progress.files, FILES.len() as u64);
+
+    assert_copied(&fs, "/src", "/dst").await;
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn copy_encrypted_tree() {
+    let key = EncryptionKey::parse_value(&"5a".repeat(32)).unwrap();
+    let fs = TestFs::new(vec![MountOption::Encryption(key.clone())]).await;
+    make_tree(&fs, "src").await;
+
+    let options = CopyOptions {
+        prefix: fs.prefix(),
+        ..Default::default()
+    };
+    assert!(
+        copy_tree(&client().await, "/src", "/plain", options.clone(), |_| ())
+            .await
+            .is_err()
+    );
+
+    let options = CopyOptions {
+        encryption: Some(key),
+        ..options
+    };
+    copy_tree(&client().await, "/src", "/dst", options, |_| ())
+        .await
+        .unwrap();
+    assert_copied(&fs, "/src", "/dst").await;
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn force_replaces_open_files() {
+    let fs = TestFs::new(vec![]).await;
+    make_tree(&fs, "src").await;
+    let dst = fs.mkdir_at(ROOT, "dst").await;
+    let (ino, fh) = fs.create_file(dst, "inline").await;
+    fs.write_at(ino, fh, 0, b"kept while open").await;
+
+    let options = CopyOptions {
+        prefix: fs.prefix(),
+        force: true,
+        ..Default::default()
+    };
+    copy_tree(&client().await, "/src", "/dst", options, |_| ())
+        .await
+        .unwrap();
+    assert_copied(&fs, "/src", "/dst").await;
+    // the replaced file is only unlinked, its data is kept for the open handler
+    assert_eq!(fs.read_at(ino, fh, 0, 100).await, b"kept while open");
+    fs.close(ino, fh).await;
+    assert_eq!(fs.keys_of(ino).await, 0);
+    fs.cleanup().await;
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use bytestring::ByteString;
+use futures::future::join;
+
+use common::{TestFs, GID, ROOT, UID};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::error::FsError;
+
+const MODE: u32 = libc::S_IFREG | 0o644;
+
+#[async_std::test]
+#[ignore]
+async fn exclusive_creates_race_to_one_winner() {
+    let fs = TestFs::new(vec![]).await;
+    let other = fs.remount(vec![]).await;
+    for round in 0..10 {
+        let name = ByteString::from(format!("file-{}", round));
+        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+        let (left, right) = join(
+            fs.create(UID, GID, ROOT, name.clone(), MODE, 0, flags),
+            other.create(UID, GID, ROOT, name, MODE, 0, flags),
+        )
+        .await;
+        let (created, lost) = match (left, right) {
+            (Ok(created), Err(lost)) => (created, lost),
+            (Err(lost), Ok(created)) => (created, lost),
+            (left, right) => panic!("round {}: {:?} and {:?}", round, left, right),
+        };
+        assert!(
+            matches!(lost, FsError::FileExist { .. }),
+            "round {}: {:?}",
+            round,
+            lost
+        );
+        assert_eq!(fs.getattr(created.attr.ino).await.unwrap().attr.nlink, 1);
+    }
+    other.unmount().await;
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn creates_of_existing_files_open_them() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, b"hello").await;
+    fs.close(ino, fh).await;
+    let name = ByteString::from("file");
+
+    // the kernel only sends create when it sees no file, which others may create meanwhile
+    let created = fs
+        .create(UID, GID, ROOT, name.clone(), MODE, 0, libc::O_RDWR)
+        .await
+        .unwrap();
+    assert_eq!(created.attr.ino, ino);
+    assert_eq!(fs.read_at(ino, created.fh, 0, 100).await, b"hello");
+    fs.close(ino, created.fh).await;
+
+    let created = fs
+        .create(
+            UID,
+            GID,
+            ROOT,
+            name.clone(),
+            MODE,
+            0,
+            libc::O_RDWR | libc::O_TRUNC,
+        )
+        .await
+        .unwrap();
+    assert_eq!(created.attr.size, 0);
+    fs.close(ino, created.fh).await;
+    assert_eq!(fs.read_all(ino).await, b"");
+
+    // others have no write permission of 0644
+    let denied = fs
+        .create(UID + 1, GID + 1, ROOT, name, MODE, 0, libc::O_WRONLY)
+        .await;
+    assert!(
+        matches!(denied, Err(FsError::AccessDenied { ino: denied }) if denied == ino),
+        "{:?}",
+        denied
+    );
+    fs.cleanup().await;
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use bytestring::ByteString;
+
+use common::{endpoints, keys, keys_of, TestFs, ROOT};
+use tifs::destroy_tifs;
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::error::FsError;
+
+#[async_std::test]
+#[ignore]
+async fn destroy_refuses_mounted_filesystems() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, b"data").await;
+    fs.close(ino, fh).await;
+
+    let endpoints = endpoints();
+    let endpoints: Vec<&str> = endpoints.iter().map(String::as_str).collect();
+    let err = destroy_tifs(endpoints.clone(), &[], &fs.name, false)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<FsError>(),
+        Some(FsError::Mounted { mounts: 1 })
+    ));
+    assert!(!fs.keys().await.is_empty());
+
+    let name = fs.name.clone();
+    fs.unmount().await;
+    destroy_tifs(endpoints, &[], &name, false).await.unwrap();
+    assert!(keys(&name).await.is_empty());
+}
+
+#[async_std::test]
+#[ignore]
+async fn unmount_removes_unlinked_open_files() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "open").await;
+    fs.write_at(ino, fh, 0, b"data").await;
+    fs.unlink(ROOT, ByteString::from("open")).await.unwrap();
+    // the data is kept for the open handler
+    assert!(keys_of(&fs.name, ino).await > 0);
+
+    let name = fs.name.clone();
+    fs.unmount().await;
+    assert_eq!(keys_of(&name, ino).await, 0);
+    let endpoints = endpoints();
+    destroy_tifs(
+        endpoints.iter().map(String::as_str).collect(),
+        &[],
+        &name,
+        false,
+    )
+    .await
+    .unwrap();
+}
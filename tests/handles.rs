@@ -0,0 +1,52 @@
+mod common;
+
+use std::time::Duration;
+
+use async_std::task::sleep;
+
+use common::{TestFs, ROOT};
+use tifs::MountOption;
+
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[async_std::test]
+#[ignore]
+async fn reap_flushes_dirty_buffers() {
+    let fs = TestFs::new(vec![
+        MountOption::WriteBack,
+        MountOption::HandleIdleTimeout(IDLE_TIMEOUT),
+    ])
+    .await;
+    let (ino, fh) = fs.create_file(ROOT, "dirty").await;
+    fs.write_at(ino, fh, 0, b"buffered").await;
+
+    fs.reap_idle_handles().await;
+    assert!(fs.hub.get(ino, fh).is_some(), "reaped before idle");
+
+    sleep(IDLE_TIMEOUT).await;
+    fs.reap_idle_handles().await;
+    assert!(fs.hub.get(ino, fh).is_none());
+    assert_eq!(fs.hub.reaped(), 1);
+
+    let other = fs.remount(vec![]).await;
+    assert_eq!(other.read_all(ino).await, b"buffered");
+    // the inode is all that's left, the handler is closed in tikv as well
+    assert_eq!(other.keys_of(ino).await, 1);
+    other.unmount().await;
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn accessed_handles_are_not_reaped() {
+    let fs = TestFs::new(vec![MountOption::HandleIdleTimeout(IDLE_TIMEOUT)]).await;
+    let (ino, fh) = fs.create_file(ROOT, "busy").await;
+    for _ in 0..4 {
+        sleep(IDLE_TIMEOUT / 2).await;
+        fs.write_at(ino, fh, 0, b"busy").await;
+        fs.reap_idle_handles().await;
+    }
+    assert!(fs.hub.get(ino, fh).is_some());
+    fs.close(ino, fh).await;
+    fs.cleanup().await;
+}
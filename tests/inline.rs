@@ -0,0 +1,81 @@
+mod common;
+
+use common::{TestFs, ROOT};
+use tifs::fs::key::ScopedKey;
+use tifs::fs::tikv_fs::TiFs;
+
+const THRESHOLD: usize = TiFs::DEFAULT_INLINE_DATA_THRESHOLD as usize;
+
+// Number of blocks stored of a closed file.
+async fn stored_blocks(fs: &TestFs, ino: u64) -> usize {
+    fs.keys()
+        .await
+        .iter()
+        .filter(|key| match ScopedKey::parse(key) {
+            Ok(ScopedKey::Block { ino: owner, .. }) => owner == ino,
+            _ => false,
+        })
+        .count()
+}
+
+async fn make_file(fs: &TestFs, name: &str, data: &[u8]) -> u64 {
+    let (ino, fh) = fs.create_file(ROOT, name).await;
+    fs.write_at(ino, fh, 0, data).await;
+    fs.close(ino, fh).await;
+    ino
+}
+
+#[async_std::test]
+#[ignore]
+async fn writes_spill_beyond_the_threshold() {
+    let fs = TestFs::new(vec![]).await;
+    let data: Vec<u8> = (0..THRESHOLD + 1).map(|i| i as u8).collect();
+
+    let inlined = make_file(&fs, "inlined", &data[..THRESHOLD]).await;
+    assert_eq!(stored_blocks(&fs, inlined).await, 0);
+    assert_eq!(fs.read_all(inlined).await, &data[..THRESHOLD]);
+
+    let spilled = make_file(&fs, "spilled", &data).await;
+    assert_eq!(stored_blocks(&fs, spilled).await, 1);
+    assert_eq!(fs.read_all(spilled).await, data);
+
+    // a write far from the inline data moves it to the first block
+    let ino = make_file(&fs, "overwritten", b"head").await;
+    assert_eq!(stored_blocks(&fs, ino).await, 0);
+    let fh = fs.open_file(ino, libc::O_WRONLY).await;
+    fs.write_at(ino, fh, 4096, b"tail").await;
+    fs.close(ino, fh).await;
+    assert_eq!(stored_blocks(&fs, ino).await, 1);
+    let mut expected = vec![0; 4100];
+    expected[..4].copy_from_slice(b"head");
+    expected[4096..].copy_from_slice(b"tail");
+    assert_eq!(fs.read_all(ino).await, expected);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn growing_pads_or_spills_inline_data() {
+    let fs = TestFs::new(vec![]).await;
+    let ino = make_file(&fs, "file", b"head").await;
+
+    fs.truncate(ino, THRESHOLD as u64).await;
+    assert_eq!(stored_blocks(&fs, ino).await, 0);
+    let mut expected = b"head".to_vec();
+    expected.resize(THRESHOLD, 0);
+    assert_eq!(fs.read_all(ino).await, expected);
+
+    fs.truncate(ino, THRESHOLD as u64 + 1).await;
+    assert_eq!(stored_blocks(&fs, ino).await, 1);
+    expected.push(0);
+    assert_eq!(fs.read_all(ino).await, expected);
+
+    // the grown file is written like any other
+    let fh = fs.open_file(ino, libc::O_WRONLY).await;
+    fs.write_at(ino, fh, THRESHOLD as u64, b"tail").await;
+    fs.close(ino, fh).await;
+    expected.truncate(THRESHOLD);
+    expected.extend_from_slice(b"tail");
+    assert_eq!(fs.read_all(ino).await, expected);
+    fs.cleanup().await;
+}
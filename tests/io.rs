@@ -0,0 +1,128 @@
+mod common;
+
+use futures::future::join_all;
+
+use common::{TestFs, ROOT};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::tikv_fs::TiFs;
+
+const BLOCK: u64 = TiFs::DEFAULT_BLOCK_SIZE;
+
+#[async_std::test]
+#[ignore]
+async fn zero_length_writes_change_nothing() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, b"hello").await;
+    let before = fs.getattr(ino).await.unwrap().attr;
+
+    let written = fs
+        .write(0, ino, fh, 1 << 20, Vec::new(), 0, 0, None)
+        .await
+        .unwrap();
+    assert_eq!(written.size, 0);
+    // the handler is not even looked up
+    let written = fs
+        .write(0, ino, fh + 100, 0, Vec::new(), 0, 0, None)
+        .await
+        .unwrap();
+    assert_eq!(written.size, 0);
+
+    let after = fs.getattr(ino).await.unwrap().attr;
+    assert_eq!(after.size, 5);
+    assert_eq!(after.mtime, before.mtime);
+    assert_eq!(fs.read_at(ino, fh, 0, 100).await, b"hello");
+    fs.close(ino, fh).await;
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn offsets_are_absolute() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    // inline data, a block far away after a hole, a block boundary, and back to the head
+    let writes: &[(u64, &[u8])] = &[
+        (0, b"head"),
+        (100, b"inline"),
+        (3 * BLOCK + 10, b"far"),
+        (BLOCK - 2, b"across"),
+        (2, b"XY"),
+    ];
+    let mut expected = vec![0; (3 * BLOCK + 13) as usize];
+    for (offset, data) in writes {
+        fs.write_at(ino, fh, *offset, data).await;
+        let start = *offset as usize;
+        expected[start..start + data.len()].copy_from_slice(data);
+    }
+
+    for (offset, data) in writes {
+        let read = fs.read_at(ino, fh, *offset, data.len() as u32).await;
+        assert_eq!(
+            read,
+            &expected[*offset as usize..*offset as usize + data.len()]
+        );
+    }
+    // reads of the same handler don't move later ones
+    assert_eq!(fs.read_at(ino, fh, 0, 6).await, b"heXY\0\0");
+    assert_eq!(fs.read_at(ino, fh, 0, 6).await, b"heXY\0\0");
+    fs.close(ino, fh).await;
+    assert_eq!(fs.read_all(ino).await, expected);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn appends_follow_each_other() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.close(ino, fh).await;
+
+    // `echo line >> file` twice: the kernel sends each write at the size it knows
+    for _ in 0..2 {
+        let fh = fs.open_file(ino, libc::O_WRONLY | libc::O_APPEND).await;
+        let size = fs.size_of(ino).await;
+        fs.write_at(ino, fh, size, b"line\n").await;
+        fs.close(ino, fh).await;
+    }
+    assert_eq!(fs.read_all(ino).await, b"line\nline\n");
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn concurrent_appends_never_overlap() {
+    const APPENDERS: usize = 10;
+    const RECORDS: usize = 20;
+    const RECORD: usize = 100;
+
+    let fs = TestFs::new(vec![]).await;
+    let other = fs.remount(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "log").await;
+    fs.close(ino, fh).await;
+
+    // appenders on two mounts, each writing records of its own byte at a stale offset
+    let appenders = (0..APPENDERS).map(|appender| {
+        let fs = if appender % 2 == 0 { &fs } else { &other };
+        async move {
+            let fh = fs.open_file(ino, libc::O_WRONLY | libc::O_APPEND).await;
+            for _ in 0..RECORDS {
+                fs.write_at(ino, fh, 0, &[b'a' + appender as u8; RECORD])
+                    .await;
+            }
+            fs.close(ino, fh).await;
+        }
+    });
+    join_all(appenders).await;
+
+    let data = fs.read_all(ino).await;
+    assert_eq!(data.len(), APPENDERS * RECORDS * RECORD);
+    let mut records = [0; APPENDERS];
+    for record in data.chunks(RECORD) {
+        assert!(record.iter().all(|byte| *byte == record[0]), "{:?}", record);
+        records[(record[0] - b'a') as usize] += 1;
+    }
+    assert_eq!(records, [RECORDS; APPENDERS]);
+    other.unmount().await;
+    fs.cleanup().await;
+}
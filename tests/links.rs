@@ -0,0 +1,152 @@
+mod common;
+
+use bytestring::ByteString;
+
+use common::{TestFs, GID, ROOT, UID};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::error::FsError;
+use tifs::fs::tikv_fs::TiFs;
+
+async fn nlink(fs: &TestFs, ino: u64) -> u32 {
+    fs.getattr(ino).await.unwrap().attr.nlink
+}
+
+#[async_std::test]
+#[ignore]
+async fn hard_links_count_names() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "first").await;
+    fs.write_at(ino, fh, 0, b"hello").await;
+    fs.close(ino, fh).await;
+    assert_eq!(nlink(&fs, ino).await, 1);
+
+    let entry = fs
+        .link(ino, ROOT, ByteString::from("second"))
+        .await
+        .unwrap();
+    assert_eq!(entry.stat.ino, ino);
+    assert_eq!(entry.stat.nlink, 2);
+    assert_eq!(nlink(&fs, ino).await, 2);
+
+    fs.unlink(ROOT, ByteString::from("first")).await.unwrap();
+    assert_eq!(nlink(&fs, ino).await, 1);
+    let entry = fs.lookup(ROOT, ByteString::from("second")).await.unwrap();
+    assert_eq!(entry.stat.ino, ino);
+    assert_eq!(fs.read_all(ino).await, b"hello");
+
+    // a rename moves the name without a net change of links
+    fs.rename(
+        ROOT,
+        ByteString::from("second"),
+        ROOT,
+        ByteString::from("third"),
+        0,
+    )
+    .await
+    .unwrap();
+    assert_eq!(nlink(&fs, ino).await, 1);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn unlinking_the_last_name_removes_the_data() {
+    let fs = TestFs::new(vec![]).await;
+    // spans several blocks, beyond the inline data
+    let data = vec![7; 3 * TiFs::DEFAULT_BLOCK_SIZE as usize];
+    let (ino, fh) = fs.create_file(ROOT, "first").await;
+    fs.write_at(ino, fh, 0, &data).await;
+    fs.close(ino, fh).await;
+    fs.link(ino, ROOT, ByteString::from("second"))
+        .await
+        .unwrap();
+    let keys = fs.keys_of(ino).await;
+    assert!(keys > 1);
+
+    fs.unlink(ROOT, ByteString::from("first")).await.unwrap();
+    assert_eq!(fs.keys_of(ino).await, keys);
+    let entry = fs.lookup(ROOT, ByteString::from("second")).await.unwrap();
+    assert_eq!(entry.stat.ino, ino);
+    let fh = fs.open_file(ino, libc::O_RDONLY).await;
+    assert_eq!(fs.read_at(ino, fh, 0, data.len() as u32).await, data);
+    fs.close(ino, fh).await;
+
+    fs.unlink(ROOT, ByteString::from("second")).await.unwrap();
+    assert_eq!(fs.keys_of(ino).await, 0);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn directories_count_subdirectories() {
+    let fs = TestFs::new(vec![]).await;
+    let root_links = nlink(&fs, ROOT).await;
+    let dir = fs.mkdir_at(ROOT, "dir").await;
+    assert_eq!(nlink(&fs, dir).await, 2);
+    assert_eq!(nlink(&fs, ROOT).await, root_links + 1);
+
+    let sub = fs.mkdir_at(dir, "sub").await;
+    fs.mkdir_at(dir, "other").await;
+    // files are no links of the directory
+    let (ino, fh) = fs.create_file(dir, "file").await;
+    fs.close(ino, fh).await;
+    assert_eq!(nlink(&fs, dir).await, 4);
+
+    // a subdirectory moved out takes its `..` along
+    fs.rename(
+        dir,
+        ByteString::from("sub"),
+        ROOT,
+        ByteString::from("sub"),
+        0,
+    )
+    .await
+    .unwrap();
+    assert_eq!(nlink(&fs, dir).await, 3);
+    assert_eq!(nlink(&fs, ROOT).await, root_links + 2);
+    assert_eq!(nlink(&fs, sub).await, 2);
+
+    fs.rmdir(dir, ByteString::from("other")).await.unwrap();
+    assert_eq!(nlink(&fs, dir).await, 2);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn only_symlinks_are_read() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.close(ino, fh).await;
+    assert!(matches!(
+        fs.readlink(ino).await,
+        Err(FsError::NotSymlink { .. })
+    ));
+
+    // targets take at most a page with its nul
+    let target = "a".repeat(TiFs::MAX_SYMLINK_LEN);
+    let link = fs
+        .symlink(
+            GID,
+            UID,
+            ROOT,
+            ByteString::from("link"),
+            ByteString::from(target.as_str()),
+        )
+        .await
+        .unwrap()
+        .stat
+        .ino;
+    assert_eq!(fs.readlink(link).await.unwrap().data, target.as_bytes());
+    let result = fs
+        .symlink(
+            GID,
+            UID,
+            ROOT,
+            ByteString::from("long"),
+            ByteString::from(target + "a"),
+        )
+        .await;
+    assert!(matches!(result, Err(FsError::NameTooLong { .. })));
+    assert!(fs.lookup(ROOT, ByteString::from("long")).await.is_err());
+    fs.cleanup().await;
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use std::collections::HashMap;
+
+use bytestring::ByteString;
+use fuser::FileType;
+
+use common::{TestFs, GID, ROOT, UID};
+use tifs::fs::async_fs::AsyncFileSystem;
+
+const ENTRIES: usize = 500;
+
+fn kind_of(i: usize) -> FileType {
+    match i % 3 {
+        0 => FileType::RegularFile,
+        1 => FileType::Directory,
+        _ => FileType::Symlink,
+    }
+}
+
+#[async_std::test]
+#[ignore]
+async fn listings_carry_types_of_entries() {
+    let fs = TestFs::new(vec![]).await;
+    let dir = fs.mkdir_at(ROOT, "dir").await;
+    let mut expected = HashMap::new();
+    for i in 0..ENTRIES {
+        let name = format!("entry-{}", i);
+        let ino = match kind_of(i) {
+            FileType::RegularFile => {
+                let (ino, fh) = fs.create_file(dir, &name).await;
+                fs.close(ino, fh).await;
+                ino
+            }
+            FileType::Directory => fs.mkdir_at(dir, &name).await,
+            _ => {
+                fs.symlink(
+                    GID,
+                    UID,
+                    dir,
+                    ByteString::from(name.as_str()),
+                    ByteString::from("target"),
+                )
+                .await
+                .unwrap()
+                .stat
+                .ino
+            }
+        };
+        expected.insert(name, (ino, kind_of(i)));
+    }
+
+    // page through the listing as the kernel does, resuming after the last entry
+    let fh = fs.opendir(dir, 0).await.unwrap().fh;
+    let mut found = HashMap::new();
+    let mut offset = 0;
+    loop {
+        let page = fs.readdir(dir, fh, offset).await.unwrap().items;
+        let last = match page.last() {
+            Some((last, _)) => *last,
+            None => break,
+        };
+        for (_, item) in page {
+            if item.name != "." && item.name != ".." {
+                assert!(found.insert(item.name, (item.ino, item.typ)).is_none());
+            }
+        }
+        offset = last;
+    }
+    fs.releasedir(dir, fh, 0).await.unwrap();
+    assert_eq!(found, expected);
+    fs.cleanup().await;
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TestFs;
+use tifs::fs::error::FsError;
+use tifs::fs::retry::RetryPolicy;
+
+const CONFLICTS: usize = 4;
+
+fn policy(max_attempts: Option<u32>) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_delay: Duration::from_millis(20),
+        max_delay: Duration::from_secs(1),
+        jitter: false,
+        deadline: None,
+    }
+}
+
+#[async_std::test]
+#[ignore]
+async fn conflicts_are_retried_with_backoff() {
+    let fs = TestFs::new(vec![]).await;
+    let mut attempts = Vec::new();
+    let result = fs
+        .spin(policy(None), |_, _| {
+            attempts.push(Instant::now());
+            let conflicts = attempts.len() <= CONFLICTS;
+            Box::pin(async move {
+                if conflicts {
+                    Err(FsError::KeyError("injected write conflict".to_string()))
+                } else {
+                    Ok(())
+                }
+            })
+        })
+        .await;
+    assert!(result.is_ok());
+    assert_eq!(attempts.len(), CONFLICTS + 1);
+
+    let intervals: Vec<Duration> = attempts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    for (i, interval) in intervals.iter().enumerate() {
+        assert!(*interval >= policy(None).delay(i as u32 + 1));
+    }
+    assert!(intervals.windows(2).all(|pair| pair[0] < pair[1]));
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn retries_give_up_at_max_attempts() {
+    let fs = TestFs::new(vec![]).await;
+    let mut attempts = 0;
+    let result = fs
+        .spin(policy(Some(3)), |_, _| {
+            attempts += 1;
+            Box::pin(async move { Err::<(), _>(FsError::RegionError("injected".to_string())) })
+        })
+        .await;
+    assert!(matches!(
+        result,
+        Err(FsError::TooManyRetries { attempts: 3 })
+    ));
+    assert_eq!(attempts, 3);
+    fs.cleanup().await;
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::{TestFs, ROOT};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::error::FsError;
+use tifs::fs::tikv_fs::TiFs;
+
+const BLOCK: u64 = TiFs::DEFAULT_BLOCK_SIZE;
+
+#[async_std::test]
+#[ignore]
+async fn disjoint_data_regions_are_found() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "sparse").await;
+    // data in blocks 0, 3 and 6, holes in 1-2, 4-5 and 7
+    for block in &[0, 3, 6] {
+        fs.write_at(ino, fh, block * BLOCK, &vec![1; BLOCK as usize])
+            .await;
+    }
+    fs.truncate(ino, 8 * BLOCK).await;
+
+    let seek = |offset: u64, whence| fs.lseek(ino, fh, offset as i64, whence);
+    let cases = [
+        (0, 0, BLOCK),
+        (BLOCK - 1, BLOCK - 1, BLOCK),
+        (BLOCK, 3 * BLOCK, BLOCK),
+        (2 * BLOCK + 5, 3 * BLOCK, 2 * BLOCK + 5),
+        (3 * BLOCK + 7, 3 * BLOCK + 7, 4 * BLOCK),
+        (4 * BLOCK, 6 * BLOCK, 4 * BLOCK),
+        (6 * BLOCK, 6 * BLOCK, 7 * BLOCK),
+    ];
+    for (offset, data, hole) in cases.iter().copied() {
+        let found = seek(offset, libc::SEEK_DATA).await.unwrap().offset as u64;
+        assert_eq!(found, data, "SEEK_DATA from {}", offset);
+        let found = seek(offset, libc::SEEK_HOLE).await.unwrap().offset as u64;
+        assert_eq!(found, hole, "SEEK_HOLE from {}", offset);
+    }
+
+    // no data after the last region, and nothing past the end
+    assert!(matches!(
+        seek(7 * BLOCK, libc::SEEK_DATA).await,
+        Err(FsError::NoSeekTarget { .. })
+    ));
+    assert_eq!(
+        seek(7 * BLOCK, libc::SEEK_HOLE).await.unwrap().offset as u64,
+        7 * BLOCK
+    );
+    for whence in &[libc::SEEK_DATA, libc::SEEK_HOLE] {
+        assert!(matches!(
+            seek(8 * BLOCK, *whence).await,
+            Err(FsError::NoSeekTarget { .. })
+        ));
+    }
+    fs.close(ino, fh).await;
+    fs.cleanup().await;
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use bytestring::ByteString;
+use tikv_client::TimestampExt;
+
+use common::{client, TestFs, ROOT};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::error::FsError;
+use tifs::MountOption;
+
+#[async_std::test]
+#[ignore]
+async fn snapshot_mounts_see_old_content() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, b"old content").await;
+    fs.close(ino, fh).await;
+    let (gone, fh) = fs.create_file(ROOT, "gone").await;
+    fs.close(gone, fh).await;
+
+    let ts = client().await.current_timestamp().await.unwrap().version();
+    let fh = fs.open_file(ino, libc::O_WRONLY).await;
+    fs.write_at(ino, fh, 0, b"new").await;
+    fs.close(ino, fh).await;
+    fs.truncate(ino, 3).await;
+    fs.unlink(ROOT, ByteString::from("gone")).await.unwrap();
+    let (later, fh) = fs.create_file(ROOT, "later").await;
+    fs.close(later, fh).await;
+
+    let snapshot = fs.remount(vec![MountOption::SnapshotTs(ts)]).await;
+    let entry = snapshot
+        .lookup(ROOT, ByteString::from("file"))
+        .await
+        .unwrap();
+    assert_eq!(entry.stat.ino, ino);
+    assert_eq!(entry.stat.size, 11);
+    assert_eq!(snapshot.read_all(ino).await, b"old content");
+    assert!(snapshot
+        .lookup(ROOT, ByteString::from("gone"))
+        .await
+        .is_ok());
+    assert!(matches!(
+        snapshot.lookup(ROOT, ByteString::from("later")).await,
+        Err(FsError::FileNotFound { .. })
+    ));
+    assert!(matches!(
+        snapshot
+            .write(0, ino, 0, 0, b"x".to_vec(), 0, 0, None)
+            .await,
+        Err(FsError::ReadOnly)
+    ));
+    // the writable mount is not affected
+    assert_eq!(fs.read_all(ino).await, b"new");
+    snapshot.unmount().await;
+    fs.cleanup().await;
+}
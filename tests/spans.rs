@@ -0,0 +1,101 @@
+mod common;
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use bytestring::ByteString;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_futures::Instrument;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use common::{TestFs, ROOT};
+use tifs::fs::async_fs::{request_span, AsyncFileSystem};
+
+#[derive(Debug)]
+struct CapturedSpan {
+    name: &'static str,
+    parent: Option<&'static str>,
+    request_id: Option<u64>,
+}
+
+// Record the spans created with their parents.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<CapturedSpan>>>);
+
+impl Capture {
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+struct RequestId(Option<u64>);
+
+impl Visit for RequestId {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "id" {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+}
+
+impl<S> Layer<S> for Capture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut request_id = RequestId(None);
+        attrs.record(&mut request_id);
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.name());
+        self.0.lock().unwrap().push(CapturedSpan {
+            name: attrs.metadata().name(),
+            parent,
+            request_id: request_id.0,
+        });
+    }
+}
+
+#[test]
+#[ignore]
+fn transactions_and_kv_calls_are_beneath_the_request() {
+    let capture = Capture::default();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+
+    let (start, end) = async_std::task::block_on(async {
+        let fs = TestFs::new(vec![]).await;
+        fs.mkdir_at(ROOT, "dir").await;
+        // a mount of its own, so the lookup is not answered by caches
+        let other = fs.remount(vec![]).await;
+        let start = capture.len();
+        other
+            .lookup(ROOT, ByteString::from("dir"))
+            .instrument(request_span(42, "lookup"))
+            .await
+            .unwrap();
+        let end = capture.len();
+        other.unmount().await;
+        fs.cleanup().await;
+        (start, end)
+    });
+
+    let spans = &capture.0.lock().unwrap()[start..end];
+    assert_eq!(spans[0].name, "request");
+    assert_eq!(spans[0].parent, None);
+    assert_eq!(spans[0].request_id, Some(42));
+    let txns: Vec<_> = spans.iter().filter(|span| span.name == "txn").collect();
+    assert!(!txns.is_empty());
+    assert!(txns.iter().all(|span| span.parent == Some("request")));
+    assert!(spans
+        .iter()
+        .any(|span| span.name == "get" && span.parent == Some("txn")));
+    // nothing is created out of the request
+    assert!(spans[1..].iter().all(|span| span.parent.is_some()));
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use std::time::Duration;
+
+use async_std::task::sleep;
+use futures::future::join;
+
+use common::{TestFs, ROOT};
+use tifs::fs::async_fs::AsyncFileSystem;
+use tifs::fs::tikv_fs::TiFs;
+
+const MIB: usize = 1 << 20;
+
+// More than two transactions of `copy_file_range`, with no zero byte.
+async fn make_source(fs: &TestFs) -> (u64, Vec<u8>) {
+    let len = 2 * TiFs::COPY_CHUNK_SIZE as usize + 4096;
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8 + 1).collect();
+    let (ino, fh) = fs.create_file(ROOT, "source").await;
+    for offset in (0..len).step_by(MIB) {
+        let end = (offset + MIB).min(len);
+        fs.write_at(ino, fh, offset as u64, &data[offset..end])
+            .await;
+    }
+    fs.close(ino, fh).await;
+    (ino, data)
+}
+
+async fn copy_all(fs: &TestFs, src: u64, dst: u64, len: usize) -> u64 {
+    let fh_in = fs.open_file(src, libc::O_RDONLY).await;
+    let fh_out = fs.open_file(dst, libc::O_WRONLY).await;
+    let copied = fs
+        .copy_file_range(src, fh_in, 0, dst, fh_out, 0, len as u64, 0)
+        .await
+        .unwrap()
+        .size as u64;
+    fs.close(src, fh_in).await;
+    fs.close(dst, fh_out).await;
+    copied
+}
+
+#[async_std::test]
+#[ignore]
+async fn only_reductions_bump_the_truncation_epoch() {
+    let fs = TestFs::new(vec![]).await;
+    let (ino, fh) = fs.create_file(ROOT, "file").await;
+    fs.write_at(ino, fh, 0, &[1; 10000]).await;
+    fs.close(ino, fh).await;
+    let epoch = fs.truncate_epoch(ino).await;
+
+    fs.truncate(ino, 20000).await;
+    fs.truncate(ino, 20000).await;
+    assert_eq!(fs.truncate_epoch(ino).await, epoch);
+    fs.truncate(ino, 5000).await;
+    assert_eq!(fs.truncate_epoch(ino).await, epoch + 1);
+    fs.truncate(ino, 0).await;
+    assert_eq!(fs.truncate_epoch(ino).await, epoch + 2);
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn truncation_racing_a_chunked_copy_ends_in_either_outcome() {
+    let fs = TestFs::new(vec![]).await;
+    let other = fs.remount(vec![]).await;
+    let (src, data) = make_source(&fs).await;
+    for (round, delay) in [0, 100, 500, 2000].iter().enumerate() {
+        let (dst, fh) = fs.create_file(ROOT, &format!("copy-{}", round)).await;
+        fs.close(dst, fh).await;
+        let (copied, ()) = join(copy_all(&fs, src, dst, data.len()), async {
+            sleep(Duration::from_millis(*delay)).await;
+            other.truncate(dst, 0).await;
+        })
+        .await;
+
+        // either the copy or the truncation comes last, chunks copied before a truncation
+        // are not brought back by later ones
+        let copy = fs.read_all(dst).await;
+        assert!(
+            copy.is_empty() || copy == data,
+            "round {}: {} bytes copied, {} bytes found",
+            round,
+            copied,
+            copy.len()
+        );
+    }
+    other.unmount().await;
+    fs.cleanup().await;
+}
+
+#[async_std::test]
+#[ignore]
+async fn growing_racing_a_chunked_copy_keeps_it_whole() {
+    let fs = TestFs::new(vec![]).await;
+    let other = fs.remount(vec![]).await;
+    let (src, data) = make_source(&fs).await;
+    let (dst, fh) = fs.create_file(ROOT, "copy").await;
+    fs.close(dst, fh).await;
+    let grown = 3 * TiFs::COPY_CHUNK_SIZE;
+    let (copied, ()) = join(copy_all(&fs, src, dst, data.len()), async {
+        sleep(Duration::from_millis(500)).await;
+        other.truncate(dst, grown).await;
+    })
+    .await;
+
+    assert_eq!(copied, data.len() as u64);
+    let copy = fs.read_all(dst).await;
+    assert_eq!(copy.len() as u64, grown.max(data.len() as u64));
+    assert!(copy[..data.len()] == data[..]);
+    assert!(copy[data.len()..].iter().all(|byte| *byte == 0));
+    other.unmount().await;
+    fs.cleanup().await;
+}